@@ -0,0 +1,78 @@
+#!/bin/bash
+
+# -----------------------------
+# Sketching throughput of adas-build on the bundled data/ FASTA files, before and after batched sketching.
+# The baseline is adas-build of the commit before batched sketching (374500f): a reader thread feeding
+# one sketch call per record to the consumer threads, signatures collected under two mutexes.
+# Build it in a separate worktree and give its path in ADAS_BUILD_BASELINE:
+#   git worktree add /tmp/adas-baseline 374500f && (cd /tmp/adas-baseline && cargo build --release)
+#   ADAS_BUILD_BASELINE=/tmp/adas-baseline/target/release/adas-build run_sketching_throughput.sh
+# The current adas-build is run with several --batch-size.
+# The sketching time of both is the time between the "Sketching..." and "Sketching done" lines of the output.
+# The input is replicated to get enough sequences for stable timings.
+# Results are appended to sketching_throughput.tsv (dataset, sequences, threads, binary, batch size, seconds, sequences/s).
+# Usage: run_sketching_throughput.sh [threads] [replicates]
+# -----------------------------
+
+threads=${1:-8}
+replicates=${2:-50}
+script_dir=$(cd "$(dirname "$0")" && pwd)
+adas_build=${ADAS_BUILD:-$script_dir/../target/release/adas-build}
+adas_build_baseline=${ADAS_BUILD_BASELINE:-}
+data_dir=$script_dir/../data
+results=${RESULTS:-$script_dir/sketching_throughput.tsv}
+
+if [ -z "$adas_build_baseline" ]; then
+    echo "ADAS_BUILD_BASELINE is not set, only the current adas-build is measured" >&2
+fi
+
+work_dir=$(mktemp -d)
+trap 'rm -rf "$work_dir"' EXIT
+
+# runs adas-build given as arguments in a fresh directory, prints the sketching time in seconds
+sketching_seconds() {
+    local db_dir="$work_dir/db"
+    rm -rf "$db_dir"
+    mkdir -p "$db_dir"
+    cd "$db_dir" || exit 1
+    "$@" 2>/dev/null | while IFS= read -r line; do
+        case "$line" in
+            Sketching...*) start=$(date +%s.%N) ;;
+            "Sketching done"*) awk -v s="$start" -v e="$(date +%s.%N)" 'BEGIN { printf "%.3f\n", e - s }' ;;
+        esac
+    done
+    cd - > /dev/null || exit 1
+    rm -rf "$db_dir"
+}
+
+# appends a result line and prints it
+record() {
+    local dataset=$1 nb_seqs=$2 binary=$3 batch_size=$4 seconds=$5
+    local rate
+    rate=$(awk -v n="$nb_seqs" -v s="$seconds" 'BEGIN { printf "%.0f", n / s }')
+    printf "%s\t%s\t%s\t%s\t%s\t%s\t%s\n" "$dataset" "$nb_seqs" "$threads" "$binary" "$batch_size" "$seconds" "$rate" | tee -a "$results"
+}
+
+if [ ! -s "$results" ]; then
+    printf "dataset\tsequences\tthreads\tbinary\tbatch_size\tseconds\tsequences_per_s\n" > "$results"
+fi
+echo "# $(date -u +%Y-%m-%d) $(uname -m), $(nproc) cpus, $(git -C "$script_dir" rev-parse --short HEAD 2>/dev/null)" >> "$results"
+
+build_args=(-k 8 -s 128 -t "$threads" --max_nb_connection 64 --hnsw-ef 200)
+for fasta in SAR11_cluster_centroid.fa test_16S_SAR11.fa; do
+    input="$work_dir/$fasta"
+    for r in $(seq 1 "$replicates"); do
+        # distinct ids for each replicate
+        sed "s/^>\([^ ]*\)/>\1_$r/" "$data_dir/$fasta"
+    done > "$input"
+    nb_seqs=$(grep -c '^>' "$input")
+    echo "========== $fasta x $replicates: $nb_seqs sequences, $threads threads =========="
+    if [ -n "$adas_build_baseline" ]; then
+        seconds=$(sketching_seconds "$adas_build_baseline" -i "$input" "${build_args[@]}")
+        record "$fasta" "$nb_seqs" baseline - "$seconds"
+    fi
+    for batch_size in 100 1000 10000; do
+        seconds=$(sketching_seconds "$adas_build" -i "$input" "${build_args[@]}" --batch-size "$batch_size")
+        record "$fasta" "$nb_seqs" current "$batch_size" "$seconds"
+    done
+done
@@ -0,0 +1,212 @@
+//! Abundance of database sequences from the hits of reads.
+//!
+//! Each read is compatible with its hits whose distance is within `delta` of its best hit and at most
+//! `max_distance`: a read whose best hit is farther (off-target, host or chimeric read) is unassigned.
+//! Reads compatible with several references are shared among them by expectation-maximisation:
+//! a read goes to a reference with probability proportional to its abundance divided by its length,
+//! as a longer reference gives more reads for the same number of molecules.
+//! Reads with the same compatible references are grouped in equivalence classes, so EM iterations
+//! cost the number of classes and not the number of reads.
+
+use std::collections::HashMap;
+
+use hnsw_rs::prelude::Neighbour;
+
+/// Collects the compatible references of reads, by equivalence classes
+pub struct AbundanceCollector {
+    delta: f32,
+    max_distance: f32,
+    /// sorted data ids -> number of reads
+    classes: HashMap<Vec<usize>, usize>,
+    nb_reads: usize,
+    nb_unassigned: usize,
+}
+
+impl AbundanceCollector {
+    /// hits at a distance at most the best one plus `delta`, and at most `max_distance`, are compatible with a read
+    pub fn new(delta: f32, max_distance: f32) -> Self {
+        AbundanceCollector {
+            delta,
+            max_distance,
+            classes: HashMap::new(),
+            nb_reads: 0,
+            nb_unassigned: 0,
+        }
+    }
+
+    /// adds a read from its neighbours, a read without neighbour within max_distance is unassigned
+    pub fn add_read(&mut self, neighbours: &[Neighbour]) {
+        let hits: Vec<(usize, f32)> = neighbours.iter().map(|n| (n.d_id, n.distance)).collect();
+        self.add_hits(&hits);
+    }
+
+    /// adds a read from its hits given as (data id, distance)
+    pub fn add_hits(&mut self, hits: &[(usize, f32)]) {
+        self.nb_reads += 1;
+        let best = hits
+            .iter()
+            .map(|(_, distance)| *distance)
+            .filter(|distance| *distance <= self.max_distance)
+            .min_by(|a, b| a.partial_cmp(b).unwrap());
+        let best = match best {
+            Some(best) => best,
+            None => {
+                self.nb_unassigned += 1;
+                return;
+            }
+        };
+        let threshold = (best + self.delta).min(self.max_distance);
+        let mut class: Vec<usize> = hits
+            .iter()
+            .filter(|(_, distance)| *distance <= threshold)
+            .map(|(d_id, _)| *d_id)
+            .collect();
+        class.sort_unstable();
+        class.dedup();
+        *self.classes.entry(class).or_insert(0) += 1;
+    }
+
+    pub fn get_nb_reads(&self) -> usize {
+        self.nb_reads
+    }
+
+    pub fn get_nb_unassigned(&self) -> usize {
+        self.nb_unassigned
+    }
+
+    pub fn get_nb_classes(&self) -> usize {
+        self.classes.len()
+    }
+
+    /// Runs EM over the collected reads, `ref_len` gives the length of a database sequence from its data id
+    pub fn estimate<F: Fn(usize) -> usize>(&self, ref_len: F, max_iter: usize, tolerance: f64) -> Abundances {
+        // local index of references seen in classes
+        let mut d_ids: Vec<usize> = self.classes.keys().flatten().copied().collect();
+        d_ids.sort_unstable();
+        d_ids.dedup();
+        let local: HashMap<usize, usize> = d_ids.iter().enumerate().map(|(i, d_id)| (*d_id, i)).collect();
+        let classes: Vec<(Vec<usize>, f64)> = self
+            .classes
+            .iter()
+            .map(|(class, count)| (class.iter().map(|d_id| local[d_id]).collect(), *count as f64))
+            .collect();
+        let lens: Vec<f64> = d_ids.iter().map(|d_id| ref_len(*d_id).max(1) as f64).collect();
+        let (counts, nb_iterations) = em_counts(&classes, &lens, max_iter, tolerance);
+        // relative abundance of molecules: reads by base of reference, normalised
+        let by_base: Vec<f64> = counts.iter().zip(lens.iter()).map(|(c, l)| c / l).collect();
+        let total: f64 = by_base.iter().sum();
+        let abundances = by_base
+            .iter()
+            .map(|x| if total > 0. { x / total } else { 0. })
+            .collect();
+        Abundances {
+            d_ids,
+            counts,
+            abundances,
+            nb_iterations,
+        }
+    } // end of estimate
+} // end of impl AbundanceCollector
+
+/// Result of EM
+pub struct Abundances {
+    /// data ids of references with at least one compatible read
+    pub d_ids: Vec<usize>,
+    /// expected number of reads of each reference
+    pub counts: Vec<f64>,
+    /// relative abundance of each reference, reads by base normalised to sum 1
+    pub abundances: Vec<f64>,
+    pub nb_iterations: usize,
+}
+
+/// EM on equivalence classes given as (references, number of reads).
+/// Returns the expected read counts of references and the number of iterations done.
+/// Iterations stop when no count moves more than `tolerance` reads.
+pub fn em_counts(classes: &[(Vec<usize>, f64)], lens: &[f64], max_iter: usize, tolerance: f64) -> (Vec<f64>, usize) {
+    let nb_refs = lens.len();
+    let nb_reads: f64 = classes.iter().map(|(_, count)| count).sum();
+    // start from a uniform read distribution
+    let mut theta = vec![1. / nb_refs.max(1) as f64; nb_refs];
+    let mut counts = vec![0f64; nb_refs];
+    let mut nb_iterations = 0;
+    while nb_iterations < max_iter {
+        nb_iterations += 1;
+        let mut new_counts = vec![0f64; nb_refs];
+        for (class, count) in classes {
+            let norm: f64 = class.iter().map(|r| theta[*r] / lens[*r]).sum();
+            if norm <= 0. {
+                continue;
+            }
+            for r in class {
+                new_counts[*r] += count * theta[*r] / lens[*r] / norm;
+            }
+        }
+        let max_change = new_counts
+            .iter()
+            .zip(counts.iter())
+            .map(|(new, old)| (new - old).abs())
+            .fold(0., f64::max);
+        counts = new_counts;
+        for (t, c) in theta.iter_mut().zip(counts.iter()) {
+            *t = c / nb_reads;
+        }
+        if max_change < tolerance {
+            break;
+        }
+    }
+    (counts, nb_iterations)
+} // end of em_counts
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 30 reads only on reference 0, 10 only on 1 and 20 shared. With equal lengths, at the fixed point
+    /// c0 = 30 + 20 c0 / 60, so c0 = 45 and c1 = 15
+    #[test]
+    fn em_equal_lengths() {
+        let classes = vec![(vec![0], 30.), (vec![1], 10.), (vec![0, 1], 20.)];
+        let (counts, nb_iterations) = em_counts(&classes, &[1000., 1000.], 1000, 1e-9);
+        assert!(nb_iterations < 1000);
+        assert!((counts[0] - 45.).abs() < 1e-6);
+        assert!((counts[1] - 15.).abs() < 1e-6);
+    }
+
+    /// same reads with reference 1 twice as long: shared reads go by abundance over length,
+    /// c0 = 30 + 20 (c0 / 1000) / (c0 / 1000 + c1 / 2000) gives c0^2 - 10 c0 - 1800 = 0
+    #[test]
+    fn em_lengths() {
+        let classes = vec![(vec![0], 30.), (vec![1], 10.), (vec![0, 1], 20.)];
+        let (counts, _) = em_counts(&classes, &[1000., 2000.], 1000, 1e-9);
+        let c0 = (10. + 7300f64.sqrt()) / 2.;
+        assert!((counts[0] - c0).abs() < 1e-6);
+        assert!((counts[1] - (60. - c0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn collector_classes_and_cutoff() {
+        let mut collector = AbundanceCollector::new(0.02, 0.5);
+        for _ in 0..30 {
+            collector.add_hits(&[(10, 0.1), (11, 0.3)]);
+        }
+        for _ in 0..10 {
+            collector.add_hits(&[(11, 0.2), (10, 0.25)]);
+        }
+        for _ in 0..20 {
+            collector.add_hits(&[(11, 0.1), (10, 0.11)]);
+        }
+        // off-target reads, and a read without hit
+        for _ in 0..5 {
+            collector.add_hits(&[(10, 0.9), (11, 0.95)]);
+        }
+        collector.add_hits(&[]);
+        assert_eq!(collector.get_nb_reads(), 66);
+        assert_eq!(collector.get_nb_unassigned(), 6);
+        assert_eq!(collector.get_nb_classes(), 3);
+        let estimate = collector.estimate(|_| 1000, 1000, 1e-9);
+        assert_eq!(estimate.d_ids, vec![10, 11]);
+        assert!((estimate.counts[0] - 45.).abs() < 1e-6);
+        assert!((estimate.abundances[0] - 0.75).abs() < 1e-6);
+        assert!((estimate.abundances[1] - 0.25).abs() < 1e-6);
+    }
+}
@@ -1,44 +1,26 @@
+use clap::parser::ValueSource;
 use clap::{Arg, ArgAction, Command};
-use crossbeam::channel::{unbounded, Sender, Receiver};
-use needletail::{parse_fastx_file, Sequence};
 use std::path::Path;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::time::Instant;
 use std::path::PathBuf;
 use num_cpus;
 
-use hnsw_rs::prelude::*;
 use gsearch::utils::idsketch::{Id, ItemDict};
 use gsearch::utils::parameters::*;
-use gsearch::utils::dumpload::*;
 use gsearch::utils::SeqDict;
 
 use kmerutils::sketcharg::{SeqSketcherParams, SketchAlgo};
-use kmerutils::base::{kmergenerator::*, Kmer32bit, CompressedKmerT};
+use kmerutils::base::Kmer32bit;
 use kmerutils::sketching::setsketchert::*;
 use kmerutils::sketcharg::DataType;
-use kmerutils::base::alphabet::Alphabet2b;
-use kmerutils::base::sequence::Sequence as SequenceStruct;
 use log::info;
 
-fn ascii_to_seq(bases: &[u8]) -> Result<SequenceStruct, ()> {
-    let alphabet = Alphabet2b::new();
-    let mut seq = SequenceStruct::with_capacity(2, bases.len());
-    seq.encode_and_add(bases, &alphabet);
-    Ok(seq)
-} // end of ascii_to_seq
-
-// Define the k-mer hash function as a function
-fn kmer_hash_fn_32bit(kmer: &Kmer32bit) -> <Kmer32bit as CompressedKmerT>::Val {
-    let canonical = kmer.reverse_complement().min(*kmer);
-    let nb_alphabet_bits = Alphabet2b::new().get_nb_bits();
-    let mask: <Kmer32bit as CompressedKmerT>::Val =
-        ((1u64 << (nb_alphabet_bits * kmer.get_nb_base())) - 1)
-            .try_into()
-            .unwrap();
-    let hashval = canonical.get_compressed_value() & mask;
-    hashval
-}
+use adas::buildconfig::BuildConfig;
+use adas::seqstore::SeqStoreWriter;
+use adas::sketchcode::{SketchCode, SketchIndex};
+use adas::sketching::sketch_fasta_batches;
+use adas::spill::SignatureSpool;
+use adas::taxonomy::attach_taxonomy;
 
 fn main() {
     // Initialize logger (optional)
@@ -59,6 +41,14 @@ fn main() {
                 .action(ArgAction::Set)
                 .value_parser(clap::value_parser!(String)),
         )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("BUILD_CONFIG")
+                .help("JSON build config (e.g. written by adas-tune) giving k-mer size, sketch size and HNSW parameters not set on the command line")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String)),
+        )
         .arg(
             Arg::new("kmer_size")
                 .short('k')
@@ -89,6 +79,23 @@ fn main() {
                 .value_parser(clap::value_parser!(usize))
                 .default_value("1"),
         )
+        .arg(
+            Arg::new("batch_size")
+                .long("batch-size")
+                .value_name("BATCH_SIZE")
+                .help("Number of sequences sketched together")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("10000"),
+        )
+        .arg(
+            Arg::new("max_memory")
+                .long("max-memory")
+                .value_name("MB")
+                .help("Memory budget in MB for signatures waiting for insertion, beyond it they are spilled to a temporary file. The HNSW keeps every inserted point: peak memory is the whole graph plus one chunk of this size")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize)),
+        )
         .arg(
             Arg::new("hnsw_ef")
                 .long("hnsw-ef")
@@ -116,12 +123,40 @@ fn main() {
                 .action(ArgAction::Set)
                 .value_parser(clap::value_parser!(f64))
         )
+        .arg(
+            Arg::new("sketch_code")
+                .long("sketch-code")
+                .value_name("CODE")
+                .help("Storage of sketch values in the index: f64, hash codes u32 or u16, or b8, b4, b2, b1 bits packed codes")
+                .action(ArgAction::Set)
+                .value_parser(["f64", "u32", "u16", "b8", "b4", "b2", "b1"])
+                .default_value("f64"),
+        )
+        .arg(
+            Arg::new("store_seqs")
+                .long("store-seqs")
+                .help("Store compressed sequences in the database directory, so that adas-fetch can retrieve them")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("taxonomy")
+                .long("taxonomy")
+                .value_name("TAXONOMY_TSV")
+                .help("Taxonomy TSV file (sequence id, lineage separated by ;) attached to the database for adas-classify")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String)),
+        )
         .get_matches();
 
     let fasta_path = matches.get_one::<String>("input").unwrap().to_string();
     let kmer_size = *matches.get_one::<usize>("kmer_size").unwrap();
     let sketch_size = *matches.get_one::<usize>("sketch_size").unwrap();
     let num_threads = *matches.get_one::<usize>("threads").unwrap();
+    let batch_size = *matches.get_one::<usize>("batch_size").unwrap();
+    let max_memory = matches.get_one::<usize>("max_memory").map(|mb| {
+        mb.checked_mul(1024 * 1024)
+            .unwrap_or_else(|| panic!("--max-memory {} MB does not fit in {} bytes", mb, usize::MAX))
+    });
     let num_cpus = num_cpus::get();
     let num_threads = if num_threads > num_cpus {
         num_cpus
@@ -132,6 +167,36 @@ fn main() {
     let hnsw_ef = *matches.get_one::<usize>("hnsw_ef").unwrap();
     let hnsw_max_nb_conn = *matches.get_one::<u8>("hnsw_max_nb_conn").unwrap();
     let scale_modify = *matches.get_one::<f64>("scale_modification").unwrap();
+    // A build config gives the parameters not set on the command line
+    let (kmer_size, sketch_size, hnsw_max_nb_conn, hnsw_ef, scale_modify) = match matches.get_one::<String>("config") {
+        Some(config_path) => {
+            let config = BuildConfig::reload_json(Path::new(config_path))
+                .unwrap_or_else(|e| panic!("Cannot read build config {} : {:?}", config_path, e));
+            println!("Build config {} : {:?}", config_path, config);
+            let on_command_line = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+            (
+                if on_command_line("kmer_size") { kmer_size } else { config.kmer_size },
+                if on_command_line("sketch_size") { sketch_size } else { config.sketch_size },
+                if on_command_line("hnsw_max_nb_conn") { hnsw_max_nb_conn } else { config.max_nb_connection },
+                if on_command_line("hnsw_ef") { hnsw_ef } else { config.hnsw_ef },
+                if on_command_line("scale_modification") { scale_modify } else { config.scale_modify },
+            )
+        }
+        None => (kmer_size, sketch_size, hnsw_max_nb_conn, hnsw_ef, scale_modify),
+    };
+    let store_seqs = matches.get_flag("store_seqs");
+    let taxonomy_path = matches.get_one::<String>("taxonomy").cloned();
+    let sketch_code: SketchCode = matches.get_one::<String>("sketch_code").unwrap().parse().unwrap();
+    // parameters are checked once merged with the build config, which may have set them
+    if let Err(e) = sketch_code.check_sketch_size(sketch_size) {
+        panic!("{}", e);
+    }
+    if sketch_size == 0 || hnsw_max_nb_conn == 0 || hnsw_ef == 0 {
+        panic!("sketch size, max_nb_connection and hnsw-ef must be positive");
+    }
+    if !(0.2..=1.).contains(&scale_modify) {
+        panic!("scale modification factor must be in [0.2,1], got {}", scale_modify);
+    }
 
     if kmer_size > 15 {
         panic!("kmer_size must be ≤14");
@@ -160,130 +225,75 @@ fn main() {
     info!("Calling sketch_compressedkmer for OptDensHashSketch::<Kmer32bit, f64>");
     let sketcher = Sketcher::new(&sketch_args);
 
-    // Use Crossbeam's unbounded channel:
-    let (tx, rx): (Sender<(Vec<u8>, Vec<u8>)>, Receiver<(Vec<u8>, Vec<u8>)>) = unbounded();
-
-    // Spawn a producer thread to read the FASTA file and send sequences
-    let fasta_path_clone = fasta_path.clone();
-    let producer_handle = thread::spawn(move || {
-        let mut reader = parse_fastx_file(Path::new(&fasta_path_clone))
-            .expect("Invalid path/file for FASTA");
-
-        // Read sequences and send them to the channel
-        while let Some(record) = reader.next() {
-            let seqrec = record.expect("Invalid record");
-            let seq_id = seqrec.id().to_owned(); // Vec<u8>
-            let seq_seq = seqrec.normalize(false).into_owned(); // Vec<u8>
-            tx.send((seq_id, seq_seq)).expect("Could not send data");
+    // Sequences are sketched by batches on the rayon pool and collected in file order: the data id of a
+    // sequence is its rank in the file, and sequences are written to the store in the same order
+    let mut seqstore = if store_seqs {
+        let writer = SeqStoreWriter::create(Path::new("."))
+            .unwrap_or_else(|e| panic!("Cannot create sequence store : {:?}", e));
+        Some(writer)
+    } else {
+        None
+    };
+    // Signatures beyond the memory budget wait for insertion in a temporary file
+    let mut signatures = SignatureSpool::new(max_memory, &std::env::temp_dir());
+    let mut itemv: Vec<ItemDict> = Vec::new();
+    let start = Instant::now();
+    let nb_sketched = sketch_fasta_batches(&fasta_path, &sketcher, batch_size, |batch, lens, batch_signatures| {
+        signatures.extend(batch_signatures).expect("Could not spill signatures");
+        for ((seq_id, seq_seq), len) in batch.into_iter().zip(lens) {
+            if let Some(writer) = seqstore.as_mut() {
+                writer.push(&seq_seq).expect("Could not write sequence store");
+            }
+            let id = Id::new(&fasta_path, &String::from_utf8(seq_id).unwrap());
+            itemv.push(ItemDict::new(id, len));
         }
-        // Close the sending side
-        drop(tx);
     });
-
-    // Set up shared storage for signatures and metadata
-    let signatures = Arc::new(Mutex::new(Vec::new()));
-    let itemv = Arc::new(Mutex::new(Vec::new()));
-
-    // We’ll spawn multiple consumer threads
-    let mut consumer_handles = Vec::with_capacity(num_threads);
-
-    for _ in 0..num_threads {
-        // Clone everything needed inside this thread:
-        let rx_clone = rx.clone(); // Crossbeam receivers can be cloned
-        let sketcher_clone = sketcher.clone();
-        let signatures_clone = Arc::clone(&signatures);
-        let itemv_clone = Arc::clone(&itemv);
-        let fasta_path_clone_for_consumer = fasta_path.clone();
-
-        let handle = thread::spawn(move || {
-            // Each consumer thread pulls data in parallel
-            for (seq_id, seq_seq) in rx_clone.iter() {
-                // Convert the sequence to the format needed by the sketcher
-                let seq = ascii_to_seq(&seq_seq).unwrap();
-                let vseq = vec![&seq];
-
-                // Sketch the sequence
-                let signatures_vec =
-                    sketcher_clone.sketch_compressedkmer(&vseq, kmer_hash_fn_32bit);
-
-                // We only have one sequence in the vector
-                let signature = signatures_vec.into_iter().next().unwrap();
-
-                // Store the signature (only 1 item)
-                {
-                    let mut signatures_lock = signatures_clone.lock().unwrap();
-                    signatures_lock.push(signature);
-                }
-                // Store sequence metadata
-                {
-                    let mut itemv_lock = itemv_clone.lock().unwrap();
-                    // Create an Id instance
-                    let id = Id::new(
-                        &fasta_path_clone_for_consumer,
-                        &String::from_utf8(seq_id).unwrap(),
-                    );
-                    // Create an ItemDict instance
-                    let item = ItemDict::new(id, seq.size());
-                    itemv_lock.push(item);
-                }
-                // Here seq and seq_seq are dropped -> memory freed
-            }
-        });
-        consumer_handles.push(handle);
-    }
-
-    // Wait for the producer to finish
-    producer_handle.join().expect("Producer thread panicked");
-
-    // Wait for all consumers to finish
-    for handle in consumer_handles {
-        handle.join().expect("Consumer thread panicked");
+    let elapsed = start.elapsed().as_secs_f64();
+    if let Some(writer) = seqstore {
+        writer.finish().expect("Could not write sequence store");
+        println!("Sequences stored in {} and {}", adas::seqstore::SEQSTORE_BIN, adas::seqstore::SEQSTORE_IDX);
     }
-    println!("Sketching done...");
+    println!(
+        "Sketching done: {} sequences in {:.2} s, {:.0} sequences/s",
+        nb_sketched,
+        elapsed,
+        nb_sketched as f64 / elapsed.max(1e-9)
+    );
     // ---- Now build HNSW in the main thread ----
     println!("Building HNSW index...");
 
-    // Retrieve the collected signatures and sequence metadata
-    let signatures = Arc::try_unwrap(signatures)
-        .expect("Multiple references to signatures remain")
-        .into_inner()
-        .expect("Mutex was poisoned in signatures");
-    let mut itemv = match Arc::try_unwrap(itemv) {
-            Ok(mutex) => mutex.into_inner().unwrap(),
-            Err(_arc_mutex) => {
-                // Handle the case where the Arc has more than one strong reference.
-                panic!("Cannot unwrap Arc because there are multiple references");
-            }
-        };
-
-    // Create data as Vec<(&Vec<f64>, usize)> for HNSW insertion
-    let data: Vec<(&Vec<f64>, usize)> = signatures
-        .iter()
-        .enumerate()
-        .map(|(idx, sig)| (sig, idx))
-        .collect();
-
     // Build the HNSW index
     let max_nb_conn: u8 = 255.min(hnsw_max_nb_conn as u8);
     let hnsw_params = HnswParams::new(2_500_000, hnsw_ef, max_nb_conn, scale_modify);
 
-    let mut hnsw = Hnsw::<
-        <Sketcher as SeqSketcherT<Kmer>>::Sig,
-        DistHamming,
-    >::new(
+    // Signatures are stored with the type of the sketch code, f64 signatures are encoded at insertion
+    println!("Sketch values stored as {} ({} bits by value)", sketch_code, sketch_code.get_bits());
+    let mut hnsw = SketchIndex::new(
+        sketch_code,
         hnsw_params.get_max_nb_connection() as usize,
         hnsw_params.capacity,
         16, // Adjust as needed
         hnsw_params.get_ef(),
-        DistHamming {},
     );
 
     hnsw.modify_level_scale(scale_modify);
     hnsw.set_extend_candidates(true);
     hnsw.set_keeping_pruned(false);
 
-    // Parallel insert all signatures to build HNSW index
-    hnsw.parallel_insert(&data);
+    // Parallel insert of signatures by chunks fitting in the memory budget, data id is the rank in the file
+    if signatures.get_nb_spilled() > 0 {
+        println!("{} signatures spilled to disk, inserted by chunks", signatures.get_nb_spilled());
+    }
+    signatures
+        .drain_chunks(|first, chunk| {
+            let data: Vec<(&Vec<f64>, usize)> = chunk
+                .iter()
+                .enumerate()
+                .map(|(idx, sig)| (sig, first + idx))
+                .collect();
+            hnsw.parallel_insert(&data);
+        })
+        .expect("Could not read spilled signatures");
 
     // Create seqdict
     let mut seqdict = SeqDict::new(1000000);
@@ -296,7 +306,16 @@ fn main() {
     // Dump all data
     let dump_path = PathBuf::from(".");
     let dump_path_ref = &dump_path;
-    let _ = dumpall(dump_path_ref, &hnsw, &seqdict, &processing_params);
+    if let Err(e) = hnsw.dump(dump_path_ref, &seqdict, &processing_params) {
+        log::error!("could not dump database : {}", e);
+    }
+
+    if let Some(taxonomy_path) = taxonomy_path {
+        match attach_taxonomy(dump_path_ref, Path::new(&taxonomy_path)) {
+            Ok(nb_lineages) => println!("Taxonomy attached to the database, {} lineages", nb_lineages),
+            Err(e) => log::error!("could not attach taxonomy {} : {:?}", taxonomy_path, e),
+        }
+    }
 
     println!("HNSW index built successfully.\n");
 }
@@ -1,12 +1,299 @@
-use minimap2::Aligner;
+use minimap2::{Aligner, Mapping, Strand};
 use needletail::parse_fastx_file;
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use std::sync::Arc;
 use std::thread;
 use clap::{Arg, ArgAction, Command, value_parser};
+use clap::parser::ValueSource;
+use std::collections::{BTreeMap, HashSet};
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufWriter, Write};
 use num_cpus;
 
+use adas::presets::{preset_from_name, PRESET_NAMES};
+use adas::sce::{SceAligner, SceHit, SceParams};
+
+/// A mapping of a query with what PAF and SAM output need, from minimap2 or from the native engine.
+/// Coordinates are 0-based, query ones on the forward strand of the query.
+struct ChainMapping {
+    target_name: String,
+    target_len: usize,
+    target_start: usize,
+    target_end: usize,
+    query_start: usize,
+    query_end: usize,
+    reverse: bool,
+    match_len: usize,
+    block_len: usize,
+    mapq: u32,
+    is_primary: bool,
+    is_supplementary: bool,
+    /// None if no base level alignment was computed
+    nm: Option<usize>,
+    /// (length, operation) with SAM operation codes
+    cigar: Option<Vec<(u32, u8)>>,
+}
+
+impl ChainMapping {
+    fn from_minimap2(mapping: &Mapping) -> Self {
+        ChainMapping {
+            target_name: mapping.target_name.as_ref().map(|n| n.to_string()).unwrap_or_else(|| String::from("*")),
+            target_len: mapping.target_len as usize,
+            target_start: mapping.target_start as usize,
+            target_end: mapping.target_end as usize,
+            query_start: mapping.query_start as usize,
+            query_end: mapping.query_end as usize,
+            reverse: matches!(mapping.strand, Strand::Reverse),
+            match_len: mapping.match_len as usize,
+            block_len: mapping.block_len as usize,
+            mapq: mapping.mapq,
+            is_primary: mapping.is_primary,
+            is_supplementary: mapping.is_supplementary,
+            nm: mapping.alignment.as_ref().map(|a| a.nm as usize),
+            cigar: mapping.alignment.as_ref().and_then(|a| a.cigar.clone()),
+        }
+    }
+
+    fn from_sce(hit: SceHit, aligner: &SceAligner) -> Self {
+        let index = aligner.get_index();
+        ChainMapping {
+            target_name: index.get_name(hit.ref_id).to_string(),
+            target_len: index.get_seq(hit.ref_id).len(),
+            target_start: hit.ref_start,
+            target_end: hit.ref_end,
+            query_start: hit.query_start,
+            query_end: hit.query_end,
+            reverse: hit.rev,
+            match_len: hit.match_len,
+            block_len: hit.block_len,
+            mapq: hit.mapq,
+            is_primary: hit.is_primary,
+            is_supplementary: hit.is_supplementary,
+            nm: hit.nm,
+            cigar: hit.cigar,
+        }
+    }
+} // end of impl ChainMapping
+
+/// CIGAR string from (length, operation) pairs
+fn cigar_string(cigar: &[(u32, u8)]) -> String {
+    const OPS: &[u8] = b"MIDNSHP=X";
+    cigar
+        .iter()
+        .map(|(len, op)| format!("{}{}", len, OPS[*op as usize] as char))
+        .collect()
+}
+
+/// gap-compressed per-base divergence as minimap2 `de` tag: gaps count once whatever their length
+fn gap_compressed_divergence(mapping: &ChainMapping, cigar: &[(u32, u8)]) -> f64 {
+    let mut nb_gap_open = 0;
+    let mut nb_gap_bases = 0;
+    for (len, op) in cigar {
+        // minimap2 cigar operations: 1 insertion, 2 deletion
+        if *op == 1 || *op == 2 {
+            nb_gap_open += 1;
+            nb_gap_bases += *len as i64;
+        }
+    }
+    let denom = mapping.block_len as i64 - nb_gap_bases + nb_gap_open;
+    if denom <= 0 {
+        return 0.;
+    }
+    1. - mapping.match_len as f64 / denom as f64
+}
+
+/// In all-vs-all mode, the mappings of a query to report: self hits are dropped, a pair of reads is reported
+/// only when mapping the read whose name comes first (as minimap2 does without dual mappings), and once
+/// among the mappings of that read. Nothing is kept from one query to the next.
+fn overlap_mappings<'a>(query_name: &str, mappings: &'a [ChainMapping]) -> Vec<&'a ChainMapping> {
+    let mut targets: HashSet<&str> = HashSet::new();
+    mappings
+        .iter()
+        .filter(|mapping| {
+            let target_name = mapping.target_name.as_str();
+            target_name != "*" && query_name < target_name && targets.insert(target_name)
+        })
+        .collect()
+}
+
+/// A query with its mappings. Sequence and qualities are kept only for SAM output
+struct QueryResult {
+    name: String,
+    len: usize,
+    seq: Vec<u8>,
+    qual: Option<Vec<u8>>,
+    mappings: Vec<ChainMapping>,
+}
+
+/// Names and lengths of the sequences of the minimap2 index, in index order
+fn minimap2_references(aligner: &Aligner) -> Vec<(String, usize)> {
+    (0..aligner.n_seq() as usize)
+        .filter_map(|i| aligner.get_seq(i))
+        .map(|seq| {
+            // names are nul terminated C strings owned by the index
+            let name = unsafe { std::ffi::CStr::from_ptr(seq.name) };
+            (name.to_string_lossy().into_owned(), seq.len as usize)
+        })
+        .collect()
+}
+
+/// Write the SAM header: @HD, one @SQ by reference sequence (name, length) in index order, and @PG with the command line
+fn write_sam_header<W: Write>(out: &mut W, references: &[(String, usize)]) -> std::io::Result<()> {
+    writeln!(out, "@HD\tVN:1.6\tSO:unsorted")?;
+    for (name, len) in references {
+        writeln!(out, "@SQ\tSN:{}\tLN:{}", name, len)?;
+    }
+    let command_line = std::env::args().collect::<Vec<String>>().join(" ");
+    writeln!(
+        out,
+        "@PG\tID:adas-chain\tPN:adas-chain\tVN:{}\tCL:{}",
+        env!("CARGO_PKG_VERSION"),
+        command_line
+    )
+} // end of write_sam_header
+
+fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|b| match b {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            b'a' => b't',
+            b'c' => b'g',
+            b'g' => b'c',
+            b't' => b'a',
+            _ => b'N',
+        })
+        .collect()
+}
+
+/// Write the SAM records of a query: one by mapping, or one unmapped record if there is no mapping.
+/// As in minimap2, supplementary alignments are hard clipped and secondary alignments have no SEQ/QUAL.
+fn write_sam_records<W: Write>(out: &mut W, query: &QueryResult) -> std::io::Result<()> {
+    let qual_str = |qual: Option<&[u8]>| -> String {
+        match qual {
+            Some(q) => String::from_utf8_lossy(q).into_owned(),
+            None => String::from("*"),
+        }
+    };
+    if query.mappings.is_empty() {
+        return writeln!(
+            out,
+            "{}\t4\t*\t0\t0\t*\t*\t0\t0\t{}\t{}",
+            query.name,
+            String::from_utf8_lossy(&query.seq),
+            qual_str(query.qual.as_deref())
+        );
+    }
+    for mapping in query.mappings.iter() {
+        let reverse = mapping.reverse;
+        let mut flag = 0u16;
+        if reverse {
+            flag |= 0x10;
+        }
+        if !mapping.is_primary {
+            flag |= 0x100;
+        } else if mapping.is_supplementary {
+            flag |= 0x800;
+        }
+        // sequence and qualities in reference orientation
+        let (seq, qual) = if reverse {
+            let qual = query.qual.as_ref().map(|q| q.iter().rev().copied().collect::<Vec<u8>>());
+            (reverse_complement(&query.seq), qual)
+        } else {
+            (query.seq.clone(), query.qual.clone())
+        };
+        // clips at left and right in reference orientation
+        let (clip_left, clip_right) = if reverse {
+            (query.len - mapping.query_end, mapping.query_start)
+        } else {
+            (mapping.query_start, query.len - mapping.query_end)
+        };
+        let hard_clip = mapping.is_supplementary;
+        let clip_op = if hard_clip { 'H' } else { 'S' };
+        let cigar = mapping
+            .cigar
+            .as_ref()
+            .map(|cigar_ops| {
+                let mut cigar = String::new();
+                if clip_left > 0 {
+                    cigar.push_str(&format!("{}{}", clip_left, clip_op));
+                }
+                cigar.push_str(&cigar_string(cigar_ops));
+                if clip_right > 0 {
+                    cigar.push_str(&format!("{}{}", clip_right, clip_op));
+                }
+                cigar
+            })
+            .unwrap_or_else(|| String::from("*"));
+        let (seq_str, qual_field) = if !mapping.is_primary {
+            (String::from("*"), String::from("*"))
+        } else if hard_clip {
+            let range = clip_left..(query.len - clip_right);
+            (
+                String::from_utf8_lossy(&seq[range.clone()]).into_owned(),
+                qual_str(qual.as_ref().map(|q| &q[range])),
+            )
+        } else {
+            (String::from_utf8_lossy(&seq).into_owned(), qual_str(qual.as_deref()))
+        };
+        write!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}\t*\t0\t0\t{}\t{}",
+            query.name,
+            flag,
+            mapping.target_name,
+            mapping.target_start + 1,
+            mapping.mapq,
+            cigar,
+            seq_str,
+            qual_field
+        )?;
+        if let Some(nm) = mapping.nm {
+            write!(out, "\tNM:i:{}", nm)?;
+        }
+        writeln!(out, "\ttp:A:{}", if mapping.is_primary { 'P' } else { 'S' })?;
+    }
+    Ok(())
+} // end of write_sam_records
+
+/// Write a mapping as a PAF line: the 12 mandatory columns then tp, NM, de and cg tags
+fn write_paf_line<W: Write>(
+    out: &mut W,
+    query_name: &str,
+    query_len: usize,
+    mapping: &ChainMapping,
+) -> std::io::Result<()> {
+    let strand = if mapping.reverse { '-' } else { '+' };
+    write!(
+        out,
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\ttp:A:{}",
+        query_name,
+        query_len,
+        mapping.query_start,
+        mapping.query_end,
+        strand,
+        mapping.target_name,
+        mapping.target_len,
+        mapping.target_start,
+        mapping.target_end,
+        mapping.match_len,
+        mapping.block_len,
+        mapping.mapq,
+        if mapping.is_primary { 'P' } else { 'S' }
+    )?;
+    if let Some(nm) = mapping.nm {
+        write!(out, "\tNM:i:{}", nm)?;
+    }
+    if let Some(cigar) = mapping.cigar.as_ref() {
+        write!(out, "\tde:f:{:.4}", gap_compressed_divergence(mapping, cigar))?;
+        write!(out, "\tcg:Z:{}", cigar_string(cigar))?;
+    }
+    writeln!(out)
+} // end of write_paf_line
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Parse command-line arguments using Clap 4.3
     let matches = Command::new("adas-chaining")
@@ -18,7 +305,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .long("reference")
                 .value_name("REFERENCE_FASTA")
                 .help("Reference FASTA file")
-                .required(true)
+                .required_unless_present("all_vs_all")
                 .action(ArgAction::Set)
                 .value_parser(value_parser!(String)),
         )
@@ -47,17 +334,121 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .short('o')
                 .long("output")
                 .value_name("OUTPUT_PATH")
-                .help("Output path to write the results (SAM format)")
+                .help("Output path to write the results (PAF or SAM format), - for stdout")
                 .required(true)
                 .action(ArgAction::Set)
                 .value_parser(value_parser!(String)),
         )
+        .arg(
+            Arg::new("outfmt")
+                .long("outfmt")
+                .value_name("FORMAT")
+                .help("Output format")
+                .action(ArgAction::Set)
+                .value_parser(["paf", "sam"])
+                .default_value("paf"),
+        )
+        .arg(
+            Arg::new("preset")
+                .short('x')
+                .long("preset")
+                .value_name("PRESET")
+                .help("minimap2 preset, depends on sequencing technology")
+                .action(ArgAction::Set)
+                .value_parser(PRESET_NAMES)
+                .default_value("map-ont"),
+        )
+        .arg(
+            Arg::new("engine")
+                .long("engine")
+                .value_name("ENGINE")
+                .help("Alignment engine: minimap2, or native for the Rust seed-chain-extend engine (same presets and overrides)")
+                .action(ArgAction::Set)
+                .value_parser(["minimap2", "native"])
+                .default_value("minimap2"),
+        )
+        .arg(
+            Arg::new("kmer_size")
+                .short('k')
+                .long("kmer-size")
+                .value_name("KMER_SIZE")
+                .help("Minimizer k-mer size, overrides the preset")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(i16).range(1..=28)),
+        )
+        .arg(
+            Arg::new("window_size")
+                .short('w')
+                .long("window-size")
+                .value_name("WINDOW_SIZE")
+                .help("Minimizer window size, overrides the preset")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(i16).range(1..=255)),
+        )
+        .arg(
+            Arg::new("bandwidth")
+                .long("bandwidth")
+                .value_name("BANDWIDTH")
+                .help("Bandwidth for chaining and DP alignment, overrides the preset")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(i32).range(1..)),
+        )
+        .arg(
+            Arg::new("min_chain_score")
+                .long("min-chain-score")
+                .value_name("MIN_CHAIN_SCORE")
+                .help("Minimal chaining score, overrides the preset")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(i32).range(0..)),
+        )
+        .arg(
+            Arg::new("ordered")
+                .long("ordered")
+                .help("Write results in query order (default: as soon as each query is aligned)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("all_vs_all")
+                .long("all-vs-all")
+                .help("Overlap the query reads against themselves (ava-ont preset unless --preset is given), PAF output")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("reference"),
+        )
+        .arg(
+            Arg::new("no_cigar")
+                .long("no-cigar")
+                .help("Chaining only, do not compute base level alignment (faster, no CIGAR in output)")
+                .action(ArgAction::SetTrue),
+        )
         .get_matches();
 
-    let ref_path = matches.get_one::<String>("reference").unwrap();
+    let all_vs_all = matches.get_flag("all_vs_all");
+    let ordered = matches.get_flag("ordered");
     let query_path = matches.get_one::<String>("query").unwrap();
+    // in all-vs-all mode the reads are indexed and mapped against themselves
+    let ref_path = if all_vs_all {
+        query_path
+    } else {
+        matches.get_one::<String>("reference").unwrap()
+    };
     let num_threads = *matches.get_one::<usize>("threads").unwrap();
     let output_path = matches.get_one::<String>("output").unwrap();
+    let sam_out = matches.get_one::<String>("outfmt").unwrap() == "sam";
+    let preset_name = if all_vs_all && matches.value_source("preset") == Some(ValueSource::DefaultValue) {
+        "ava-ont"
+    } else {
+        matches.get_one::<String>("preset").unwrap().as_str()
+    };
+    let preset = preset_from_name(preset_name);
+    if all_vs_all && sam_out {
+        panic!("all-vs-all mode writes PAF only");
+    }
+    let kmer_size = matches.get_one::<i16>("kmer_size").copied();
+    let window_size = matches.get_one::<i16>("window_size").copied();
+    let bandwidth = matches.get_one::<i32>("bandwidth").copied();
+    let min_chain_score = matches.get_one::<i32>("min_chain_score").copied();
+    let no_cigar = matches.get_flag("no_cigar");
+    let native = matches.get_one::<String>("engine").unwrap() == "native";
 
     let num_cpus = num_cpus::get();
     let num_threads = if num_threads > num_cpus {
@@ -65,66 +456,201 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     } else {
         num_threads
     };
-    // Build the aligner with the specified reference and number of threads
-    let aligner = Aligner::builder()
-        .map_ont()
-        .with_index_threads(num_threads)
-        .with_sam_out()
-        .with_cigar()
-        .with_index(ref_path, None)
-        .expect("Unable to build index");
-
-    // Set up a channel for passing sequences between threads
-    let (sender, receiver): (Sender<(String, Vec<u8>)>, Receiver<(String, Vec<u8>)>) = unbounded();
+    // Build the aligner with the specified preset, reference and number of threads,
+    // explicit seeding and chaining parameters override the preset ones
+    let mut aligner: Option<Aligner> = None;
+    let mut native_aligner: Option<Arc<SceAligner>> = None;
+    if native {
+        if ref_path.ends_with(".mmi") {
+            panic!("the native engine indexes FASTA references, {} is a minimap2 index", ref_path);
+        }
+        let mut params = SceParams::from_preset(preset_name).expect("unknown preset");
+        if let Some(k) = kmer_size {
+            params.k = k as usize;
+        }
+        if let Some(w) = window_size {
+            params.w = w as usize;
+        }
+        if let Some(bw) = bandwidth {
+            params.bandwidth = bw as usize;
+        }
+        if let Some(min_chain_score) = min_chain_score {
+            params.min_chain_score = min_chain_score;
+        }
+        if no_cigar {
+            params.extend = false;
+        }
+        // references are named by the first word of their header, as minimap2 does
+        let mut names = Vec::new();
+        let mut seqs = Vec::new();
+        let mut reader = parse_fastx_file(ref_path).expect("valid path/file");
+        while let Some(result) = reader.next() {
+            let record = result.expect("Error reading record");
+            let id = String::from_utf8_lossy(record.id()).into_owned();
+            names.push(id.split_whitespace().next().unwrap_or("").to_string());
+            seqs.push(record.seq().to_vec());
+        }
+        native_aligner = Some(Arc::new(SceAligner::new(params, names, seqs)));
+    } else {
+        let mut builder = Aligner::builder()
+            .preset(preset)
+            .with_index_threads(num_threads)
+            .with_sam_out();
+        if let Some(k) = kmer_size {
+            builder.idxopt.k = k;
+        }
+        if let Some(w) = window_size {
+            builder.idxopt.w = w;
+        }
+        if let Some(bw) = bandwidth {
+            builder.mapopt.bw = bw;
+        }
+        if let Some(min_chain_score) = min_chain_score {
+            builder.mapopt.min_chain_score = min_chain_score;
+        }
+        if !no_cigar {
+            builder = builder.with_cigar();
+        }
+        aligner = Some(builder.with_index(ref_path, None).expect("Unable to build index"));
+    }
+
+    // Bounded channels: the reader waits for the aligners and the aligners wait for the writer,
+    // so memory does not grow with the size of the read set
+    let channel_capacity = 4 * num_threads.max(1);
+    // (query rank, name, bases, qualities)
+    type QueryRecord = (usize, String, Vec<u8>, Option<Vec<u8>>);
+    let (sender, receiver): (Sender<QueryRecord>, Receiver<QueryRecord>) = bounded(channel_capacity);
+    let (result_sender, result_receiver): (Sender<(usize, QueryResult)>, Receiver<(usize, QueryResult)>) =
+        bounded(channel_capacity);
+    // Permits bound the queries read but not yet written: the producer takes one by query and the writer
+    // gives it back once the query is written. In ordered mode, results waiting for a slow query are thus
+    // at most `reorder_window`, the reader blocks instead of letting them pile up
+    let reorder_window = 4 * num_threads.max(1);
+    let (permit_sender, permit_receiver): (Sender<()>, Receiver<()>) = bounded(reorder_window);
 
     // Producer thread: reads sequences and sends them to the channel
     let query_path_clone = query_path.clone();
     let producer = thread::spawn(move || {
         let mut reader = parse_fastx_file(&query_path_clone).expect("valid path/file");
+        let mut rank = 0;
         while let Some(result) = reader.next() {
             let record = result.expect("Error reading record");
-            let seq_name = String::from_utf8_lossy(record.id()).into_owned();
+            // As minimap2, the query name is the first word of the header
+            let seq_name = String::from_utf8_lossy(record.id())
+                .split_whitespace()
+                .next()
+                .unwrap_or("")
+                .to_string();
             let seq = record.seq().to_vec();
+            let qual = if sam_out { record.qual().map(|q| q.to_vec()) } else { None };
 
-            // Send (sequence_name, sequence_bytes) to the consumers
-            sender.send((seq_name, seq)).expect("Error sending data to channel");
+            permit_sender.send(()).expect("Error taking a permit");
+            // Send (rank, sequence_name, sequence_bytes, qualities) to the consumers
+            sender.send((rank, seq_name, seq, qual)).expect("Error sending data to channel");
+            rank += 1;
         }
     });
 
-    // Consumer threads: receive (name, seq) and perform alignment
+    // Consumer threads: receive (rank, name, seq, qual), align and send the mappings to the writer
     let consumers: Vec<_> = (0..num_threads).map(|_| {
         let receiver = receiver.clone();
-        // Make aligner mutable for each thread
+        let result_sender = result_sender.clone();
+        // Make aligner mutable for each thread, the native one is shared
         let mut aligner = aligner.clone();
+        let native_aligner = native_aligner.clone();
         thread::spawn(move || {
-            // For each (name, seq) in the channel, call aligner.map with the name
-            let results = receiver
-                .iter()
-                .filter_map(|(seq_name, seq)| {
-                    // Use the query name by passing it as Some(...) in aligner.map
-                    aligner.map(&seq, false, false, None, None, Some(seq_name.as_bytes())).ok()
-                })
-                .collect::<Vec<_>>();
+            for (rank, seq_name, seq, qual) in receiver.iter() {
+                // Use the query name by passing it as Some(...) in aligner.map.
+                // Unmapped queries are sent too, they are reported in SAM and keep the order
+                let mappings: Vec<ChainMapping> = match (aligner.as_ref(), native_aligner.as_ref()) {
+                    (_, Some(native_aligner)) => native_aligner
+                        .map(&seq)
+                        .into_iter()
+                        .map(|hit| ChainMapping::from_sce(hit, native_aligner))
+                        .collect(),
+                    (Some(aligner), None) => aligner
+                        .map(&seq, false, false, None, None, Some(seq_name.as_bytes()))
+                        .unwrap_or_default()
+                        .iter()
+                        .map(ChainMapping::from_minimap2)
+                        .collect(),
+                    (None, None) => unreachable!(),
+                };
+                let query = QueryResult {
+                    name: seq_name,
+                    len: seq.len(),
+                    seq: if sam_out { seq } else { Vec::new() },
+                    qual,
+                    mappings,
+                };
+                result_sender.send((rank, query)).expect("Error sending results to writer");
+            }
             // Set aligner.idx = None before the thread exits (helps ensure resources free)
-            aligner.idx = None;
-            results
+            if let Some(aligner) = aligner.as_mut() {
+                aligner.idx = None;
+            }
         })
     }).collect();
+    drop(receiver);
+    drop(result_sender);
 
-    // Wait for the producer to finish reading
-    producer.join().expect("Producer thread panicked");
-
-    // Collect results from consumer threads
-    let mut results = Vec::new();
-    for consumer in consumers {
-        let mut res = consumer.join().expect("Consumer thread panicked");
-        results.append(&mut res);
+    // Write the results as they come, one PAF line or SAM record by mapping
+    let output: Box<dyn Write> = if output_path == "-" {
+        Box::new(std::io::stdout().lock())
+    } else {
+        Box::new(File::create(output_path)?)
+    };
+    let mut output_file = BufWriter::new(output);
+    if sam_out {
+        // @SQ lines come from the loaded index, which may be a prebuilt .mmi file
+        let references: Vec<(String, usize)> = match (aligner.as_ref(), native_aligner.as_ref()) {
+            (_, Some(native_aligner)) => {
+                let index = native_aligner.get_index();
+                (0..index.get_nb_seqs())
+                    .map(|ref_id| (index.get_name(ref_id).to_string(), index.get_seq(ref_id).len()))
+                    .collect()
+            }
+            (Some(aligner), None) => minimap2_references(aligner),
+            (None, None) => unreachable!(),
+        };
+        write_sam_header(&mut output_file, &references)?;
+    }
+    let write_query = |output_file: &mut BufWriter<Box<dyn Write>>, query: &QueryResult| -> std::io::Result<()> {
+        if sam_out {
+            write_sam_records(output_file, query)?;
+        } else if all_vs_all {
+            for mapping in overlap_mappings(&query.name, &query.mappings) {
+                write_paf_line(output_file, &query.name, query.len, mapping)?;
+            }
+        } else {
+            for mapping in query.mappings.iter() {
+                write_paf_line(output_file, &query.name, query.len, mapping)?;
+            }
+        }
+        Ok(())
+    };
+    // in ordered mode, results arriving early wait here for the preceding queries
+    let mut pending: BTreeMap<usize, QueryResult> = BTreeMap::new();
+    let mut next_rank = 0;
+    for (rank, query) in result_receiver.iter() {
+        if !ordered {
+            write_query(&mut output_file, &query)?;
+            permit_receiver.recv().expect("Error giving back a permit");
+            continue;
+        }
+        pending.insert(rank, query);
+        while let Some(query) = pending.remove(&next_rank) {
+            write_query(&mut output_file, &query)?;
+            permit_receiver.recv().expect("Error giving back a permit");
+            next_rank += 1;
+        }
     }
+    output_file.flush()?;
 
-    // Write the results to output file
-    let mut output_file = File::create(output_path)?;
-    for result in results {
-        writeln!(output_file, "{:?}", result)?;
+    // Wait for the producer and the consumers
+    producer.join().expect("Producer thread panicked");
+    for consumer in consumers {
+        consumer.join().expect("Consumer thread panicked");
     }
 
     Ok(())
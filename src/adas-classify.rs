@@ -0,0 +1,285 @@
+use clap::{Arg, ArgAction, Command};
+use needletail::parse_fastx_file;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use gsearch::utils::parameters::*;
+use gsearch::utils::reloadhnsw;
+use gsearch::utils::SeqDict;
+use kmerutils::base::Kmer32bit;
+use kmerutils::sketching::setsketchert::*;
+use kmerutils::base::sequence::Sequence as SequenceStruct;
+use log::info;
+use rayon::prelude::*;
+
+use adas::sketchcode::{reload_sketch_code, SketchIndex};
+use adas::sketching::{ascii_to_seq, kmer_hash_fn_32bit};
+use adas::taxonomy::{classify, taxonomy_exists, Classification, Taxonomy, RANKS, TAXONOMY_FILE};
+
+/// write the classification of a query: id, length, number of hits used, deepest assigned rank and taxon,
+/// then taxon and confidence at each rank
+fn dump_classification(
+    out: &mut BufWriter<File>,
+    query_id: &str,
+    query_len: usize,
+    classification: &Classification,
+) -> std::io::Result<()> {
+    let (lca_rank, lca_taxon) = classification.get_lca().unwrap_or(("none", "unclassified"));
+    write!(out, "{}\t{}\t{}\t{}\t{}", query_id, query_len, classification.nb_hits, lca_rank, lca_taxon)?;
+    for assignment in classification.ranks.iter() {
+        write!(
+            out,
+            "\t{}\t{:.4}",
+            assignment.taxon.as_deref().unwrap_or("unclassified"),
+            assignment.confidence
+        )?;
+    }
+    writeln!(out)
+}
+
+fn main() {
+    // Initialize logger
+    println!("\n ************** initializing logger *****************\n");
+    let _ = env_logger::Builder::from_default_env().init();
+
+    let matches = Command::new("adas-classify")
+        .version("0.1.1")
+        .about("Taxonomic classification of sequences by weighted LCA of their neighbours in a Pre-built Hierarchical Navigable Small World Graphs (HNSW) Index")
+        .arg(
+            Arg::new("input")
+                .short('i')
+                .long("input")
+                .value_name("FASTA_FILE")
+                .help("Input FASTA file")
+                .required(true)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("database_path")
+                .short('b')
+                .long("hnsw")
+                .value_name("DATADIR")
+                .help("directory contains pre-built HNSW database files")
+                .required(true)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("taxonomy")
+                .long("taxonomy")
+                .value_name("TAXONOMY_TSV")
+                .help("Taxonomy TSV file (sequence id, lineage separated by ;), default to the one attached to the database")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("nb_answers")
+                .short('n')
+                .long("nbng")
+                .value_name("NB_SEARCH_ANSWERS")
+                .help("Number of neighbours searched by query")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("32"),
+        )
+        .arg(
+            Arg::new("max_distance")
+                .long("max-distance")
+                .value_name("MAX_DISTANCE")
+                .help("Neighbours farther than this distance are not used for classification")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(f32))
+                .default_value("0.6"),
+        )
+        .arg(
+            Arg::new("min_confidence")
+                .long("min-confidence")
+                .value_name("MIN_CONFIDENCE")
+                .help("Minimal fraction of the hit weight supporting a taxon to assign it")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(f64))
+                .default_value("0.8"),
+        )
+        .arg(
+            Arg::new("threads")
+                .short('t')
+                .long("threads")
+                .value_name("THREADS")
+                .help("Number of threads for sketching and searching")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1"),
+        )
+        .arg(
+            Arg::new("batch_size")
+                .long("batch-size")
+                .value_name("BATCH_SIZE")
+                .help("Number of queries sketched and searched together")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("10000"),
+        )
+        .get_matches();
+
+    let fasta_path = matches.get_one::<String>("input").unwrap().to_string();
+    let db_path = matches.get_one::<String>("database_path").unwrap().to_string();
+    let taxonomy_path = matches.get_one::<String>("taxonomy").cloned();
+    let nb_answers = *matches.get_one::<usize>("nb_answers").unwrap();
+    let max_distance = *matches.get_one::<f32>("max_distance").unwrap();
+    let min_confidence = *matches.get_one::<f64>("min_confidence").unwrap();
+    let batch_size = (*matches.get_one::<usize>("batch_size").unwrap()).max(1);
+    let num_threads = *matches.get_one::<usize>("threads").unwrap();
+    let num_cpus = num_cpus::get();
+    let num_threads = if num_threads > num_cpus {
+        num_cpus
+    } else {
+        num_threads
+    };
+    println!("Using {} threads", num_threads);
+
+    let database_dirpath = Path::new(&db_path);
+    // Taxonomy first, no need to load the graph if it is missing
+    let taxonomy = match taxonomy_path {
+        Some(path) => Taxonomy::from_tsv(Path::new(&path)),
+        None if taxonomy_exists(database_dirpath) => Taxonomy::reload(database_dirpath),
+        None => panic!(
+            "no {} in {}, attach one with adas-build --taxonomy or give it with --taxonomy",
+            TAXONOMY_FILE, db_path
+        ),
+    };
+    let taxonomy = taxonomy.unwrap_or_else(|e| panic!("Cannot read taxonomy : {:?}", e));
+    println!("Taxonomy loaded, {} lineages", taxonomy.get_nb_entries());
+
+    let hnswio_res = reloadhnsw::get_hnswio(database_dirpath);
+    if let Err(e) = hnswio_res {
+        panic!("error : {:?}", e);
+    }
+    let mut hnswio = hnswio_res.unwrap();
+    let hnsw_path = std::path::PathBuf::from(database_dirpath);
+    let processing_params = match ProcessingParams::reload_json(&hnsw_path) {
+        Ok(params) => params,
+        Err(_) => panic!(
+            "Cannot reload parameters (file parameters.json) from dir: {:?}",
+            &hnsw_path
+        ),
+    };
+    let sketch_params = processing_params.get_sketching_params();
+    info!("Sketching parameters: {:?}", sketch_params);
+    let sketcher = OptDensHashSketch::<Kmer32bit, f64>::new(&sketch_params);
+
+    println!("Loading HNSW index...");
+    // signatures of a database with a compact sketch code are integer codes, queries are encoded on the fly
+    let sketch_code = reload_sketch_code(database_dirpath)
+        .unwrap_or_else(|e| panic!("Cannot read sketch code of the database: {:?}", e));
+    let hnsw_res = SketchIndex::load(&mut hnswio, sketch_code);
+    if let Err(e) = hnsw_res {
+        panic!("error : {:?}", e);
+    }
+    let hnsw = hnsw_res.unwrap();
+    let seqname = database_dirpath.join("seqdict.json");
+    let seqdict = match SeqDict::reload_json(&seqname) {
+        Ok(seqdict) => seqdict,
+        _ => panic!("SeqDict reload from dump file  {} failed", seqname.display()),
+    };
+    println!("HNSW index loaded...");
+
+    // lineage of each database entry, by data id
+    let lineages: Vec<Option<&[String]>> = seqdict
+        .0
+        .iter()
+        .map(|item| taxonomy.get_lineage(item.get_id().get_fasta_id()))
+        .collect();
+    let nb_missing = lineages.iter().filter(|lineage| lineage.is_none()).count();
+    if nb_missing > 0 {
+        log::warn!("{} database sequences have no lineage, they are not used", nb_missing);
+        println!("Warning: {} database sequences have no lineage, they are not used", nb_missing);
+    }
+
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build_global()
+        .unwrap();
+
+    let ef_search = 5000;
+    let outname = "adas.classify.txt";
+    let summary_name = "adas.classify.summary.txt";
+    let mut outfile = BufWriter::new(File::create(outname).unwrap_or_else(|e| {
+        log::error!("Could not open file {}. Error: {:?}", outname, e);
+        std::process::exit(1);
+    }));
+    let mut header = String::from("query_id\tquery_len\tnb_hits\tlca_rank\tlca_taxon");
+    for rank in RANKS.iter() {
+        header.push_str(&format!("\t{}\t{}_confidence", rank, rank));
+    }
+    writeln!(outfile, "{}", header).unwrap();
+
+    // number of queries by taxon, for each rank
+    let mut counts: Vec<HashMap<String, usize>> = vec![HashMap::new(); RANKS.len()];
+    let mut nb_queries = 0;
+    let mut classify_batch = |batch: &mut Vec<(String, Vec<u8>)>, outfile: &mut BufWriter<File>| {
+        let seqs: Vec<SequenceStruct> = batch.iter().map(|(_, bases)| ascii_to_seq(bases)).collect();
+        let vseq: Vec<&SequenceStruct> = seqs.iter().collect();
+        let signatures = sketcher.sketch_compressedkmer(&vseq, kmer_hash_fn_32bit);
+        let knn_neighbours = hnsw.parallel_search(&signatures, nb_answers, ef_search);
+        // hits within the distance cutoff weigh their similarity
+        let classifications: Vec<Classification> = knn_neighbours
+            .par_iter()
+            .map(|neighbours| {
+                let hits: Vec<(&[String], f64)> = neighbours
+                    .iter()
+                    .filter(|n| n.distance <= max_distance)
+                    .filter_map(|n| lineages[n.d_id].map(|lineage| (lineage, 1. - n.distance as f64)))
+                    .collect();
+                classify(&hits, min_confidence)
+            })
+            .collect();
+        for (((query_id, _), seq), classification) in batch.iter().zip(seqs.iter()).zip(classifications.iter()) {
+            if dump_classification(outfile, query_id, seq.size(), classification).is_err() {
+                log::info!("could not dump classification of {}", query_id);
+            }
+            for (r, assignment) in classification.ranks.iter().enumerate() {
+                let taxon = assignment.taxon.clone().unwrap_or_else(|| String::from("unclassified"));
+                *counts[r].entry(taxon).or_insert(0) += 1;
+            }
+        }
+        nb_queries += batch.len();
+        batch.clear();
+        log::info!("{} queries classified", nb_queries);
+    };
+
+    println!("Classifying...");
+    let mut reader = parse_fastx_file(Path::new(&fasta_path)).expect("Invalid path/file for FASTA");
+    let mut batch: Vec<(String, Vec<u8>)> = Vec::with_capacity(batch_size);
+    while let Some(record) = reader.next() {
+        let seqrec = record.expect("Invalid record");
+        let seq_id = String::from_utf8_lossy(seqrec.id()).into_owned();
+        batch.push((seq_id, seqrec.normalize(false).into_owned()));
+        if batch.len() == batch_size {
+            classify_batch(&mut batch, &mut outfile);
+        }
+    }
+    if !batch.is_empty() {
+        classify_batch(&mut batch, &mut outfile);
+    }
+    outfile.flush().unwrap();
+    println!("Classified {} queries. Results saved to {}", nb_queries, outname);
+
+    // summary: for each rank the number and fraction of queries by taxon, most abundant first
+    let mut summary = BufWriter::new(File::create(summary_name).unwrap_or_else(|e| {
+        log::error!("Could not open file {}. Error: {:?}", summary_name, e);
+        std::process::exit(1);
+    }));
+    writeln!(summary, "rank\ttaxon\tnb_queries\tfraction").unwrap();
+    for (r, rank_counts) in counts.iter().enumerate() {
+        let mut rank_counts: Vec<(&String, &usize)> = rank_counts.iter().collect();
+        rank_counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (taxon, count) in rank_counts {
+            let fraction = *count as f64 / nb_queries.max(1) as f64;
+            writeln!(summary, "{}\t{}\t{}\t{:.6}", RANKS[r], taxon, count, fraction).unwrap();
+        }
+    }
+    summary.flush().unwrap();
+    println!("Summary by rank saved to {}", summary_name);
+}
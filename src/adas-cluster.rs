@@ -0,0 +1,321 @@
+use clap::{Arg, ArgAction, Command};
+use gsearch::utils::reloadhnsw;
+use gsearch::utils::SeqDict;
+use log::info;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use hnsw_rs::prelude::*;
+
+use adas::seqstore::DatabaseSequences;
+use adas::sketchcode::load_f64_index;
+
+/// number of sequences whose candidates are searched together, in clustering order
+const SEARCH_CHUNK: usize = 10000;
+
+/// abundance given in a label as usearch does, `;size=N` , 1 if absent
+fn size_from_label(label: &str) -> usize {
+    label
+        .split(';')
+        .find_map(|field| field.trim().strip_prefix("size="))
+        .and_then(|size| size.parse::<usize>().ok())
+        .unwrap_or(1)
+}
+
+/// What a sequence became during clustering
+#[derive(Clone, Copy)]
+enum Membership {
+    /// centroid of a new cluster
+    Centroid(usize),
+    /// member of a cluster, with its centroid data id and distance to it
+    Member(usize, usize, f32),
+}
+
+fn main() {
+    // Initialize logger
+    println!("\n ************** initializing logger *****************\n");
+    let _ = env_logger::Builder::from_default_env().init();
+
+    let matches = Command::new("adas-cluster")
+        .version("0.1.1")
+        .about("Greedy distance threshold clustering of the sequences of a Pre-built Hierarchical Navigable Small World Graphs (HNSW) Index")
+        .arg(
+            Arg::new("database_path")
+                .short('b')
+                .long("hnsw")
+                .value_name("DATADIR")
+                .help("directory contains pre-built HNSW database files")
+                .required(true)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("max_distance")
+                .short('d')
+                .long("max-distance")
+                .value_name("MAX_DISTANCE")
+                .help("A sequence joins the nearest centroid within this distance, else it becomes a centroid")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(f32))
+                .default_value("0.1"),
+        )
+        .arg(
+            Arg::new("sort")
+                .long("sort")
+                .value_name("ORDER")
+                .help("Sequences are processed by decreasing length, or by decreasing abundance given as ;size=N in their ID")
+                .action(ArgAction::Set)
+                .value_parser(["length", "size"])
+                .default_value("length"),
+        )
+        .arg(
+            Arg::new("candidates")
+                .short('n')
+                .long("candidates")
+                .value_name("NB_CANDIDATES")
+                .help("Number of HNSW neighbours first examined to find a centroid, widened while they are all within the distance")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("64"),
+        )
+        .arg(
+            Arg::new("ef")
+                .long("ef")
+                .value_name("EF_SEARCH")
+                .help("HNSW search parameter ef")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("500"),
+        )
+        .arg(
+            Arg::new("threads")
+                .short('t')
+                .long("threads")
+                .value_name("THREADS")
+                .help("Number of threads for searching")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1"),
+        )
+        .arg(
+            Arg::new("uc")
+                .long("uc")
+                .value_name("UC_PATH")
+                .help("Output cluster membership table, UC format")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .default_value("adas.cluster.uc"),
+        )
+        .arg(
+            Arg::new("centroids")
+                .long("centroids")
+                .value_name("CENTROIDS_FASTA")
+                .help("Output FASTA of cluster centroids")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .default_value("adas.centroids.fa"),
+        )
+        .arg(
+            Arg::new("reference")
+                .short('r')
+                .long("reference")
+                .value_name("REFERENCE_FASTA")
+                .help("FASTA file of database sequences for the centroids, default to the sequence store or the paths recorded in the database")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .get_matches();
+
+    let db_path = matches.get_one::<String>("database_path").unwrap().to_string();
+    let max_distance = *matches.get_one::<f32>("max_distance").unwrap();
+    let by_size = matches.get_one::<String>("sort").unwrap() == "size";
+    let nb_candidates = *matches.get_one::<usize>("candidates").unwrap();
+    let ef_search = *matches.get_one::<usize>("ef").unwrap();
+    let uc_path = matches.get_one::<String>("uc").unwrap().to_string();
+    let centroids_path = matches.get_one::<String>("centroids").unwrap().to_string();
+    let reference_path = matches.get_one::<String>("reference").cloned();
+    let num_threads = *matches.get_one::<usize>("threads").unwrap();
+    let num_cpus = num_cpus::get();
+    let num_threads = if num_threads > num_cpus {
+        num_cpus
+    } else {
+        num_threads
+    };
+    println!("Using {} threads", num_threads);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build_global()
+        .unwrap();
+
+    let database_dirpath = Path::new(&db_path);
+    let hnswio_res = reloadhnsw::get_hnswio(database_dirpath);
+    if hnswio_res.is_err() {
+        panic!("Error: {:?}", hnswio_res.err());
+    }
+    let mut hnswio = hnswio_res.unwrap();
+    let seqdict_path = database_dirpath.join("seqdict.json");
+    info!("\nRe-loading sequence dictionary from {}", seqdict_path.display());
+    let seqdict = match SeqDict::reload_json(&seqdict_path) {
+        Ok(seqdict) => seqdict,
+        _ => panic!("SeqDict reload from dump file {} failed", seqdict_path.display()),
+    };
+    println!("Loading HNSW index...");
+    // graph points are used with their f64 signatures
+    let hnsw = match load_f64_index(&mut hnswio, database_dirpath, "adas-cluster") {
+        Ok(hnsw) => hnsw,
+        Err(e) => panic!("Error: {}", e),
+    };
+    println!("HNSW index loaded...");
+
+    // stored signature of each sequence, by data id: sequences are searched with their own signature
+    let nb_seqs = seqdict.0.len();
+    let mut points: Vec<Option<Arc<Point<f64>>>> = vec![None; nb_seqs];
+    for point in hnsw.get_point_indexation().into_iter() {
+        let d_id = point.get_origin_id();
+        if d_id < nb_seqs {
+            points[d_id] = Some(point);
+        }
+    }
+
+    // clustering order: decreasing length or size, then data id
+    let mut order: Vec<usize> = (0..nb_seqs).filter(|d_id| points[*d_id].is_some()).collect();
+    if by_size {
+        let sizes: Vec<usize> = seqdict.0.iter().map(|item| size_from_label(item.get_id().get_fasta_id())).collect();
+        order.sort_by(|a, b| sizes[*b].cmp(&sizes[*a]).then(a.cmp(b)));
+    } else {
+        order.sort_by(|a, b| seqdict.0[*b].get_len().cmp(&seqdict.0[*a].get_len()).then(a.cmp(b)));
+    }
+
+    // Greedy clustering: candidates of a chunk are searched in parallel (the graph does not change),
+    // then sequences of the chunk are assigned in order to the nearest centroid within the threshold.
+    // Candidates are the nearest points, not the nearest centroids: when all of them are within the threshold
+    // but none is a centroid (a dense region of members), the search is widened, doubling the number of
+    // neighbours and ef, until a centroid is found or the farthest neighbour is beyond the threshold.
+    println!("Clustering {} sequences at distance {}...", order.len(), max_distance);
+    let nb_points = order.len();
+    let mut membership: Vec<Option<Membership>> = vec![None; nb_seqs];
+    let mut centroids: Vec<usize> = Vec::new();
+    let mut nb_widened = 0usize;
+    for chunk in order.chunks(SEARCH_CHUNK) {
+        let candidates: Vec<Vec<Neighbour>> = chunk
+            .par_iter()
+            .map(|d_id| hnsw.search(points[*d_id].as_ref().unwrap().get_v(), nb_candidates + 1, ef_search))
+            .collect();
+        for (d_id, first_neighbours) in chunk.iter().zip(candidates.into_iter()) {
+            let mut neighbours = first_neighbours;
+            let mut knbn = nb_candidates + 1;
+            let mut ef = ef_search;
+            let centroid = loop {
+                let found = neighbours
+                    .iter()
+                    .filter(|n| n.d_id != *d_id && n.distance <= max_distance)
+                    .find(|n| matches!(membership[n.d_id], Some(Membership::Centroid(_))))
+                    .map(|n| (n.d_id, n.distance));
+                if found.is_some() {
+                    break found;
+                }
+                // neighbours are sorted by distance: no centroid can be within the threshold further away
+                let beyond = neighbours.len() < knbn || !matches!(neighbours.last(), Some(n) if n.distance <= max_distance);
+                if beyond || knbn >= nb_points {
+                    break None;
+                }
+                knbn = (2 * knbn).min(nb_points);
+                ef = (2 * ef).max(knbn);
+                nb_widened += 1;
+                neighbours = hnsw.search(points[*d_id].as_ref().unwrap().get_v(), knbn, ef);
+            };
+            membership[*d_id] = match centroid {
+                Some((c_id, distance)) => match membership[c_id] {
+                    Some(Membership::Centroid(cluster)) => Some(Membership::Member(cluster, c_id, distance)),
+                    _ => unreachable!(),
+                },
+                None => {
+                    centroids.push(*d_id);
+                    Some(Membership::Centroid(centroids.len() - 1))
+                }
+            };
+        }
+        log::info!("{} clusters", centroids.len());
+    }
+    log::info!("{} searches widened to find a centroid", nb_widened);
+    let mut cluster_sizes = vec![0usize; centroids.len()];
+    for m in membership.iter().flatten() {
+        match m {
+            Membership::Centroid(cluster) | Membership::Member(cluster, _, _) => cluster_sizes[*cluster] += 1,
+        }
+    }
+    println!("{} clusters", centroids.len());
+
+    // UC table: S record for centroids, H record for members in clustering order, then one C record by cluster.
+    // The %Id column of H records is the sketch similarity 100 * (1 - distance)
+    let write_uc = || -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(&uc_path)?);
+        for d_id in order.iter() {
+            let item = &seqdict.0[*d_id];
+            match membership[*d_id] {
+                Some(Membership::Centroid(cluster)) => writeln!(
+                    out,
+                    "S\t{}\t{}\t*\t*\t*\t*\t*\t{}\t*",
+                    cluster,
+                    item.get_len(),
+                    item.get_id().get_fasta_id()
+                )?,
+                Some(Membership::Member(cluster, centroid, distance)) => writeln!(
+                    out,
+                    "H\t{}\t{}\t{:.1}\t+\t*\t*\t*\t{}\t{}",
+                    cluster,
+                    item.get_len(),
+                    100. * (1. - distance),
+                    item.get_id().get_fasta_id(),
+                    seqdict.0[centroid].get_id().get_fasta_id()
+                )?,
+                None => {}
+            }
+        }
+        for (cluster, centroid) in centroids.iter().enumerate() {
+            writeln!(
+                out,
+                "C\t{}\t{}\t*\t*\t*\t*\t*\t{}\t*",
+                cluster,
+                cluster_sizes[cluster],
+                seqdict.0[*centroid].get_id().get_fasta_id()
+            )?;
+        }
+        out.flush()
+    };
+    match write_uc() {
+        Ok(()) => println!("Cluster membership saved to {}", uc_path),
+        Err(e) => log::error!("could not write {} : {:?}", uc_path, e),
+    }
+
+    // centroid sequences, in cluster order
+    let refseqs = DatabaseSequences::open(&seqdict, database_dirpath, reference_path.as_deref());
+    let write_centroids = || -> std::io::Result<usize> {
+        let mut out = BufWriter::new(File::create(&centroids_path)?);
+        let mut nb_missing = 0;
+        for centroid in centroids.iter() {
+            match refseqs.get(*centroid) {
+                Some(seq) => {
+                    writeln!(out, ">{}", seqdict.0[*centroid].get_id().get_fasta_id())?;
+                    out.write_all(&seq)?;
+                    writeln!(out)?;
+                }
+                None => nb_missing += 1,
+            }
+        }
+        out.flush()?;
+        Ok(nb_missing)
+    };
+    match write_centroids() {
+        Ok(nb_missing) => {
+            if nb_missing > 0 {
+                log::warn!("{} centroid sequences not found", nb_missing);
+                println!("Warning: {} centroid sequences not found, they are not in {}", nb_missing, centroids_path);
+            }
+            println!("Centroid sequences saved to {}", centroids_path);
+        }
+        Err(e) => log::error!("could not write {} : {:?}", centroids_path, e),
+    }
+}
@@ -0,0 +1,197 @@
+use clap::{Arg, ArgAction, Command};
+use gsearch::utils::reloadhnsw;
+use gsearch::utils::SeqDict;
+use log::info;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use annembed::fromhnsw::kgraph_from_hnsw_all;
+use annembed::prelude::*;
+use hnsw_rs::prelude::*;
+
+use adas::sketchcode::load_f64_index;
+
+/// quotes a CSV field if it contains a separator, a quote or a line break
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn main() {
+    // Initialize logger
+    println!("\n ************** initializing logger *****************\n");
+    let _ = env_logger::Builder::from_default_env().init();
+
+    let matches = Command::new("adas-embed")
+        .version("0.1.1")
+        .about("Low-dimensional embedding (annembed) of the sequences of a Pre-built Hierarchical Navigable Small World Graphs (HNSW) Index")
+        .arg(
+            Arg::new("database_path")
+                .short('b')
+                .long("hnsw")
+                .value_name("DATADIR")
+                .help("Directory containing pre-built HNSW database files")
+                .required(true)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_name("OUTPUT_PATH")
+                .help("Output CSV of the coordinates of each sequence")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .default_value("adas.embed.csv"),
+        )
+        .arg(
+            Arg::new("dimension")
+                .short('d')
+                .long("dimension")
+                .value_name("DIM")
+                .help("Dimension of the embedding")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("2"),
+        )
+        .arg(
+            Arg::new("knn")
+                .short('n')
+                .long("k-nearest-neighbors")
+                .value_name("KNN")
+                .help("Number of neighbors of each sequence in the graph to embed")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("10"),
+        )
+        .arg(
+            Arg::new("iterations")
+                .long("iterations")
+                .value_name("NB_BATCHES")
+                .help("Number of gradient batches of the embedding")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("20"),
+        )
+        .arg(
+            Arg::new("sampling")
+                .long("sampling")
+                .value_name("NB_SAMPLING")
+                .help("Number of negative samplings by edge in each gradient batch")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("10"),
+        )
+        .arg(
+            Arg::new("threads")
+                .short('t')
+                .long("threads")
+                .value_name("THREADS")
+                .help("Number of threads for embedding")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1"),
+        )
+        .get_matches();
+
+    let db_path = matches.get_one::<String>("database_path").unwrap().to_string();
+    let out_path = matches.get_one::<String>("output").unwrap().to_string();
+    let dimension = *matches.get_one::<usize>("dimension").unwrap();
+    let knbn = *matches.get_one::<usize>("knn").unwrap();
+    let nb_grad_batch = *matches.get_one::<usize>("iterations").unwrap();
+    let nb_sampling = *matches.get_one::<usize>("sampling").unwrap();
+    let num_threads = *matches.get_one::<usize>("threads").unwrap();
+    let num_cpus = num_cpus::get();
+    let num_threads = if num_threads > num_cpus {
+        num_cpus
+    } else {
+        num_threads
+    };
+    println!("Using {} threads", num_threads);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build_global()
+        .unwrap();
+
+    let database_dirpath = Path::new(&db_path);
+    let hnswio_res = reloadhnsw::get_hnswio(database_dirpath);
+    if hnswio_res.is_err() {
+        panic!("Error: {:?}", hnswio_res.err());
+    }
+    let mut hnswio = hnswio_res.unwrap();
+    let seqdict_path = database_dirpath.join("seqdict.json");
+    info!("\nRe-loading sequence dictionary from {}", seqdict_path.display());
+    let seqdict = match SeqDict::reload_json(&seqdict_path) {
+        Ok(seqdict) => seqdict,
+        _ => panic!("SeqDict reload from dump file {} failed", seqdict_path.display()),
+    };
+    println!("Loading HNSW index...");
+    // graph points are used with their f64 signatures
+    let hnsw = match load_f64_index(&mut hnswio, database_dirpath, "adas-embed") {
+        Ok(hnsw) => hnsw,
+        Err(e) => panic!("Error: {}", e),
+    };
+    println!("HNSW index loaded...");
+
+    let kgraph = match kgraph_from_hnsw_all::<_, _, f64>(&hnsw, knbn) {
+        Ok(kgraph) => kgraph,
+        Err(e) => panic!("Error creating KGraph: {:?}", e),
+    };
+    println!("KGraph successfully created with {} nodes.", kgraph.get_nb_nodes());
+
+    // mean distance of each sequence to its neighbors, large values point to outliers
+    let mut mean_distances = vec![f64::NAN; seqdict.0.len()];
+    for node_idx in 0..kgraph.get_nb_nodes() {
+        let edges = kgraph.get_out_edges_by_idx(node_idx);
+        if let Some(data_id) = kgraph.get_data_id_from_idx(node_idx) {
+            if !edges.is_empty() {
+                mean_distances[*data_id] = edges.iter().map(|edge| edge.weight).sum::<f64>() / edges.len() as f64;
+            }
+        }
+    }
+
+    let embed_params = EmbedderParams {
+        asked_dim: dimension,
+        nb_grad_batch,
+        nb_sampling_by_edge: nb_sampling,
+        ..Default::default()
+    };
+    let mut embedder = Embedder::new(&kgraph, embed_params);
+    println!("Embedding in dimension {}...", dimension);
+    if let Err(e) = embedder.embed() {
+        panic!("Embedding failed: {:?}", e);
+    }
+    // rows in data id order, that is in seqdict order
+    let embedded = embedder.get_embedded_reindexed();
+
+    let write_csv = || -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(&out_path)?);
+        write!(out, "path,fasta_id")?;
+        for dim in 0..embedded.ncols() {
+            write!(out, ",dim{}", dim + 1)?;
+        }
+        writeln!(out, ",knn_mean_distance")?;
+        for (data_id, row) in embedded.rows().into_iter().enumerate() {
+            let item = &seqdict.0[data_id];
+            write!(
+                out,
+                "{},{}",
+                csv_field(item.get_id().get_path()),
+                csv_field(item.get_id().get_fasta_id())
+            )?;
+            for x in row.iter() {
+                write!(out, ",{:.6e}", x)?;
+            }
+            writeln!(out, ",{:.6}", mean_distances[data_id])?;
+        }
+        out.flush()
+    };
+    match write_csv() {
+        Ok(()) => println!("Coordinates saved to {}", out_path),
+        Err(e) => log::error!("could not write {} : {:?}", out_path, e),
+    }
+}
@@ -0,0 +1,220 @@
+use clap::{Arg, ArgAction, ArgGroup, Command};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use gsearch::utils::SeqDict;
+use adas::seqstore::{seqstore_exists, SeqStore};
+
+/// Lookup tables from the identifiers found in id lists and search outputs to data ids
+struct IdResolver {
+    /// fasta id -> data ids (the same fasta id can come from several files)
+    by_fasta_id: HashMap<String, Vec<usize>>,
+    /// path|fasta_id, as written by adas-knn -> data id
+    by_path_and_id: HashMap<String, usize>,
+    /// path immediately followed by fasta_id, as written in adas.neighbors.txt -> data id
+    by_concat: HashMap<String, usize>,
+}
+
+impl IdResolver {
+    fn new(seqdict: &SeqDict) -> Self {
+        let mut by_fasta_id: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut by_path_and_id = HashMap::new();
+        let mut by_concat = HashMap::new();
+        for (d_id, item) in seqdict.0.iter().enumerate() {
+            let path = item.get_id().get_path();
+            let fasta_id = item.get_id().get_fasta_id();
+            by_fasta_id.entry(fasta_id.clone()).or_default().push(d_id);
+            by_path_and_id.insert(format!("{}|{}", path, fasta_id), d_id);
+            by_concat.insert(format!("{}{}", path, fasta_id), d_id);
+        }
+        IdResolver {
+            by_fasta_id,
+            by_path_and_id,
+            by_concat,
+        }
+    }
+
+    /// resolves a line of an id list: a fasta id or a path|fasta_id
+    fn resolve_id(&self, id: &str) -> Vec<usize> {
+        if let Some(d_id) = self.by_path_and_id.get(id) {
+            return vec![*d_id];
+        }
+        self.by_fasta_id.get(id).cloned().unwrap_or_default()
+    }
+} // end of impl IdResolver
+
+/// reads an id list, one identifier by line (first whitespace separated field)
+fn ids_from_list(path: &str, resolver: &IdResolver) -> std::io::Result<(Vec<usize>, usize)> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut d_ids = Vec::new();
+    let mut nb_missing = 0;
+    for line in reader.lines() {
+        let line = line?;
+        let id = match line.split_whitespace().next() {
+            Some(id) => id.trim_start_matches('>'),
+            None => continue,
+        };
+        let found = resolver.resolve_id(id);
+        if found.is_empty() {
+            log::warn!("id {} not found in database", id);
+            nb_missing += 1;
+        }
+        d_ids.extend(found);
+    }
+    Ok((d_ids, nb_missing))
+} // end of ids_from_list
+
+/// reads hits of adas-search, from adas.neighbors.txt or adas.align.txt
+fn ids_from_hits(path: &str, resolver: &IdResolver) -> std::io::Result<(Vec<usize>, usize)> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut d_ids = Vec::new();
+    let mut nb_missing = 0;
+    let mut align_format = false;
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with("query_rank\t") {
+            align_format = true;
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let d_id = if align_format {
+            // target_path and target_id columns
+            if fields.len() < 6 {
+                continue;
+            }
+            resolver.by_path_and_id.get(&format!("{}|{}", fields[4], fields[5])).copied()
+        } else {
+            // query_id: path distance: d answer_fasta_path pathfasta_id answer_seq_len: len
+            if !line.starts_with("query_id:") || fields.len() < 6 {
+                continue;
+            }
+            resolver.by_concat.get(fields[5].trim()).copied()
+        };
+        match d_id {
+            Some(d_id) => d_ids.push(d_id),
+            None => {
+                log::warn!("hit not found in database : {}", line);
+                nb_missing += 1;
+            }
+        }
+    }
+    Ok((d_ids, nb_missing))
+} // end of ids_from_hits
+
+fn main() {
+    // Initialize logger, messages go to stderr as FASTA can be written to stdout
+    eprintln!("\n ************** initializing logger *****************\n");
+    let _ = env_logger::Builder::from_default_env().init();
+
+    let matches = Command::new("adas-fetch")
+        .version("0.1.1")
+        .about("Fetch sequences from the sequence store of a database, as FASTA")
+        .arg(
+            Arg::new("database_path")
+                .short('b')
+                .long("hnsw")
+                .value_name("DATADIR")
+                .help("directory contains pre-built HNSW database files")
+                .required(true)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("ids")
+                .long("ids")
+                .value_name("IDS_FILE")
+                .help("File with one sequence ID by line (fasta_id or path|fasta_id)")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("hits")
+                .long("hits")
+                .value_name("SEARCH_RESULT")
+                .help("Search result (adas.neighbors.txt or adas.align.txt), all hits are fetched")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .group(ArgGroup::new("request").args(["ids", "hits"]).required(true))
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_name("OUTPUT_FASTA")
+                .help("Output FASTA file, - for stdout")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .default_value("-"),
+        )
+        .get_matches();
+
+    let db_path = matches.get_one::<String>("database_path").unwrap().to_string();
+    let output = matches.get_one::<String>("output").unwrap().to_string();
+    let database_dirpath = Path::new(&db_path);
+
+    if !seqstore_exists(database_dirpath) {
+        eprintln!(
+            "No sequence store in {}, the database must be built with adas-build --store-seqs",
+            db_path
+        );
+        std::process::exit(1);
+    }
+    let seqstore = SeqStore::open(database_dirpath)
+        .unwrap_or_else(|e| panic!("Cannot open sequence store : {:?}", e));
+
+    let seqname = database_dirpath.join("seqdict.json");
+    log::info!("\n reloading sequence dictionary from {}", &seqname.display());
+    let seqdict = match SeqDict::reload_json(&seqname) {
+        Ok(seqdict) => seqdict,
+        _ => {
+            panic!(
+                "SeqDict reload from dump file  {} failed",
+                seqname.display()
+            );
+        }
+    };
+    if seqstore.get_nb_seqs() != seqdict.get_nb_entries() {
+        panic!(
+            "Sequence store has {} sequences but database has {} entries",
+            seqstore.get_nb_seqs(),
+            seqdict.get_nb_entries()
+        );
+    }
+
+    let resolver = IdResolver::new(&seqdict);
+    let requested = if let Some(ids_path) = matches.get_one::<String>("ids") {
+        ids_from_list(ids_path, &resolver)
+    } else {
+        ids_from_hits(matches.get_one::<String>("hits").unwrap(), &resolver)
+    };
+    let (d_ids, nb_missing) = requested.unwrap_or_else(|e| panic!("Cannot read request file : {:?}", e));
+    if nb_missing > 0 {
+        eprintln!("Warning: {} requested sequences not found in database", nb_missing);
+    }
+
+    let writer: Box<dyn Write> = if output == "-" {
+        Box::new(std::io::stdout().lock())
+    } else {
+        Box::new(File::create(&output).unwrap_or_else(|e| panic!("Cannot create {} : {:?}", output, e)))
+    };
+    let mut writer = BufWriter::new(writer);
+
+    // each sequence is written once, in order of first request
+    let mut seen = HashSet::new();
+    let mut nb_written = 0;
+    for d_id in d_ids {
+        if !seen.insert(d_id) {
+            continue;
+        }
+        let seq = seqstore
+            .get(d_id)
+            .unwrap_or_else(|e| panic!("Cannot read sequence of data id {} : {:?}", d_id, e));
+        writeln!(writer, ">{}", seqdict.0[d_id].get_id().get_fasta_id()).unwrap();
+        writer.write_all(&seq).unwrap();
+        writeln!(writer).unwrap();
+        nb_written += 1;
+    }
+    writer.flush().unwrap();
+    eprintln!("{} sequences written", nb_written);
+}
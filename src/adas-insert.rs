@@ -1,43 +1,22 @@
 use clap::{Arg, ArgAction, Command};
-use needletail::{parse_fastx_file, Sequence};
 use std::path::Path;
-use std::sync::{Arc, Mutex};
-use std::thread;
 use std::path::PathBuf;
+use std::time::Instant;
 use num_cpus;
-use crossbeam::channel::{unbounded, Sender, Receiver};
 
-use hnsw_rs::prelude::*;
 use gsearch::utils::idsketch::{Id, ItemDict};
 use gsearch::utils::parameters::*;
-use gsearch::utils::dumpload::*;
 use gsearch::utils::reloadhnsw;
 use gsearch::utils::SeqDict;
-use kmerutils::base::{kmergenerator::*, Kmer32bit, CompressedKmerT};
+use kmerutils::base::Kmer32bit;
 use kmerutils::sketching::setsketchert::*;
-use kmerutils::base::alphabet::Alphabet2b;
-use kmerutils::base::sequence::Sequence as SequenceStruct;
 
 use log::info;
 
-fn ascii_to_seq(bases: &[u8]) -> Result<SequenceStruct, ()> {
-    let alphabet = Alphabet2b::new();
-    let mut seq = SequenceStruct::with_capacity(2, bases.len());
-    seq.encode_and_add(bases, &alphabet);
-    Ok(seq)
-} // end of ascii_to_seq
-
-// Define the k-mer hash function as a function
-fn kmer_hash_fn_32bit(kmer: &Kmer32bit) -> <Kmer32bit as CompressedKmerT>::Val {
-    let canonical = kmer.reverse_complement().min(*kmer);
-    let nb_alphabet_bits = Alphabet2b::new().get_nb_bits();
-    let mask: <Kmer32bit as CompressedKmerT>::Val =
-        ((1u64 << (nb_alphabet_bits * kmer.get_nb_base())) - 1)
-            .try_into()
-            .unwrap();
-    let hashval = canonical.get_compressed_value() & mask;
-    hashval
-}
+use adas::seqstore::{seqstore_exists, SeqStoreWriter};
+use adas::sketchcode::{reload_sketch_code, SketchIndex};
+use adas::sketching::sketch_fasta_batches;
+use adas::spill::SignatureSpool;
 
 /// Parameters defining a Request in a Hnsw database
 pub struct SearchParams {
@@ -102,11 +81,33 @@ fn main() {
                 .value_parser(clap::value_parser!(usize))
                 .default_value("1"),
         )
+        .arg(
+            Arg::new("batch_size")
+                .long("batch-size")
+                .value_name("BATCH_SIZE")
+                .help("Number of sequences sketched together")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("10000"),
+        )
+        .arg(
+            Arg::new("max_memory")
+                .long("max-memory")
+                .value_name("MB")
+                .help("Memory budget in MB for signatures waiting for insertion, beyond it they are spilled to a temporary file. The HNSW keeps every inserted point: peak memory is the whole graph plus one chunk of this size")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize)),
+        )
         .get_matches();
     
     let fasta_path = matches.get_one::<String>("input").unwrap().to_string();
     let db_path = matches.get_one::<String>("database_path").unwrap().to_string();
     let num_threads = *matches.get_one::<usize>("threads").unwrap();
+    let batch_size = *matches.get_one::<usize>("batch_size").unwrap();
+    let max_memory = matches.get_one::<usize>("max_memory").map(|mb| {
+        mb.checked_mul(1024 * 1024)
+            .unwrap_or_else(|| panic!("--max-memory {} MB does not fit in {} bytes", mb, usize::MAX))
+    });
     let num_cpus = num_cpus::get();
     let num_threads = if num_threads > num_cpus {
         num_cpus
@@ -150,11 +151,10 @@ fn main() {
     let sketcher = Sketcher::new(&sketch_params);
 
     println!("Loading HNSW index...");
-    // Load the HNSW index
-    let hnsw_res = hnswio.load_hnsw::<
-        <OptDensHashSketch<Kmer, f64> as SeqSketcherT<Kmer32bit>>::Sig,
-        DistHamming
-    >();
+    // Load the HNSW index, with the signature type of the database sketch code
+    let sketch_code = reload_sketch_code(database_dirpath)
+        .unwrap_or_else(|e| panic!("Cannot read sketch code of the database: {:?}", e));
+    let hnsw_res = SketchIndex::load(&mut hnswio, sketch_code);
     if let Err(e) = hnsw_res {
         panic!("Error loading HNSW: {:?}", e);
     }
@@ -178,6 +178,22 @@ fn main() {
     };
     println!("HNSW index loaded...");
 
+    // If the database has a sequence store, new sequences are appended to it
+    let mut seqstore = if seqstore_exists(database_dirpath) {
+        let writer = SeqStoreWriter::append(database_dirpath)
+            .unwrap_or_else(|e| panic!("Cannot open sequence store : {:?}", e));
+        if writer.get_nb_seqs() != seqdict.get_nb_entries() {
+            panic!(
+                "Sequence store has {} sequences but database has {} entries",
+                writer.get_nb_seqs(),
+                seqdict.get_nb_entries()
+            );
+        }
+        Some(writer)
+    } else {
+        None
+    };
+
     println!("Sketching...");
     // Set the number of threads globally using Rayon
     rayon::ThreadPoolBuilder::new()
@@ -185,104 +201,50 @@ fn main() {
         .build_global()
         .unwrap();
 
-
-    let (tx, rx): (Sender<(Vec<u8>, Vec<u8>)>, Receiver<(Vec<u8>, Vec<u8>)>) = unbounded();
-
-    // 2) Producer thread: reads FASTA -> sends to channel
-    let fasta_path_clone = fasta_path.clone();
-    let producer_handle = thread::spawn(move || {
-        let mut reader =
-            parse_fastx_file(&Path::new(&fasta_path_clone))
-                .expect("Invalid path/file for FASTA");
-
-        while let Some(record) = reader.next() {
-            let seqrec = record.expect("Invalid record");
-            let seq_id = seqrec.id().to_owned();       // Vec<u8>
-            let seq_seq = seqrec.normalize(false).into_owned(); // Vec<u8>
-            tx.send((seq_id, seq_seq)).expect("Could not send data");
+    // Sequences are sketched by batches and collected in file order, so that data ids are the same
+    // in graph, dictionary and sequence store
+    // Signatures beyond the memory budget wait for insertion in a temporary file
+    let mut signatures = SignatureSpool::new(max_memory, &std::env::temp_dir());
+    let mut itemv: Vec<ItemDict> = Vec::new();
+    let start = Instant::now();
+    let nb_sketched = sketch_fasta_batches(&fasta_path, &sketcher, batch_size, |batch, lens, batch_signatures| {
+        signatures.extend(batch_signatures).expect("Could not spill signatures");
+        for ((seq_id, seq_seq), len) in batch.into_iter().zip(lens) {
+            if let Some(writer) = seqstore.as_mut() {
+                writer.push(&seq_seq).expect("Could not write sequence store");
+            }
+            let id = Id::new(&fasta_path, &String::from_utf8(seq_id).unwrap());
+            itemv.push(ItemDict::new(id, len));
         }
-        // Once done, drop the sender
-        drop(tx);
     });
-
-    // 3) Shared storage (signatures + itemv):
-    let signatures = Arc::new(Mutex::new(Vec::new()));
-    let itemv = Arc::new(Mutex::new(Vec::new()));
-
-    // 4) Multiple consumers (using crossbeam::channel)
-    let mut consumer_handles = Vec::with_capacity(num_threads);
-
-    for _ in 0..num_threads {
-        let rx_clone = rx.clone();       // clone the receiver
-        let sketcher_clone = sketcher.clone();
-        let signatures_clone = Arc::clone(&signatures);
-        let itemv_clone = Arc::clone(&itemv);
-        let fasta_path_clone_for_consumer = fasta_path.clone();
-
-        // Each consumer will pull from rx_clone and do the sketching
-        let handle = thread::spawn(move || {
-            // Loop over all messages
-            for (seq_id, seq_seq) in rx_clone.iter() {
-                // Convert the sequence to the format needed by the sketcher
-                let seq = ascii_to_seq(&seq_seq).unwrap();
-                let vseq = vec![&seq];
-
-                // Sketch the sequence (1 item)
-                let signatures_vec = sketcher_clone.sketch_compressedkmer(&vseq, kmer_hash_fn_32bit);
-                let signature = signatures_vec.into_iter().next().unwrap();
-
-                // Store the signature
-                {
-                    let mut s_lock = signatures_clone.lock().unwrap();
-                    s_lock.push(signature);
-                }
-                // Store sequence metadata
-                {
-                    let mut itemv_lock = itemv_clone.lock().unwrap();
-                    let id = Id::new(
-                        &fasta_path_clone_for_consumer,
-                        &String::from_utf8(seq_id).unwrap(),
-                    );
-                    let item = ItemDict::new(id, seq.size());
-                    itemv_lock.push(item);
-                }
-            }
-        });
-        consumer_handles.push(handle);
-    }
-
-    // 5) Wait for producer + all consumers
-    producer_handle.join().expect("Producer thread panicked");
-    for handle in consumer_handles {
-        handle.join().expect("Consumer thread panicked");
+    let elapsed = start.elapsed().as_secs_f64();
+    if let Some(writer) = seqstore {
+        writer.finish().expect("Could not write sequence store");
     }
-    
-
-    // 6) Retrieve collected signatures & itemv
-    let signatures = Arc::try_unwrap(signatures)
-        .expect("Multiple references to signatures remain")
-        .into_inner()
-        .expect("Mutex was poisoned in signatures");
-    let mut itemv = match Arc::try_unwrap(itemv) {
-            Ok(mutex) => mutex.into_inner().unwrap(),
-            Err(_arc_mutex) => {
-                // Handle the case where the Arc has more than one strong reference.
-                panic!("Cannot unwrap Arc because there are multiple references");
-            }
-        };
-
-    println!("Sketching done.");
+    println!(
+        "Sketching done: {} sequences in {:.2} s, {:.0} sequences/s",
+        nb_sketched,
+        elapsed,
+        nb_sketched as f64 / elapsed.max(1e-9)
+    );
 
     println!("Inserting into HNSW index...");
-    // 7) Create data as Vec<(&Vec<f64>, usize)> for HNSW insertion
-    let data: Vec<(&Vec<f64>, usize)> = signatures
-        .iter()
-        .enumerate()
-        .map(|(idx, sig)| (sig, idx))
-        .collect();
-
-    // Insert in parallel
-    hnsw.parallel_insert(&data);
+    // 7) New data ids follow the ones already in the database, they index the SeqDict.
+    // Signatures are inserted in parallel by chunks fitting in the memory budget
+    let first_data_id = seqdict.get_nb_entries();
+    if signatures.get_nb_spilled() > 0 {
+        println!("{} signatures spilled to disk, inserted by chunks", signatures.get_nb_spilled());
+    }
+    signatures
+        .drain_chunks(|first, chunk| {
+            let data: Vec<(&Vec<f64>, usize)> = chunk
+                .iter()
+                .enumerate()
+                .map(|(idx, sig)| (sig, first_data_id + first + idx))
+                .collect();
+            hnsw.parallel_insert(&data);
+        })
+        .expect("Could not read spilled signatures");
 
     // Update seqdict
     seqdict.0.append(&mut itemv);
@@ -293,7 +255,9 @@ fn main() {
     let dump_path = db_path.clone();
     let dump_path_ref = &PathBuf::from(dump_path);
 
-    let _ = dumpall(dump_path_ref, &hnsw, &seqdict, &processing_params);
+    if let Err(e) = hnsw.dump(dump_path_ref, &seqdict, &processing_params) {
+        log::error!("could not dump database : {}", e);
+    }
 
     println!("Inserting inton HNSW index done. \n");
 }
\ No newline at end of file
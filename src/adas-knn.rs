@@ -1,19 +1,24 @@
 use clap::{Arg, ArgAction, Command};
 use gsearch::utils::reloadhnsw;
-use log::info;
+use log::{info, warn};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::path::Path;
+use std::sync::Arc;
+use rayon::prelude::*;
 
 use hnsw_rs::prelude::*;
 use annembed::fromhnsw::kgraph::KGraph;
 use annembed::fromhnsw::kgraph_from_hnsw_all;
 use num::Float;
-use kmerutils::base::Kmer32bit;
-use kmerutils::sketching::setsketchert::*;
 use gsearch::utils::SeqDict;
 use num_traits::cast::FromPrimitive;
 
+use adas::community::{louvain, symmetrize, Adjacency, Communities};
+use adas::knnstats::KnnDiagnostics;
+use adas::sketchcode::load_f64_index;
+
 fn main() {
     // Initialize logger
     println!("\n ************** initializing logger *****************\n");
@@ -52,6 +57,93 @@ fn main() {
                 .value_parser(clap::value_parser!(usize))
                 .default_value("32"),
         )
+        .arg(
+            Arg::new("communities")
+                .long("communities")
+                .value_name("COMMUNITIES_PATH")
+                .help("Detect communities (Louvain) on the symmetrised kNN graph and write the community of each sequence")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("resolution")
+                .long("resolution")
+                .value_name("RESOLUTION")
+                .help("Modularity resolution, larger values give smaller communities")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(f64))
+                .default_value("1.0")
+                .requires("communities"),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Output format: adas neighbor lists, tsv edge list, mtx (Matrix Market), graphml or gfa links")
+                .action(ArgAction::Set)
+                .value_parser(["adas", "tsv", "mtx", "graphml", "gfa"])
+                .default_value("adas"),
+        )
+        .arg(
+            Arg::new("symmetrize")
+                .long("symmetrize")
+                .value_name("MODE")
+                .help("Make the graph undirected: union keeps an edge if either sequence is a neighbor of the other, mutual if both are")
+                .action(ArgAction::Set)
+                .value_parser(["union", "mutual"]),
+        )
+        .arg(
+            Arg::new("max_distance")
+                .long("max-distance")
+                .value_name("MAX_DISTANCE")
+                .help("Drop edges with a distance above this value")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(f64)),
+        )
+        .arg(
+            Arg::new("diagnostics")
+                .long("diagnostics")
+                .value_name("REPORT_PATH")
+                .help("Write a quality report of the kNN graph: out-degrees, isolated nodes, reverse kNN counts and hubs")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("top_hubs")
+                .long("top-hubs")
+                .value_name("NB_HUBS")
+                .help("Number of hubs (sequences in the most neighbor lists) in the report")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("20")
+                .requires("diagnostics"),
+        )
+        .arg(
+            Arg::new("exact")
+                .long("exact")
+                .help("Search the HNSW with each sequence to get k neighbors, instead of reading the graph links")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("ef")
+                .long("ef")
+                .value_name("EF_SEARCH")
+                .help("HNSW search parameter ef with --exact")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("128")
+                .requires("exact"),
+        )
+        .arg(
+            Arg::new("threads")
+                .short('t')
+                .long("threads")
+                .value_name("THREADS")
+                .help("Number of threads for searching with --exact")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1"),
+        )
         .get_matches();
 
     // Extract command-line arguments
@@ -61,6 +153,27 @@ fn main() {
         .to_string();
     let out_path = matches.get_one::<String>("output").unwrap().to_string();
     let knbn = *matches.get_one::<usize>("knn").unwrap();
+    let communities_path = matches.get_one::<String>("communities").cloned();
+    let resolution = *matches.get_one::<f64>("resolution").unwrap();
+    let format = matches.get_one::<String>("format").unwrap().to_string();
+    let symmetrize_mode = matches.get_one::<String>("symmetrize").cloned();
+    let max_distance = matches.get_one::<f64>("max_distance").copied().unwrap_or(f64::INFINITY);
+    let diagnostics_path = matches.get_one::<String>("diagnostics").cloned();
+    let nb_hubs = *matches.get_one::<usize>("top_hubs").unwrap();
+    let exact = matches.get_flag("exact");
+    let ef_search = *matches.get_one::<usize>("ef").unwrap();
+    let num_threads = *matches.get_one::<usize>("threads").unwrap();
+    let num_cpus = num_cpus::get();
+    let num_threads = if num_threads > num_cpus {
+        num_cpus
+    } else {
+        num_threads
+    };
+    println!("Using {} threads", num_threads);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build_global()
+        .unwrap();
     // Prepare to reload HNSW from disk
     let database_dirpath = Path::new(&db_path);
     let hnswio_res = reloadhnsw::get_hnswio(database_dirpath);
@@ -87,92 +200,452 @@ fn main() {
     };
 
     // Load the HNSW graph
-    let hnsw_res = hnswio.load_hnsw::<
-        <OptDensHashSketch<Kmer32bit, f64> as SeqSketcherT<Kmer32bit>>::Sig,
-        DistHamming,
-    >();
-    if hnsw_res.is_err() {
-        panic!("Error: {:?}", hnsw_res.err());
-    }
-    let hnsw = hnsw_res.unwrap();
-
-    // Choose how many neighbors to extract, the actual neighbors extracted can be small than requested
-    // since t also depends on HNSW build maximumlly allowed neighbors
-    let kgraph_res = kgraph_from_hnsw_all::<_, _, f64>(&hnsw, knbn);
-
-    // Build the KGraph from HNSW, then save neighbor lists
-    match kgraph_res {
-        Ok(kgraph) => {
-            println!(
-                "KGraph successfully created with {} nodes.",
-                kgraph.get_nb_nodes()
-            );
-            // Save the neighbor list to a file, printing actual sequence IDs
-            if let Err(e) = save_neighbor_list_to_file(&kgraph, &seqdict, &out_path) {
-                eprintln!("Error saving neighbor list: {:?}", e);
-            } else {
-                println!("Neighbor list saved to {}", out_path);
+    // graph points are used with their f64 signatures
+    let hnsw = match load_f64_index(&mut hnswio, database_dirpath, "adas-knn") {
+        Ok(hnsw) => hnsw,
+        Err(e) => panic!("Error: {}", e),
+    };
+
+    // data id (index in seqdict) of each node and kNN lists (node index, distance) by node index
+    let (data_ids, knn) = if exact {
+        println!("Searching {} neighbors of each sequence with ef {}...", knbn, ef_search);
+        let (data_ids, knn) = exact_knn(&hnsw, knbn, ef_search);
+        println!("kNN lists of {} sequences searched.", data_ids.len());
+        (data_ids, knn)
+    } else {
+        // Choose how many neighbors to extract, the actual neighbors extracted can be small than requested
+        // since t also depends on HNSW build maximumlly allowed neighbors
+        match kgraph_from_hnsw_all::<_, _, f64>(&hnsw, knbn) {
+            Ok(kgraph) => {
+                println!(
+                    "KGraph successfully created with {} nodes.",
+                    kgraph.get_nb_nodes()
+                );
+                let data_ids: Vec<usize> = (0..kgraph.get_nb_nodes())
+                    .map(|node_idx| *kgraph.get_data_id_from_idx(node_idx).unwrap())
+                    .collect();
+                (data_ids, kgraph_to_lists(&kgraph))
+            }
+            Err(e) => {
+                eprintln!("Error creating KGraph: {:?}", e);
+                return;
             }
         }
-        Err(e) => {
-            eprintln!("Error creating KGraph: {:?}", e);
+    };
+    let mutual = symmetrize_mode.as_deref() == Some("mutual");
+    // undirected edges weigh the similarity 1 - distance
+    let graph = match symmetrize_mode {
+        Some(_) => Graph::undirected(&symmetrize(&knn, mutual, max_distance)),
+        None => Graph::directed(&knn, max_distance),
+    };
+    println!(
+        "{} {} edges with distance at most {}",
+        graph.get_nb_edges(),
+        if graph.undirected { "undirected" } else { "directed" },
+        max_distance
+    );
+    // Save the graph to a file, printing actual sequence IDs
+    let res = match format.as_str() {
+        "tsv" => save_edge_list(&graph, &data_ids, &seqdict, &out_path),
+        "mtx" => save_matrix_market(&graph, &data_ids, &seqdict, &out_path),
+        "graphml" => save_graphml(&graph, &data_ids, &seqdict, &out_path),
+        "gfa" => save_gfa(&graph, &data_ids, &seqdict, &out_path),
+        _ => save_neighbor_list_to_file(&graph, &data_ids, &seqdict, &out_path),
+    };
+    if let Err(e) = res {
+        eprintln!("Error saving neighbor list: {:?}", e);
+    } else {
+        println!("Neighbor list saved to {}", out_path);
+    }
+    if let Some(diagnostics_path) = diagnostics_path {
+        // on the kNN lists as extracted, without cutoff
+        let diagnostics = KnnDiagnostics::new(&knn, knbn);
+        if let Err(e) = save_diagnostics(&diagnostics, nb_hubs, &data_ids, &seqdict, &diagnostics_path) {
+            eprintln!("Error saving diagnostics: {:?}", e);
+        } else {
+            println!("kNN graph diagnostics saved to {}", diagnostics_path);
         }
     }
+    if let Some(communities_path) = communities_path {
+        let undirected = symmetrize(&knn, mutual, max_distance);
+        let communities = louvain(&undirected, resolution);
+        if let Err(e) = save_communities(&data_ids, &seqdict, &communities, resolution, &communities_path) {
+            eprintln!("Error saving communities: {:?}", e);
+        } else {
+            println!("Communities saved to {} and {}.stats", communities_path, communities_path);
+        }
+    }
+}
+
+/// kNN graph to export, as neighbor lists (node index, distance) by node index
+struct Graph {
+    lists: Vec<Vec<(usize, f64)>>,
+    /// if true each edge {i,j} is in the lists of i and j
+    undirected: bool,
+}
+
+impl Graph {
+    fn directed(knn: &[Vec<(usize, f64)>], max_distance: f64) -> Self {
+        let lists = knn
+            .iter()
+            .map(|neighbors| neighbors.iter().filter(|(_, d)| *d <= max_distance).copied().collect())
+            .collect();
+        Graph { lists, undirected: false }
+    }
+
+    /// from a symmetrised graph, distances are recovered from weights 1 - distance
+    fn undirected(adjacency: &Adjacency) -> Self {
+        let lists = adjacency
+            .iter()
+            .map(|neighbors| neighbors.iter().map(|(j, w)| (*j, 1. - w)).collect())
+            .collect();
+        Graph { lists, undirected: true }
+    }
+
+    /// edges (i, j, distance), an undirected edge once with i < j
+    fn edges(&self) -> impl Iterator<Item = (usize, usize, f64)> + '_ {
+        self.lists.iter().enumerate().flat_map(move |(i, neighbors)| {
+            neighbors
+                .iter()
+                .filter(move |(j, _)| !self.undirected || i < *j)
+                .map(move |(j, d)| (i, *j, *d))
+        })
+    }
+
+    fn get_nb_edges(&self) -> usize {
+        self.edges().count()
+    }
 }
 
-/// Save KGraph neighbor lists to a file, printing *actual sequence IDs* from SeqDict
+/// Name of each node for formats where nodes are named: the FASTA ID, or with `tokens` the FASTA ID with blanks
+/// replaced by _. The same ID can come from different files, so IDs occurring more than once are prefixed by the file path
+/// (path|ID), and by the node index (index|path|ID) if still not unique.
+fn node_names(data_ids: &[usize], seqdict: &SeqDict, tokens: bool) -> Vec<String> {
+    let format_name = |name: String| {
+        if tokens {
+            name.split_whitespace().collect::<Vec<&str>>().join("_")
+        } else {
+            name
+        }
+    };
+    let mut names: Vec<String> = data_ids
+        .iter()
+        .map(|data_id| format_name(seqdict.0[*data_id].get_id().get_fasta_id().to_string()))
+        .collect();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for name in names.iter() {
+        *counts.entry(name.clone()).or_insert(0) += 1;
+    }
+    if counts.len() == names.len() {
+        return names;
+    }
+    let mut nb_prefixed = 0;
+    for (name, data_id) in names.iter_mut().zip(data_ids.iter()) {
+        if counts[name.as_str()] > 1 {
+            let id = seqdict.0[*data_id].get_id();
+            *name = format_name(format!("{}|{}", id.get_path(), id.get_fasta_id()));
+            nb_prefixed += 1;
+        }
+    }
+    counts.clear();
+    for name in names.iter() {
+        *counts.entry(name.clone()).or_insert(0) += 1;
+    }
+    for (node_idx, name) in names.iter_mut().enumerate() {
+        if counts[name.as_str()] > 1 {
+            *name = format!("{}|{}", node_idx, name);
+        }
+    }
+    warn!("{} sequences have the same ID as another one, their names are prefixed by their file path", nb_prefixed);
+    names
+} // end of node_names
+
+/// Save neighbor lists to a file, printing *actual sequence IDs* from SeqDict
 /// we look up the corresponding file path + FASTA ID from `seqdict`.
-fn save_neighbor_list_to_file<F>(
-    kgraph: &KGraph<F>,
+/// Format: path|fasta_id:\tpath|fasta_id:distance ...
+fn save_neighbor_list_to_file(
+    graph: &Graph,
+    data_ids: &[usize],
     seqdict: &SeqDict,
     output_file: &str,
-) -> std::io::Result<()>
-where
-    F: FromPrimitive + Float + std::fmt::UpperExp + Sync + Send + std::iter::Sum,
-{
+) -> std::io::Result<()> {
     let file = File::create(output_file)?;
     let mut writer = BufWriter::new(file);
 
-    // Iterate over each node index in the KGraph
-    for node_idx in 0..kgraph.get_nb_nodes() {
-        // Get the data ID for the current node (this is used to index seqdict)
-        if let Some(node_data_id) = kgraph.get_data_id_from_idx(node_idx) {
-            // The HNSW data ID is used as an index into seqdict.0[..]
-            let node_item = &seqdict.0[*node_data_id];
-            let node_path = node_item.get_id().get_path();
-            let node_fasta_id = node_item.get_id().get_fasta_id();
-
-            // Write the node's actual sequence ID
-            // Format: path|fasta_id:
-            write!(writer, "{}|{}:", node_path, node_fasta_id)?;
-
-            // Get the list of outgoing edges (neighbors)
-            let edges = kgraph.get_out_edges_by_idx(node_idx);
-            // Iterate over each neighbor
-            for edge in edges {
-                // Get the neighbor's index
-                let neighbor_idx = edge.node;
-                // Convert neighbor_idx -> neighbor_data_id -> actual sequence name
-                if let Some(neighbor_data_id) = kgraph.get_data_id_from_idx(neighbor_idx) {
-                    let neighbor_item = &seqdict.0[*neighbor_data_id];
-                    let neighbor_path = neighbor_item.get_id().get_path();
-                    let neighbor_fasta_id = neighbor_item.get_id().get_fasta_id();
-
-                    // Write neighbor's path|fasta_id plus the edge weight/distance
-                    write!(
-                        writer,
-                        "\t{}|{}:{:.6}",
-                        neighbor_path,
-                        neighbor_fasta_id,
-                        edge.weight.to_f64().unwrap()
-                    )?;
+    // Iterate over each node index in the graph
+    for (node_idx, neighbors) in graph.lists.iter().enumerate() {
+        // The HNSW data ID is used as an index into seqdict.0[..]
+        let node_item = &seqdict.0[data_ids[node_idx]];
+        write!(
+            writer,
+            "{}|{}:",
+            node_item.get_id().get_path(),
+            node_item.get_id().get_fasta_id()
+        )?;
+        for (neighbor_idx, distance) in neighbors {
+            let neighbor_item = &seqdict.0[data_ids[*neighbor_idx]];
+            // Write neighbor's path|fasta_id plus the edge weight/distance
+            write!(
+                writer,
+                "\t{}|{}:{:.6}",
+                neighbor_item.get_id().get_path(),
+                neighbor_item.get_id().get_fasta_id(),
+                distance
+            )?;
+        }
+        // Newline after each node's neighbor list
+        writeln!(writer)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Edge list, one edge by line: source, target, distance. Nodes are named by [node_names]
+fn save_edge_list(graph: &Graph, data_ids: &[usize], seqdict: &SeqDict, output_file: &str) -> std::io::Result<()> {
+    let names = node_names(data_ids, seqdict, false);
+    let mut writer = BufWriter::new(File::create(output_file)?);
+    writeln!(writer, "source\ttarget\tdistance")?;
+    for (i, j, distance) in graph.edges() {
+        writeln!(writer, "{}\t{}\t{:.6}", names[i], names[j], distance)?;
+    }
+    writer.flush()
+}
+
+/// Matrix Market coordinate matrix of distances, general or symmetric (lower triangle) if undirected.
+/// Rows and columns are node indexes + 1, the sequence of each index is written to output_file.nodes.tsv
+fn save_matrix_market(graph: &Graph, data_ids: &[usize], seqdict: &SeqDict, output_file: &str) -> std::io::Result<()> {
+    let nb_nodes = graph.lists.len();
+    let mut writer = BufWriter::new(File::create(output_file)?);
+    writeln!(
+        writer,
+        "%%MatrixMarket matrix coordinate real {}",
+        if graph.undirected { "symmetric" } else { "general" }
+    )?;
+    writeln!(writer, "% kNN graph distances, node names in {}.nodes.tsv", output_file)?;
+    writeln!(writer, "{} {} {}", nb_nodes, nb_nodes, graph.get_nb_edges())?;
+    for (i, j, distance) in graph.edges() {
+        // symmetric matrices store the lower triangle, row >= column
+        let (row, col) = if graph.undirected { (j, i) } else { (i, j) };
+        writeln!(writer, "{} {} {:.6}", row + 1, col + 1, distance)?;
+    }
+    writer.flush()?;
+    let mut writer = BufWriter::new(File::create(format!("{}.nodes.tsv", output_file))?);
+    writeln!(writer, "index\tpath\tfasta_id")?;
+    for (node_idx, data_id) in data_ids.iter().enumerate() {
+        let item = &seqdict.0[*data_id];
+        writeln!(writer, "{}\t{}\t{}", node_idx + 1, item.get_id().get_path(), item.get_id().get_fasta_id())?;
+    }
+    writer.flush()
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// GraphML with path, fasta_id and length of nodes, distance and weight (1 - distance) of edges
+fn save_graphml(graph: &Graph, data_ids: &[usize], seqdict: &SeqDict, output_file: &str) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(output_file)?);
+    writeln!(writer, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(writer, "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">")?;
+    writeln!(writer, "  <key id=\"path\" for=\"node\" attr.name=\"path\" attr.type=\"string\"/>")?;
+    writeln!(writer, "  <key id=\"fasta_id\" for=\"node\" attr.name=\"fasta_id\" attr.type=\"string\"/>")?;
+    writeln!(writer, "  <key id=\"length\" for=\"node\" attr.name=\"length\" attr.type=\"long\"/>")?;
+    writeln!(writer, "  <key id=\"distance\" for=\"edge\" attr.name=\"distance\" attr.type=\"double\"/>")?;
+    writeln!(writer, "  <key id=\"weight\" for=\"edge\" attr.name=\"weight\" attr.type=\"double\"/>")?;
+    writeln!(
+        writer,
+        "  <graph id=\"knn\" edgedefault=\"{}\">",
+        if graph.undirected { "undirected" } else { "directed" }
+    )?;
+    for (node_idx, data_id) in data_ids.iter().enumerate() {
+        let item = &seqdict.0[*data_id];
+        writeln!(writer, "    <node id=\"n{}\">", node_idx)?;
+        writeln!(writer, "      <data key=\"path\">{}</data>", xml_escape(item.get_id().get_path()))?;
+        writeln!(writer, "      <data key=\"fasta_id\">{}</data>", xml_escape(item.get_id().get_fasta_id()))?;
+        writeln!(writer, "      <data key=\"length\">{}</data>", item.get_len())?;
+        writeln!(writer, "    </node>")?;
+    }
+    for (i, j, distance) in graph.edges() {
+        writeln!(writer, "    <edge source=\"n{}\" target=\"n{}\">", i, j)?;
+        writeln!(writer, "      <data key=\"distance\">{:.6}</data>", distance)?;
+        writeln!(writer, "      <data key=\"weight\">{:.6}</data>", 1. - distance)?;
+        writeln!(writer, "    </edge>")?;
+    }
+    writeln!(writer, "  </graph>")?;
+    writeln!(writer, "</graphml>")?;
+    writer.flush()
+}
+
+/// GFA 1 segments without sequence (LN tag) and a link record by edge, with the distance in a ds:f tag.
+/// Segment names are FASTA IDs with blanks replaced by _, made unique by [node_names]
+fn save_gfa(graph: &Graph, data_ids: &[usize], seqdict: &SeqDict, output_file: &str) -> std::io::Result<()> {
+    let names = node_names(data_ids, seqdict, true);
+    let mut writer = BufWriter::new(File::create(output_file)?);
+    writeln!(writer, "H\tVN:Z:1.0")?;
+    for (name, data_id) in names.iter().zip(data_ids.iter()) {
+        writeln!(writer, "S\t{}\t*\tLN:i:{}", name, seqdict.0[*data_id].get_len())?;
+    }
+    for (i, j, distance) in graph.edges() {
+        writeln!(writer, "L\t{}\t+\t{}\t+\t*\tds:f:{:.6}", names[i], names[j], distance)?;
+    }
+    writer.flush()
+}
+
+/// kNN lists by searching the HNSW with each stored point, the point itself excluded.
+/// Nodes are the points in data id order, returns the data id of each node and kNN lists by node index.
+/// ef is doubled for points with fewer than k neighbors found, until k are found or ef reaches the number of points.
+fn exact_knn(hnsw: &Hnsw<f64, DistHamming>, k: usize, ef: usize) -> (Vec<usize>, Vec<Vec<(usize, f64)>>) {
+    let mut points: Vec<Arc<Point<f64>>> = hnsw.get_point_indexation().into_iter().collect();
+    points.sort_unstable_by_key(|point| point.get_origin_id());
+    let nb_points = points.len();
+    let max_data_id = points.last().map_or(0, |point| point.get_origin_id());
+    let mut node_of: Vec<Option<usize>> = vec![None; max_data_id + 1];
+    for (node_idx, point) in points.iter().enumerate() {
+        node_of[point.get_origin_id()] = Some(node_idx);
+    }
+    let knn = points
+        .par_iter()
+        .map(|point| {
+            let data_id = point.get_origin_id();
+            let mut ef_search = ef.max(k + 1);
+            loop {
+                let neighbours: Vec<(usize, f64)> = hnsw
+                    .search(point.get_v(), k + 1, ef_search)
+                    .iter()
+                    .filter(|n| n.d_id != data_id)
+                    .filter_map(|n| node_of.get(n.d_id).copied().flatten().map(|idx| (idx, n.distance as f64)))
+                    .take(k)
+                    .collect();
+                if neighbours.len() >= k.min(nb_points - 1) || ef_search >= nb_points {
+                    break neighbours;
                 }
+                ef_search *= 2;
             }
-            // Newline after each node's neighbor list
-            writeln!(writer)?;
+        })
+        .collect();
+    let data_ids = points.iter().map(|point| point.get_origin_id()).collect();
+    (data_ids, knn)
+}
+
+/// kNN lists of the KGraph by node index, as (neighbour index, distance)
+fn kgraph_to_lists<F>(kgraph: &KGraph<F>) -> Vec<Vec<(usize, f64)>>
+where
+    F: FromPrimitive + Float + std::fmt::UpperExp + Sync + Send + std::iter::Sum,
+{
+    (0..kgraph.get_nb_nodes())
+        .map(|node_idx| {
+            kgraph
+                .get_out_edges_by_idx(node_idx)
+                .iter()
+                .map(|edge| (edge.node, edge.weight.to_f64().unwrap()))
+                .collect()
+        })
+        .collect()
+}
+
+/// Report of kNN graph diagnostics: summary as name, value lines, out-degree distribution and top hubs,
+/// sections beginning with a # line
+fn save_diagnostics(
+    diagnostics: &KnnDiagnostics,
+    nb_hubs: usize,
+    data_ids: &[usize],
+    seqdict: &SeqDict,
+    output_file: &str,
+) -> std::io::Result<()> {
+    let nb_nodes = diagnostics.get_nb_nodes();
+    let fraction_short = diagnostics.get_fraction_short();
+    let summary = [
+        ("nb_nodes", nb_nodes.to_string()),
+        ("k", diagnostics.k.to_string()),
+        ("mean_out_degree", format!("{:.3}", diagnostics.get_mean_out_degree())),
+        ("fraction_fewer_than_k", format!("{:.6}", fraction_short)),
+        ("nb_empty_lists", diagnostics.get_nb_empty().to_string()),
+        ("nb_isolated", diagnostics.nb_isolated.to_string()),
+        ("nb_antihubs", diagnostics.get_nb_antihubs().to_string()),
+        ("max_in_degree", diagnostics.in_degrees.iter().max().copied().unwrap_or(0).to_string()),
+        ("hubness_skewness", format!("{:.4}", diagnostics.get_hubness())),
+    ];
+    let mut writer = BufWriter::new(File::create(output_file)?);
+    writeln!(writer, "# summary")?;
+    for (name, value) in summary.iter() {
+        println!("{}: {}", name, value);
+        writeln!(writer, "{}\t{}", name, value)?;
+    }
+    writeln!(writer, "# out-degree distribution")?;
+    writeln!(writer, "out_degree\tnb_nodes\tfraction")?;
+    for (degree, count) in diagnostics.out_degree_histogram().iter().enumerate() {
+        if *count > 0 {
+            writeln!(writer, "{}\t{}\t{:.6}", degree, count, *count as f64 / nb_nodes as f64)?;
         }
     }
-
+    writeln!(writer, "# top hubs")?;
+    writeln!(writer, "rank\tin_degree\tout_degree\tpath\tfasta_id")?;
+    for (rank, (node_idx, in_degree)) in diagnostics.top_hubs(nb_hubs).iter().enumerate() {
+        let item = &seqdict.0[data_ids[*node_idx]];
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}",
+            rank + 1,
+            in_degree,
+            diagnostics.out_degrees[*node_idx],
+            item.get_id().get_path(),
+            item.get_id().get_fasta_id()
+        )?;
+    }
     writer.flush()?;
+    if fraction_short > 0.1 {
+        println!(
+            "Warning: {:.1}% of sequences have fewer than {} neighbors, rebuild the database with a larger --max_nb_connection or --hnsw-ef, or ask fewer neighbors",
+            100. * fraction_short,
+            diagnostics.k
+        );
+    }
     Ok(())
 }
+
+/// Write the community of each sequence (path, fasta_id, community, community size), communities being
+/// numbered by decreasing size, and modularity statistics in a .stats file next to it
+fn save_communities(
+    data_ids: &[usize],
+    seqdict: &SeqDict,
+    communities: &Communities,
+    resolution: f64,
+    output_file: &str,
+) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(output_file)?);
+    writeln!(writer, "path\tfasta_id\tcommunity\tcommunity_size")?;
+    for (node_idx, community) in communities.labels.iter().enumerate() {
+        let item = &seqdict.0[data_ids[node_idx]];
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}",
+            item.get_id().get_path(),
+            item.get_id().get_fasta_id(),
+            community,
+            communities.sizes[*community]
+        )?;
+    }
+    writer.flush()?;
+    // statistics
+    let nb_singletons = communities.sizes.iter().filter(|size| **size == 1).count();
+    let stats = [
+        ("nb_nodes", communities.labels.len().to_string()),
+        ("nb_communities", communities.sizes.len().to_string()),
+        ("modularity", format!("{:.6}", communities.modularity)),
+        ("resolution", resolution.to_string()),
+        ("nb_levels", communities.nb_levels.to_string()),
+        ("largest_community", communities.sizes.first().copied().unwrap_or(0).to_string()),
+        ("nb_singletons", nb_singletons.to_string()),
+    ];
+    let mut writer = BufWriter::new(File::create(format!("{}.stats", output_file))?);
+    for (name, value) in stats.iter() {
+        println!("{}: {}", name, value);
+        writeln!(writer, "{}\t{}", name, value)?;
+    }
+    writer.flush()
+}
@@ -1,10 +1,11 @@
 use clap::{Arg, ArgAction, Command};
 use needletail::{parse_fastx_file, Sequence};
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use std::thread;
 use std::path::PathBuf;
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
+use std::collections::{BTreeMap, HashMap};
 
 use hnsw_rs::prelude::*;
 use gsearch::utils::idsketch::{Id, ItemDict};
@@ -12,33 +13,22 @@ use gsearch::utils::parameters::*;
 use gsearch::utils::reloadhnsw;
 use gsearch::utils::SeqDict;
 use gsearch::answer::ReqAnswer;
-use kmerutils::base::{kmergenerator::*, Kmer32bit, CompressedKmerT};
+use kmerutils::base::Kmer32bit;
 use kmerutils::sketching::setsketchert::*;
-use kmerutils::base::alphabet::Alphabet2b;
 use kmerutils::base::sequence::Sequence as SequenceStruct;
 
 use log::info;
-use std::io::BufWriter;
-use crossbeam::channel::{unbounded, Sender, Receiver};
-
-fn ascii_to_seq(bases: &[u8]) -> Result<SequenceStruct, ()> {
-    let alphabet = Alphabet2b::new();
-    let mut seq = SequenceStruct::with_capacity(2, bases.len());
-    seq.encode_and_add(bases, &alphabet);
-    Ok(seq)
-} // end of ascii_to_seq
-
-// Define the k-mer hash function as a function
-fn kmer_hash_fn_32bit(kmer: &Kmer32bit) -> <Kmer32bit as CompressedKmerT>::Val {
-    let canonical = kmer.reverse_complement().min(*kmer);
-    let nb_alphabet_bits = Alphabet2b::new().get_nb_bits();
-    let mask: <Kmer32bit as CompressedKmerT>::Val =
-        ((1u64 << (nb_alphabet_bits * kmer.get_nb_base())) - 1)
-            .try_into()
-            .unwrap();
-    let hashval = canonical.get_compressed_value() & mask;
-    hashval
-}
+use std::io::{BufWriter, Write};
+use crossbeam::channel::{bounded, Sender, Receiver};
+use minimap2::Aligner;
+use rayon::prelude::*;
+
+use adas::abundance::AbundanceCollector;
+use adas::editdist::{compare_both_strands, EditMode, EditResult};
+use adas::presets::{preset_from_name, PRESET_NAMES};
+use adas::seqstore::DatabaseSequences;
+use adas::sketchcode::{reload_sketch_code, SketchIndex};
+use adas::sketching::{ascii_to_seq, kmer_hash_fn_32bit, sketch_fasta_batches};
 
 /// Parameters defining a Request in a Hnsw database
 pub struct SearchParams {
@@ -114,12 +104,132 @@ fn main() {
                 .value_parser(clap::value_parser!(usize))
                 .default_value("1"),
         )
+        .arg(
+            Arg::new("stream")
+                .long("stream")
+                .help("Sketch and search queries by batches, writing answers in input order as they come")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("batch_size")
+                .long("batch-size")
+                .value_name("BATCH_SIZE")
+                .help("Number of queries sketched together, and searched together in streaming mode")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("10000"),
+        )
+        .arg(
+            Arg::new("align")
+                .long("align")
+                .help("Align each query against its top hits and rerank them by alignment identity and coverage")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("align_top")
+                .long("align-top")
+                .value_name("NB_ALIGNED")
+                .help("Number of best HNSW hits aligned by query")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("10"),
+        )
+        .arg(
+            Arg::new("align_preset")
+                .long("align-preset")
+                .value_name("PRESET")
+                .help("minimap2 preset for the alignment of top hits, e.g. sr or asm5 for short or high identity queries")
+                .action(ArgAction::Set)
+                .value_parser(PRESET_NAMES)
+                .default_value("map-ont"),
+        )
+        .arg(
+            Arg::new("reference")
+                .short('r')
+                .long("reference")
+                .value_name("REFERENCE_FASTA")
+                .help("FASTA file of database sequences used for alignment, default to the paths recorded in the database")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("containment")
+                .long("containment")
+                .help("Rank hits by containment of the query in the hit instead of sketch distance, for amplicons or partial genes against longer references")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("candidates")
+                .long("candidates")
+                .value_name("NB_CANDIDATES")
+                .help("Number of HNSW candidates reranked by containment, default 5 times the number of answers")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .requires("containment"),
+        )
+        .arg(
+            Arg::new("abundance")
+                .long("abundance")
+                .help("Estimate the relative abundance of database sequences and source files from the hits of all queries (reads)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("abundance_delta")
+                .long("abundance-delta")
+                .value_name("DELTA")
+                .help("A read is compatible with its hits within this distance of its best hit, shared reads are resolved by EM")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(f32))
+                .default_value("0.01")
+                .requires("abundance"),
+        )
+        .arg(
+            Arg::new("abundance_max_distance")
+                .long("abundance-max-distance")
+                .value_name("DIST")
+                .help("Reads whose best hit is farther than this distance are not assigned to any reference (off-target reads)")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(f32))
+                .default_value("0.9")
+                .requires("abundance"),
+        )
+        .arg(
+            Arg::new("identity")
+                .long("identity")
+                .value_name("MODE")
+                .help("Compute the exact edit distance and identity between each query and all its hits, global or semi-global (whole query against part of the hit)")
+                .action(ArgAction::Set)
+                .value_parser(["global", "semi-global"]),
+        )
         .get_matches();
     
     let fasta_path = matches.get_one::<String>("input").unwrap().to_string();
     let nb_answers_search = *matches.get_one::<usize>("nb_answers").unwrap();
     let db_path = matches.get_one::<String>("database_path").unwrap().to_string();
     let num_threads = *matches.get_one::<usize>("threads").unwrap();
+    let streaming = matches.get_flag("stream");
+    let batch_size = (*matches.get_one::<usize>("batch_size").unwrap()).max(1);
+    let align = matches.get_flag("align");
+    let align_top = *matches.get_one::<usize>("align_top").unwrap();
+    let align_preset = matches.get_one::<String>("align_preset").unwrap().as_str();
+    let reference_path = matches.get_one::<String>("reference").cloned();
+    let containment = matches.get_flag("containment");
+    let nb_candidates = matches
+        .get_one::<usize>("candidates")
+        .copied()
+        .unwrap_or(5 * nb_answers_search)
+        .max(nb_answers_search);
+    let mut abundance = if matches.get_flag("abundance") {
+        Some(AbundanceCollector::new(
+            *matches.get_one::<f32>("abundance_delta").unwrap(),
+            *matches.get_one::<f32>("abundance_max_distance").unwrap(),
+        ))
+    } else {
+        None
+    };
+    let identity_mode = matches
+        .get_one::<String>("identity")
+        .map(|mode| mode.parse::<EditMode>().unwrap());
     
     let num_cpus = num_cpus::get();
     let num_threads = if num_threads > num_cpus {
@@ -163,11 +273,10 @@ fn main() {
     let sketcher = Sketcher::new(&sketch_params);
 
     println!("Loading HNSW index...");
-    // Load the HNSW index
-    let hnsw_res = hnswio.load_hnsw::<
-        <OptDensHashSketch<Kmer, f64> as SeqSketcherT<Kmer32bit>>::Sig,
-        DistHamming
-    >();
+    // Load the HNSW index, signatures of a database with a compact sketch code are integer codes, queries are encoded on the fly
+    let sketch_code = reload_sketch_code(database_dirpath)
+        .unwrap_or_else(|e| panic!("Cannot read sketch code of the database: {:?}", e));
+    let hnsw_res = SketchIndex::load(&mut hnswio, sketch_code);
     if let Err(e) = hnsw_res {
         panic!("error : {:?}", e);
     }
@@ -188,100 +297,12 @@ fn main() {
     };
     println!("HNSW index loaded...");
 
-    println!("Sketching...");
     // Set the number of threads globally using Rayon
     rayon::ThreadPoolBuilder::new()
         .num_threads(num_threads)
         .build_global()
         .unwrap();
 
-    // -------------------------------------------------
-    // Use Crossbeam's unbounded channel for multiple consumers
-    let (tx, rx): (Sender<(Vec<u8>, Vec<u8>)>, Receiver<(Vec<u8>, Vec<u8>)>) = unbounded();
-
-    // Producer thread: read FASTA -> send to channel
-    let fasta_path_clone = fasta_path.clone();
-    let producer_handle = thread::spawn(move || {
-        let mut reader =
-            parse_fastx_file(&Path::new(&fasta_path_clone))
-                .expect("Invalid path/file for FASTA");
-
-        while let Some(record) = reader.next() {
-            let seqrec = record.expect("Invalid record");
-            let seq_id = seqrec.id().to_owned();         // Vec<u8>
-            let seq_seq = seqrec.normalize(false).into_owned(); // Vec<u8>
-            tx.send((seq_id, seq_seq)).expect("Could not send data");
-        }
-        // Close sending
-        drop(tx);
-    });
-
-    // Shared storage
-    let signatures = Arc::new(Mutex::new(Vec::new()));
-    let itemv = Arc::new(Mutex::new(Vec::new()));
-
-    // We’ll spawn multiple consumer threads
-    let mut consumer_handles = Vec::with_capacity(num_threads);
-
-    for _ in 0..num_threads {
-        let rx_clone = rx.clone(); // each consumer gets a cloned receiver
-        let sketcher_clone = sketcher.clone();
-        let signatures_clone = Arc::clone(&signatures);
-        let itemv_clone = Arc::clone(&itemv);
-        let fasta_path_clone_for_consumer = fasta_path.clone();
-
-        let handle = thread::spawn(move || {
-            // Loop over messages
-            for (seq_id, seq_seq) in rx_clone.iter() {
-                // Convert the sequence
-                let seq = ascii_to_seq(&seq_seq).unwrap();
-                let vseq = vec![&seq];
-
-                // Sketch the sequence (1 item)
-                let signatures_vec = sketcher_clone.sketch_compressedkmer(&vseq, kmer_hash_fn_32bit);
-                let signature = signatures_vec.into_iter().next().unwrap();
-
-                // Store the signature
-                {
-                    let mut s_lock = signatures_clone.lock().unwrap();
-                    s_lock.push(signature);
-                }
-                // Store sequence metadata
-                {
-                    let mut itemv_lock = itemv_clone.lock().unwrap();
-                    let id = Id::new(
-                        &fasta_path_clone_for_consumer,
-                        &String::from_utf8(seq_id).unwrap(),
-                    );
-                    let item = ItemDict::new(id, seq.size());
-                    itemv_lock.push(item);
-                }
-            }
-        });
-        consumer_handles.push(handle);
-    }
-
-    // Wait for producer + all consumers
-    producer_handle.join().expect("Producer thread panicked");
-    for handle in consumer_handles {
-        handle.join().expect("Consumer thread panicked");
-    }
-
-    // Retrieve the collected signatures and metadata
-    let signatures = Arc::try_unwrap(signatures)
-        .expect("Multiple references to signatures remain")
-        .into_inner()
-        .expect("Mutex was poisoned in signatures");
-    let itemv = match Arc::try_unwrap(itemv) {
-        Ok(mutex) => mutex.into_inner().unwrap(),
-        Err(_arc_mutex) => {
-            // Handle the case where the Arc has more than one strong reference.
-            panic!("Cannot unwrap Arc because there are multiple references");
-        }
-    };
-    println!("Sketching done.");
-    // Searching
-    
     let ef_search = 5000;
     let out_threshold = 1.0;
     let outname = "adas.neighbors.txt";
@@ -301,9 +322,136 @@ fn main() {
     }
     let mut outfile = BufWriter::new(outfile.unwrap());
 
+    // Reference sequences, needed for the alignment of top hits and for identities
+    let keep_query_seqs = align || identity_mode.is_some();
+    // they are read on demand, only for the hits of queries
+    let refseqs = if keep_query_seqs {
+        println!("Opening database sequences for alignment...");
+        let refseqs = DatabaseSequences::open(&seqdict, database_dirpath, reference_path.as_deref());
+        let nb_missing = refseqs.get_nb_missing(seqdict.0.len());
+        if nb_missing > 0 {
+            log::warn!("{} database sequences not found, they will not be aligned", nb_missing);
+            println!("Warning: {} database sequences not found, they will not be aligned", nb_missing);
+        }
+        Some(refseqs)
+    } else {
+        None
+    };
+    let create_writer = |outname: &str, header: &str| -> BufWriter<File> {
+        let file = File::create(outname).unwrap_or_else(|e| {
+            log::error!("Could not open file {}. Error: {:?}", outname, e);
+            std::process::exit(1);
+        });
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "{}", header).unwrap();
+        writer
+    };
+    // Outputs for the alignment of top hits and for identities
+    let align_outname = "adas.align.txt";
+    let mut aligning = if align {
+        Some((align_top, align_preset, create_writer(align_outname, ALIGN_HEADER)))
+    } else {
+        None
+    };
+    let identity_outname = "adas.identity.txt";
+    let mut identities = identity_mode.map(|mode| (mode, create_writer(identity_outname, IDENTITY_HEADER)));
+    // In containment mode more candidates are searched, then reranked and cut to the number of answers
+    let containment_outname = "adas.containment.txt";
+    let mut containments = if containment {
+        let kmer_size = sketch_params.get_kmer_size();
+        Some((kmer_size, nb_candidates, create_writer(containment_outname, CONTAINMENT_HEADER)))
+    } else {
+        None
+    };
+
+    if streaming {
+        println!("Sketching and searching by batches of {} queries...", batch_size);
+        let nb_queries = search_streaming(
+            &hnsw,
+            &seqdict,
+            &sketcher,
+            &fasta_path,
+            nb_answers_search,
+            ef_search,
+            batch_size,
+            out_threshold,
+            &mut outfile,
+            refseqs.as_ref(),
+            aligning.as_mut().map(|(align_top, align_preset, align_writer)| (*align_top, *align_preset, align_writer)),
+            identities.as_mut().map(|(mode, identity_writer)| (*mode, identity_writer)),
+            containments
+                .as_mut()
+                .map(|(kmer_size, nb_candidates, containment_writer)| (*kmer_size, *nb_candidates, containment_writer)),
+            abundance.as_mut(),
+        );
+        println!("Searched {} queries. Search results saved to {}", nb_queries, outname);
+        if aligning.is_some() {
+            println!("Alignments of top hits saved to {}", align_outname);
+        }
+        if identities.is_some() {
+            println!("Identities of hits saved to {}", identity_outname);
+        }
+        if containments.is_some() {
+            println!("Containments of queries in hits saved to {}", containment_outname);
+        }
+        if let Some(abundance) = abundance.as_ref() {
+            dump_abundances(abundance, &seqdict);
+        }
+        return;
+    }
+
+    println!("Sketching...");
+    // Queries are sketched by batches on the rayon pool and collected in file order,
+    // query sequences are kept only if we align them or compute identities
+    let mut signatures: Vec<Vec<f64>> = Vec::new();
+    let mut itemv: Vec<ItemDict> = Vec::new();
+    let mut query_seqs: Vec<Vec<u8>> = Vec::new();
+    let start = Instant::now();
+    let nb_sketched = sketch_fasta_batches(&fasta_path, &sketcher, batch_size, |batch, lens, batch_signatures| {
+        signatures.extend(batch_signatures);
+        for ((seq_id, seq_seq), len) in batch.into_iter().zip(lens) {
+            let id = Id::new(&fasta_path, &String::from_utf8(seq_id).unwrap());
+            itemv.push(ItemDict::new(id, len));
+            if keep_query_seqs {
+                query_seqs.push(seq_seq);
+            }
+        }
+    });
+    let elapsed = start.elapsed().as_secs_f64();
+    println!(
+        "Sketching done: {} queries in {:.2} s, {:.0} queries/s",
+        nb_sketched,
+        elapsed,
+        nb_sketched as f64 / elapsed.max(1e-9)
+    );
+    // Searching
+
     println!("Searching HNSW index...");
     // We do parallel_search with our signature vector
-    let knn_neighbours = hnsw.parallel_search(&signatures, nb_answers_search, ef_search); 
+    let nb_searched = if containment { nb_candidates } else { nb_answers_search };
+    let knn_neighbours = hnsw.parallel_search(&signatures, nb_searched, ef_search);
+    let knn_neighbours = match containments.as_mut() {
+        Some((kmer_size, _, containment_writer)) => {
+            let mut reranked = Vec::with_capacity(knn_neighbours.len());
+            for (i, neighbours) in knn_neighbours.into_iter().enumerate() {
+                let hits = rank_by_containment(&itemv[i], neighbours, &seqdict, *kmer_size, nb_answers_search);
+                if dump_containment_hits(i, &itemv[i], &hits, &seqdict, containment_writer).is_err() {
+                    log::info!("could not dump containments for request id {}", itemv[i].get_id().get_fasta_id());
+                }
+                reranked.push(hits.into_iter().map(|hit| hit.neighbour).collect::<Vec<Neighbour>>());
+            }
+            containment_writer.flush().unwrap();
+            println!("Containments of queries in hits saved to {}", containment_outname);
+            reranked
+        }
+        None => knn_neighbours,
+    };
+    if let Some(abundance) = abundance.as_mut() {
+        for neighbours in knn_neighbours.iter() {
+            abundance.add_read(neighbours);
+        }
+        dump_abundances(abundance, &seqdict);
+    }
     for i in 0..knn_neighbours.len() {
         let answer = ReqAnswer::new(i, itemv[i].clone(), &knn_neighbours[i]);
         if answer.dump(&seqdict, out_threshold, &mut outfile).is_err() {
@@ -314,4 +462,627 @@ fn main() {
         }
     }
     println!("Searching HNSW index done. Search results saved to adas.neighbors.txt");
+
+    let refseqs = refseqs.as_ref();
+    if let Some((align_top, align_preset, align_writer)) = aligning.as_mut() {
+        let (align_top, align_preset) = (*align_top, *align_preset);
+        println!("Aligning queries against their top {} hits...", align_top);
+        let aligned: Vec<Vec<AlignedHit>> = query_seqs
+            .par_iter()
+            .zip(knn_neighbours.par_iter())
+            .map(|(query, neighbours)| align_to_hits(query, neighbours, refseqs, align_top, align_preset))
+            .collect();
+        for (i, hits) in aligned.iter().enumerate() {
+            if dump_aligned_hits(i, &itemv[i], hits, &seqdict, align_writer).is_err() {
+                log::info!("could not dump alignments for request id {}", itemv[i].get_id().get_fasta_id());
+            }
+        }
+        align_writer.flush().unwrap();
+        println!("Alignments of top hits saved to {}", align_outname);
+    }
+    if let Some((mode, identity_writer)) = identities.as_mut() {
+        let mode = *mode;
+        println!("Computing identities of queries with their hits...");
+        let identity_hits: Vec<Vec<IdentityHit>> = query_seqs
+            .par_iter()
+            .zip(knn_neighbours.par_iter())
+            .map(|(query, neighbours)| identities_of_hits(query, neighbours, refseqs, mode))
+            .collect();
+        for (i, hits) in identity_hits.iter().enumerate() {
+            if dump_identity_hits(i, &itemv[i], hits, &seqdict, identity_writer).is_err() {
+                log::info!("could not dump identities for request id {}", itemv[i].get_id().get_fasta_id());
+            }
+        }
+        identity_writer.flush().unwrap();
+        println!("Identities of hits saved to {}", identity_outname);
+    }
+}
+
+/// Header of the alignment output file
+const ALIGN_HEADER: &str = "query_rank\tquery_id\tquery_len\trank\ttarget_path\ttarget_id\ttarget_len\tdistance\tidentity\tquery_cov\ttarget_cov\tquery_start\tquery_end\ttarget_start\ttarget_end\tstrand\tmapq";
+
+/// Alignment of a query against one of its HNSW hits
+struct AlignedHit {
+    /// data id of the hit in the database
+    d_id: usize,
+    /// sketch distance returned by the HNSW search
+    distance: f32,
+    /// matching bases over alignment block length, 0 if the hit did not align
+    identity: f64,
+    /// fraction of the query covered by the alignment
+    query_coverage: f64,
+    /// fraction of the hit covered by the alignment
+    target_coverage: f64,
+    query_start: i32,
+    query_end: i32,
+    target_start: i32,
+    target_end: i32,
+    strand: char,
+    mapq: u32,
 }
+
+impl AlignedHit {
+    /// identity times query coverage: about the fraction of the query matched, so that a short local
+    /// alignment does not outrank a full length one of slightly lower identity
+    fn get_score(&self) -> f64 {
+        self.identity * self.query_coverage
+    }
+}
+
+/// Align a query with minimap2, with preset `preset_name`, against the sequences of its `nb_top` first neighbours.
+/// Returns the hits reranked by decreasing identity times query coverage, then increasing sketch distance.
+///
+/// A minimap2 index of the (at most `nb_top`) hit sequences is built for each query, which copies them: cheap
+/// for genes and reads, noticeable for genome sized references. One index for all the hits of a batch would
+/// avoid it, but minimap2 would then rank the hits of a query against the hits of other queries and could
+/// drop them as secondary mappings.
+/// Hits without sequence or without alignment are kept with a null identity.
+fn align_to_hits(
+    query: &[u8],
+    neighbours: &[Neighbour],
+    refseqs: Option<&DatabaseSequences>,
+    nb_top: usize,
+    preset_name: &str,
+) -> Vec<AlignedHit> {
+    let top = &neighbours[..nb_top.min(neighbours.len())];
+    let mut hits: Vec<AlignedHit> = top
+        .iter()
+        .map(|n| AlignedHit {
+            d_id: n.d_id,
+            distance: n.distance,
+            identity: 0.,
+            query_coverage: 0.,
+            target_coverage: 0.,
+            query_start: 0,
+            query_end: 0,
+            target_start: 0,
+            target_end: 0,
+            strand: '*',
+            mapq: 0,
+        })
+        .collect();
+    // targets are named by their data id so we can go back to the hit
+    let mut seqs: Vec<Vec<u8>> = Vec::with_capacity(top.len());
+    let mut ids: Vec<Vec<u8>> = Vec::with_capacity(top.len());
+    for n in top {
+        if let Some(seq) = refseqs.and_then(|refseqs| refseqs.get(n.d_id)) {
+            seqs.push(seq);
+            ids.push(n.d_id.to_string().into_bytes());
+        }
+    }
+    if seqs.is_empty() || query.is_empty() {
+        return hits;
+    }
+    let aligner = match Aligner::builder().preset(preset_from_name(preset_name)).with_cigar().with_seqs_and_ids(&seqs, &ids) {
+        Ok(aligner) => aligner,
+        Err(e) => {
+            log::error!("cannot build aligner on hits : {:?}", e);
+            return hits;
+        }
+    };
+    let mappings = match aligner.map(query, false, false, None, None, None) {
+        Ok(mappings) => mappings,
+        Err(e) => {
+            log::error!("alignment failed : {:?}", e);
+            return hits;
+        }
+    };
+    // keep for each hit the mapping with the best identity times query coverage
+    for mapping in mappings.iter() {
+        let d_id = match mapping.target_name.as_ref().and_then(|name| name.parse::<usize>().ok()) {
+            Some(d_id) => d_id,
+            None => continue,
+        };
+        if mapping.block_len <= 0 {
+            continue;
+        }
+        let identity = mapping.match_len as f64 / mapping.block_len as f64;
+        let query_coverage = (mapping.query_end - mapping.query_start) as f64 / query.len() as f64;
+        for hit in hits.iter_mut().filter(|h| h.d_id == d_id) {
+            if hit.strand != '*' && identity * query_coverage <= hit.get_score() {
+                continue;
+            }
+            hit.identity = identity;
+            hit.query_coverage = query_coverage;
+            hit.target_coverage = if mapping.target_len > 0 {
+                (mapping.target_end - mapping.target_start) as f64 / mapping.target_len as f64
+            } else {
+                0.
+            };
+            hit.query_start = mapping.query_start;
+            hit.query_end = mapping.query_end;
+            hit.target_start = mapping.target_start;
+            hit.target_end = mapping.target_end;
+            hit.strand = match mapping.strand {
+                minimap2::Strand::Forward => '+',
+                minimap2::Strand::Reverse => '-',
+            };
+            hit.mapq = mapping.mapq;
+        }
+    }
+    hits.sort_by(|a, b| {
+        b.get_score()
+            .partial_cmp(&a.get_score())
+            .unwrap()
+            .then(a.distance.partial_cmp(&b.distance).unwrap())
+    });
+    hits
+} // end of align_to_hits
+
+/// write one line by aligned hit, in rerank order
+fn dump_aligned_hits(
+    query_rank: usize,
+    query: &ItemDict,
+    hits: &[AlignedHit],
+    seqdict: &SeqDict,
+    out: &mut BufWriter<File>,
+) -> std::io::Result<()> {
+    for (rank, hit) in hits.iter().enumerate() {
+        let item = &seqdict.0[hit.d_id];
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.6}\t{:.4}\t{:.4}\t{:.4}\t{}\t{}\t{}\t{}\t{}\t{}",
+            query_rank,
+            query.get_id().get_fasta_id(),
+            query.get_len(),
+            rank,
+            item.get_id().get_path(),
+            item.get_id().get_fasta_id(),
+            item.get_len(),
+            hit.distance,
+            hit.identity,
+            hit.query_coverage,
+            hit.target_coverage,
+            hit.query_start,
+            hit.query_end,
+            hit.target_start,
+            hit.target_end,
+            hit.strand,
+            hit.mapq
+        )?;
+    }
+    Ok(())
+} // end of dump_aligned_hits
+
+/// Estimate abundances by EM from the collected reads and write them by database sequence in adas.abundance.txt
+/// and by source file in adas.abundance.files.txt, most abundant first
+fn dump_abundances(abundance: &AbundanceCollector, seqdict: &SeqDict) {
+    let outname = "adas.abundance.txt";
+    let files_outname = "adas.abundance.files.txt";
+    println!(
+        "Estimating abundances from {} reads ({} unassigned, {} classes of compatible references)...",
+        abundance.get_nb_reads(),
+        abundance.get_nb_unassigned(),
+        abundance.get_nb_classes()
+    );
+    let estimate = abundance.estimate(|d_id| seqdict.0[d_id].get_len(), 1000, 1e-3);
+    log::info!("EM converged in {} iterations", estimate.nb_iterations);
+    let mut order: Vec<usize> = (0..estimate.d_ids.len()).collect();
+    order.sort_by(|a, b| estimate.abundances[*b].partial_cmp(&estimate.abundances[*a]).unwrap());
+    let write_all = || -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(outname)?);
+        writeln!(out, "path\tfasta_id\tlen\tnb_reads\trelative_abundance")?;
+        // by source file: (expected reads, relative abundance)
+        let mut by_file: HashMap<&String, (f64, f64)> = HashMap::new();
+        for i in order.iter().copied() {
+            let item = &seqdict.0[estimate.d_ids[i]];
+            let (counts, abundance) = (estimate.counts[i], estimate.abundances[i]);
+            if counts <= 0. {
+                continue;
+            }
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{:.3}\t{:.6}",
+                item.get_id().get_path(),
+                item.get_id().get_fasta_id(),
+                item.get_len(),
+                counts,
+                abundance
+            )?;
+            let file_entry = by_file.entry(item.get_id().get_path()).or_insert((0., 0.));
+            file_entry.0 += counts;
+            file_entry.1 += abundance;
+        }
+        out.flush()?;
+        let mut by_file: Vec<(&String, (f64, f64))> = by_file.into_iter().collect();
+        by_file.sort_by(|a, b| (b.1).1.partial_cmp(&(a.1).1).unwrap());
+        let mut out = BufWriter::new(File::create(files_outname)?);
+        writeln!(out, "path\tnb_reads\trelative_abundance")?;
+        for (path, (counts, abundance)) in by_file {
+            writeln!(out, "{}\t{:.3}\t{:.6}", path, counts, abundance)?;
+        }
+        out.flush()
+    };
+    match write_all() {
+        Ok(()) => println!("Abundances saved to {} and {}", outname, files_outname),
+        Err(e) => log::error!("could not write abundances : {:?}", e),
+    }
+} // end of dump_abundances
+
+/// Header of the containment output file
+const CONTAINMENT_HEADER: &str = "query_rank\tquery_id\tquery_len\trank\ttarget_path\ttarget_id\ttarget_len\tdistance\tjaccard\tcontainment";
+
+/// A hit with the estimated containment of the query in it
+struct ContainedHit {
+    neighbour: Neighbour,
+    /// Jaccard index estimated by the sketch, 1 - distance
+    jaccard: f64,
+    containment: f64,
+}
+
+/// Containment of a query in a hit, |Q ∩ T| / |Q|, from the Jaccard index J of the sketches and
+/// the numbers of k-mers of both sequences: |Q ∩ T| = J (|Q| + |T|) / (1 + J).
+/// Jaccard is low when lengths differ a lot even if the query is entirely contained in the hit, containment is not.
+fn containment_from_jaccard(jaccard: f64, query_kmers: usize, target_kmers: usize) -> f64 {
+    if query_kmers == 0 {
+        return 0.;
+    }
+    let intersection = jaccard * (query_kmers + target_kmers) as f64 / (1. + jaccard);
+    (intersection / query_kmers as f64).clamp(0., 1.)
+}
+
+/// Reranks the neighbours of a query by decreasing containment (then increasing distance) and keeps `nb_answers`.
+/// Numbers of k-mers are estimated from the sequence lengths recorded in the items.
+fn rank_by_containment(
+    query: &ItemDict,
+    neighbours: Vec<Neighbour>,
+    seqdict: &SeqDict,
+    kmer_size: usize,
+    nb_answers: usize,
+) -> Vec<ContainedHit> {
+    let nb_kmers = |len: usize| (len + 1).saturating_sub(kmer_size);
+    let query_kmers = nb_kmers(query.get_len());
+    let mut hits: Vec<ContainedHit> = neighbours
+        .into_iter()
+        .map(|neighbour| {
+            let jaccard = (1. - neighbour.distance as f64).max(0.);
+            let target_kmers = nb_kmers(seqdict.0[neighbour.d_id].get_len());
+            ContainedHit {
+                neighbour,
+                jaccard,
+                containment: containment_from_jaccard(jaccard, query_kmers, target_kmers),
+            }
+        })
+        .collect();
+    hits.sort_by(|a, b| {
+        b.containment
+            .partial_cmp(&a.containment)
+            .unwrap()
+            .then(a.neighbour.distance.partial_cmp(&b.neighbour.distance).unwrap())
+    });
+    hits.truncate(nb_answers);
+    hits
+} // end of rank_by_containment
+
+/// write one line by hit, in containment order
+fn dump_containment_hits(
+    query_rank: usize,
+    query: &ItemDict,
+    hits: &[ContainedHit],
+    seqdict: &SeqDict,
+    out: &mut BufWriter<File>,
+) -> std::io::Result<()> {
+    for (rank, hit) in hits.iter().enumerate() {
+        let item = &seqdict.0[hit.neighbour.d_id];
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.6}\t{:.4}\t{:.4}",
+            query_rank,
+            query.get_id().get_fasta_id(),
+            query.get_len(),
+            rank,
+            item.get_id().get_path(),
+            item.get_id().get_fasta_id(),
+            item.get_len(),
+            hit.neighbour.distance,
+            hit.jaccard,
+            hit.containment
+        )?;
+    }
+    Ok(())
+} // end of dump_containment_hits
+
+/// Header of the identity output file
+const IDENTITY_HEADER: &str = "query_rank\tquery_id\tquery_len\trank\ttarget_path\ttarget_id\ttarget_len\tdistance\tedit_distance\tidentity\tstrand";
+
+/// Exact edit distance of a query to one of its HNSW hits
+struct IdentityHit {
+    d_id: usize,
+    /// sketch distance returned by the HNSW search
+    distance: f32,
+    /// None if the hit sequence is not available
+    edit: Option<EditResult>,
+}
+
+/// Edit distance and identity of a query with each of its neighbours, on the best strand, in HNSW order
+fn identities_of_hits(
+    query: &[u8],
+    neighbours: &[Neighbour],
+    refseqs: Option<&DatabaseSequences>,
+    mode: EditMode,
+) -> Vec<IdentityHit> {
+    neighbours
+        .iter()
+        .map(|n| IdentityHit {
+            d_id: n.d_id,
+            distance: n.distance,
+            edit: refseqs
+                .and_then(|refseqs| refseqs.get(n.d_id))
+                .map(|target| compare_both_strands(query, &target, mode)),
+        })
+        .collect()
+} // end of identities_of_hits
+
+/// write one line by hit, hits without sequence have * as edit distance, identity and strand
+fn dump_identity_hits(
+    query_rank: usize,
+    query: &ItemDict,
+    hits: &[IdentityHit],
+    seqdict: &SeqDict,
+    out: &mut BufWriter<File>,
+) -> std::io::Result<()> {
+    for (rank, hit) in hits.iter().enumerate() {
+        let item = &seqdict.0[hit.d_id];
+        let (edit_distance, identity, strand) = match hit.edit {
+            Some(edit) => (
+                edit.distance.to_string(),
+                format!("{:.4}", edit.identity),
+                if edit.reverse { "-" } else { "+" },
+            ),
+            None => (String::from("*"), String::from("*"), "*"),
+        };
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.6}\t{}\t{}\t{}",
+            query_rank,
+            query.get_id().get_fasta_id(),
+            query.get_len(),
+            rank,
+            item.get_id().get_path(),
+            item.get_id().get_fasta_id(),
+            item.get_len(),
+            hit.distance,
+            edit_distance,
+            identity,
+            strand
+        )?;
+    }
+    Ok(())
+} // end of dump_identity_hits
+
+
+/// A batch of queries, numbered in reading order, with the rank of its first query in the input file
+type QueryBatch = (usize, usize, Vec<(Vec<u8>, Vec<u8>)>);
+
+/// The answers of a query: its description, its neighbours, the alignments of top hits, the identities of hits
+/// and the containments in hits
+type QueryAnswer = (ItemDict, Vec<Neighbour>, Vec<AlignedHit>, Vec<IdentityHit>, Vec<ContainedHit>);
+
+/// The answers of a batch, with batch rank and rank of its first query
+type AnswerBatch = (usize, usize, Vec<QueryAnswer>);
+
+/// Sketch and search queries by batches of `batch_size`.
+/// A reader thread fills batches, workers sketch and search them with the rayon pool, and answers
+/// are written as soon as all preceding batches are written, so output keeps the input order.
+/// All channels are bounded and batches read but not yet written are at most `4 * nb_workers`,
+/// memory depends on the batch size and not on the number of queries.
+/// If `aligning` is given (number of top hits, minimap2 preset, output), queries are also aligned against
+/// their top hits and the alignments dumped. Hit sequences are read on demand from `refseqs`.
+/// If `identities` is given, edit distances and identities with all hits are dumped.
+/// If `containments` is given (k-mer size, number of candidates, output), candidates are reranked by containment.
+/// If `abundance` is given, the neighbours of each query are collected for abundance estimation.
+/// Returns the number of queries processed.
+fn search_streaming(
+    hnsw: &SketchIndex,
+    seqdict: &SeqDict,
+    sketcher: &OptDensHashSketch<Kmer32bit, f64>,
+    fasta_path: &str,
+    nb_answers: usize,
+    ef_search: usize,
+    batch_size: usize,
+    out_threshold: f32,
+    outfile: &mut BufWriter<File>,
+    refseqs: Option<&DatabaseSequences>,
+    aligning: Option<(usize, &str, &mut BufWriter<File>)>,
+    identities: Option<(EditMode, &mut BufWriter<File>)>,
+    containments: Option<(usize, usize, &mut BufWriter<File>)>,
+    mut abundance: Option<&mut AbundanceCollector>,
+) -> usize {
+    // workers need the parameters, the writer needs the outputs
+    let (align_top, mut align_writer) = match aligning {
+        Some((align_top, align_preset, align_writer)) => (Some((align_top, align_preset)), Some(align_writer)),
+        None => (None, None),
+    };
+    let (identity_mode, mut identity_writer) = match identities {
+        Some((mode, identity_writer)) => (Some(mode), Some(identity_writer)),
+        None => (None, None),
+    };
+    let (containment_params, mut containment_writer) = match containments {
+        Some((kmer_size, nb_candidates, containment_writer)) => {
+            (Some((kmer_size, nb_candidates)), Some(containment_writer))
+        }
+        None => (None, None),
+    };
+    let nb_searched = containment_params.map_or(nb_answers, |(_, nb_candidates)| nb_candidates);
+    // batches are searched with the whole rayon pool, 2 workers are enough to overlap sketching and searching
+    let nb_workers = 2;
+    let (tx_batch, rx_batch): (Sender<QueryBatch>, Receiver<QueryBatch>) = bounded(nb_workers);
+    let (tx_answer, rx_answer): (Sender<AnswerBatch>, Receiver<AnswerBatch>) = bounded(nb_workers);
+    // Permits bound the batches read but not yet written: the producer takes one by batch and the writer gives
+    // it back once the batch is written, so batches finished after a slow one cannot pile up in the reorder buffer
+    let reorder_window = 4 * nb_workers;
+    let (tx_permit, rx_permit): (Sender<()>, Receiver<()>) = bounded(reorder_window);
+
+    thread::scope(|scope| {
+        // Producer: read FASTA and cut it into batches
+        scope.spawn(move || {
+            let mut reader =
+                parse_fastx_file(&Path::new(fasta_path)).expect("Invalid path/file for FASTA");
+            let mut batch_rank = 0;
+            let mut first_query = 0;
+            let mut batch = Vec::with_capacity(batch_size);
+            while let Some(record) = reader.next() {
+                let seqrec = record.expect("Invalid record");
+                let seq_id = seqrec.id().to_owned();
+                let seq_seq = seqrec.normalize(true).into_owned();
+                batch.push((seq_id, seq_seq));
+                if batch.len() == batch_size {
+                    let nb_in_batch = batch.len();
+                    let full_batch = std::mem::replace(&mut batch, Vec::with_capacity(batch_size));
+                    tx_permit.send(()).expect("Could not take a permit");
+                    tx_batch.send((batch_rank, first_query, full_batch)).expect("Could not send batch");
+                    batch_rank += 1;
+                    first_query += nb_in_batch;
+                }
+            }
+            if !batch.is_empty() {
+                tx_permit.send(()).expect("Could not take a permit");
+                tx_batch.send((batch_rank, first_query, batch)).expect("Could not send batch");
+            }
+        });
+        // Workers: sketch a whole batch at once and search it
+        for _ in 0..nb_workers {
+            let rx_batch = rx_batch.clone();
+            let tx_answer = tx_answer.clone();
+            scope.spawn(move || {
+                for (batch_rank, first_query, batch) in rx_batch.iter() {
+                    let seqs: Vec<SequenceStruct> = batch
+                        .iter()
+                        .map(|(_, seq_seq)| ascii_to_seq(seq_seq))
+                        .collect();
+                    let vseq: Vec<&SequenceStruct> = seqs.iter().collect();
+                    let signatures = sketcher.sketch_compressedkmer(&vseq, kmer_hash_fn_32bit);
+                    let knn_neighbours = hnsw.parallel_search(&signatures, nb_searched, ef_search);
+                    let items: Vec<ItemDict> = batch
+                        .iter()
+                        .zip(seqs.iter())
+                        .map(|((seq_id, _), seq)| {
+                            let id = Id::new(fasta_path, &String::from_utf8(seq_id.clone()).unwrap());
+                            ItemDict::new(id, seq.size())
+                        })
+                        .collect();
+                    // containment hits are kept for output, their neighbours replace the HNSW ones
+                    let (knn_neighbours, contained): (Vec<Vec<Neighbour>>, Vec<Vec<ContainedHit>>) =
+                        match containment_params {
+                            Some((kmer_size, _)) => knn_neighbours
+                                .into_iter()
+                                .zip(items.iter())
+                                .map(|(neighbours, item)| {
+                                    let hits = rank_by_containment(item, neighbours, seqdict, kmer_size, nb_answers);
+                                    (hits.iter().map(|hit| hit.neighbour).collect(), hits)
+                                })
+                                .unzip(),
+                            None => {
+                                let nb_queries = knn_neighbours.len();
+                                (knn_neighbours, (0..nb_queries).map(|_| Vec::new()).collect())
+                            }
+                        };
+                    let aligned: Vec<Vec<AlignedHit>> = match align_top {
+                        Some((align_top, align_preset)) => batch
+                            .par_iter()
+                            .zip(knn_neighbours.par_iter())
+                            .map(|((_, query), neighbours)| {
+                                align_to_hits(query, neighbours, refseqs, align_top, align_preset)
+                            })
+                            .collect(),
+                        None => (0..batch.len()).map(|_| Vec::new()).collect(),
+                    };
+                    let identity_hits: Vec<Vec<IdentityHit>> = match identity_mode {
+                        Some(mode) => batch
+                            .par_iter()
+                            .zip(knn_neighbours.par_iter())
+                            .map(|((_, query), neighbours)| identities_of_hits(query, neighbours, refseqs, mode))
+                            .collect(),
+                        None => (0..batch.len()).map(|_| Vec::new()).collect(),
+                    };
+                    let answers = items
+                        .into_iter()
+                        .zip(knn_neighbours)
+                        .zip(aligned)
+                        .zip(identity_hits)
+                        .zip(contained)
+                        .map(|((((item, neighbours), hits), identity_hits), contained)| {
+                            (item, neighbours, hits, identity_hits, contained)
+                        })
+                        .collect();
+                    tx_answer
+                        .send((batch_rank, first_query, answers))
+                        .expect("Could not send answers");
+                }
+            });
+        }
+        drop(rx_batch);
+        drop(tx_answer);
+        // Writer (this thread): reorder batches and dump them in input order
+        let mut pending: BTreeMap<usize, (usize, Vec<QueryAnswer>)> = BTreeMap::new();
+        let mut next_batch = 0;
+        let mut nb_queries = 0;
+        for (batch_rank, first_query, answers) in rx_answer.iter() {
+            pending.insert(batch_rank, (first_query, answers));
+            while let Some((first_query, answers)) = pending.remove(&next_batch) {
+                for (i, (item, neighbours, hits, identity_hits, contained)) in answers.into_iter().enumerate() {
+                    if let Some(containment_writer) = containment_writer.as_mut() {
+                        if dump_containment_hits(first_query + i, &item, &contained, seqdict, containment_writer).is_err() {
+                            log::info!("could not dump containments for request id {}", item.get_id().get_fasta_id());
+                        }
+                    }
+                    if let Some(align_writer) = align_writer.as_mut() {
+                        if dump_aligned_hits(first_query + i, &item, &hits, seqdict, align_writer).is_err() {
+                            log::info!("could not dump alignments for request id {}", item.get_id().get_fasta_id());
+                        }
+                    }
+                    if let Some(identity_writer) = identity_writer.as_mut() {
+                        if dump_identity_hits(first_query + i, &item, &identity_hits, seqdict, identity_writer).is_err() {
+                            log::info!("could not dump identities for request id {}", item.get_id().get_fasta_id());
+                        }
+                    }
+                    if let Some(abundance) = abundance.as_mut() {
+                        abundance.add_read(&neighbours);
+                    }
+                    let answer = ReqAnswer::new(first_query + i, item, &neighbours);
+                    if answer.dump(seqdict, out_threshold, outfile).is_err() {
+                        log::info!(
+                            "could not dump answer for request id {}",
+                            answer.get_request_id().get_id().get_fasta_id()
+                        );
+                    }
+                    nb_queries += 1;
+                }
+                rx_permit.recv().expect("Could not give back a permit");
+                next_batch += 1;
+            }
+            let _ = outfile.flush();
+            if let Some(align_writer) = align_writer.as_mut() {
+                let _ = align_writer.flush();
+            }
+            if let Some(identity_writer) = identity_writer.as_mut() {
+                let _ = identity_writer.flush();
+            }
+            if let Some(containment_writer) = containment_writer.as_mut() {
+                let _ = containment_writer.flush();
+            }
+            log::info!("{} queries searched and written", nb_queries);
+        }
+        nb_queries
+    })
+} // end of search_streaming
@@ -0,0 +1,351 @@
+use clap::{Arg, ArgAction, Command};
+use crossbeam::channel::{bounded, Receiver, Sender};
+use needletail::sequence::normalize;
+use serde::{Deserialize, Serialize};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use gsearch::utils::parameters::*;
+use gsearch::utils::reloadhnsw;
+use gsearch::utils::SeqDict;
+use kmerutils::base::Kmer32bit;
+use kmerutils::sketcharg::SeqSketcherParams;
+use kmerutils::sketching::setsketchert::*;
+use kmerutils::base::sequence::Sequence as SequenceStruct;
+use log::info;
+
+use adas::httpserve::{serve_connection, Connection, Routes};
+use adas::sketchcode::{reload_sketch_code, SketchIndex};
+use adas::sketching::{ascii_to_seq, kmer_hash_fn_32bit};
+
+/// read and write timeout of connections, so that idle clients do not hold workers
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// One query sequence of a search request
+#[derive(Deserialize)]
+struct QueryRecord {
+    id: String,
+    seq: String,
+}
+
+/// Body of a POST /search request
+#[derive(Deserialize)]
+struct SearchRequest {
+    queries: Vec<QueryRecord>,
+    /// number of answers by query, defaults to the server value
+    #[serde(default)]
+    nb_answers: Option<usize>,
+}
+
+/// One neighbour of a query, as found in the database
+#[derive(Serialize)]
+struct Hit {
+    path: String,
+    fasta_id: String,
+    len: usize,
+    distance: f32,
+}
+
+#[derive(Serialize)]
+struct QueryAnswer {
+    id: String,
+    len: usize,
+    hits: Vec<Hit>,
+}
+
+#[derive(Serialize)]
+struct SearchResponse {
+    results: Vec<QueryAnswer>,
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    status: &'static str,
+    nb_points: usize,
+    kmer_size: usize,
+    sketch_size: usize,
+}
+
+/// What is shared by all workers: the database and the sketching setup reloaded once at startup
+struct SearchServer<'a> {
+    hnsw: &'a SketchIndex<'a>,
+    seqdict: &'a SeqDict,
+    sketch_params: SeqSketcherParams,
+    nb_answers: usize,
+    ef_search: usize,
+}
+
+impl<'a> SearchServer<'a> {
+    fn health(&self) -> HealthResponse {
+        HealthResponse {
+            status: "ok",
+            nb_points: self.hnsw.get_nb_point(),
+            kmer_size: self.sketch_params.get_kmer_size(),
+            sketch_size: self.sketch_params.get_sketch_size(),
+        }
+    }
+
+    /// sketch all queries of the request in one batch and search them in the graph
+    fn search(
+        &self,
+        sketcher: &OptDensHashSketch<Kmer32bit, f64>,
+        request: SearchRequest,
+    ) -> Result<SearchResponse, String> {
+        let nb_answers = request.nb_answers.unwrap_or(self.nb_answers);
+        if nb_answers == 0 {
+            return Err(String::from("nb_answers must be > 0"));
+        }
+        let mut ids = Vec::with_capacity(request.queries.len());
+        let mut seqs = Vec::with_capacity(request.queries.len());
+        for query in request.queries {
+            let bases = query.seq.as_bytes();
+            let bases = normalize(bases, false).unwrap_or_else(|| bases.to_vec());
+            if bases.len() < self.sketch_params.get_kmer_size() {
+                return Err(format!("query {} is shorter than kmer size", query.id));
+            }
+            seqs.push(ascii_to_seq(&bases));
+            ids.push(query.id);
+        }
+        if seqs.is_empty() {
+            return Ok(SearchResponse { results: Vec::new() });
+        }
+        let vseq: Vec<&SequenceStruct> = seqs.iter().collect();
+        let signatures = sketcher.sketch_compressedkmer(&vseq, kmer_hash_fn_32bit);
+        let knn_neighbours = self.hnsw.parallel_search(&signatures, nb_answers, self.ef_search);
+        //
+        let results = knn_neighbours
+            .iter()
+            .zip(ids)
+            .zip(seqs.iter())
+            .map(|((neighbours, id), seq)| {
+                let hits = neighbours
+                    .iter()
+                    .map(|n| {
+                        let item = &self.seqdict.0[n.d_id];
+                        Hit {
+                            path: item.get_id().get_path().clone(),
+                            fasta_id: item.get_id().get_fasta_id().clone(),
+                            len: item.get_len(),
+                            distance: n.distance,
+                        }
+                    })
+                    .collect();
+                QueryAnswer { id, len: seq.size(), hits }
+            })
+            .collect();
+        Ok(SearchResponse { results })
+    } // end of search
+} // end of impl SearchServer
+
+/// A worker answers requests with the shared server and its own sketcher
+struct Worker<'s> {
+    server: &'s SearchServer<'s>,
+    sketcher: OptDensHashSketch<Kmer32bit, f64>,
+}
+
+impl Routes for Worker<'_> {
+    fn health(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.server.health()).unwrap()
+    }
+
+    fn search(&self, body: &[u8]) -> Result<Vec<u8>, String> {
+        let request = serde_json::from_slice::<SearchRequest>(body).map_err(|e| format!("invalid json request: {}", e))?;
+        let answer = self.server.search(&self.sketcher, request)?;
+        Ok(serde_json::to_vec(&answer).unwrap())
+    }
+}
+
+fn main() {
+    // Initialize logger
+    println!("\n ************** initializing logger *****************\n");
+    let _ = env_logger::Builder::from_default_env().init();
+
+    let matches = Command::new("adas-serve")
+        .version("0.1.1")
+        .about("Serve searches against a Pre-built Hierarchical Navigable Small World Graphs (HNSW) Index over local HTTP")
+        .arg(
+            Arg::new("database_path")
+                .short('b')
+                .long("hnsw")
+                .value_name("DATADIR")
+                .help("directory contains pre-built HNSW database files")
+                .required(true)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("bind")
+                .long("bind")
+                .value_name("ADDRESS")
+                .help("TCP address to listen on")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .default_value("127.0.0.1:7878"),
+        )
+        .arg(
+            Arg::new("socket")
+                .long("socket")
+                .value_name("SOCKET_PATH")
+                .help("Listen on a Unix socket at this path instead of TCP")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("nb_answers")
+                .short('n')
+                .long("nbng")
+                .value_name("NB_SEARCH_ANSWERS")
+                .help("Default number of search answers by query")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("128"),
+        )
+        .arg(
+            Arg::new("ef_search")
+                .long("ef")
+                .value_name("EF_SEARCH")
+                .help("HNSW ef parameter used for searching")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("5000"),
+        )
+        .arg(
+            Arg::new("workers")
+                .short('w')
+                .long("workers")
+                .value_name("WORKERS")
+                .help("Number of requests served concurrently")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("4"),
+        )
+        .arg(
+            Arg::new("threads")
+                .short('t')
+                .long("threads")
+                .value_name("THREADS")
+                .help("Number of threads for sketching and searching")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1"),
+        )
+        .get_matches();
+
+    let db_path = matches.get_one::<String>("database_path").unwrap().to_string();
+    let bind_address = matches.get_one::<String>("bind").unwrap().to_string();
+    let socket_path = matches.get_one::<String>("socket").cloned();
+    let nb_answers = *matches.get_one::<usize>("nb_answers").unwrap();
+    let ef_search = *matches.get_one::<usize>("ef_search").unwrap();
+    let nb_workers = (*matches.get_one::<usize>("workers").unwrap()).max(1);
+    let num_threads = *matches.get_one::<usize>("threads").unwrap();
+    let num_cpus = num_cpus::get();
+    let num_threads = if num_threads > num_cpus {
+        num_cpus
+    } else {
+        num_threads
+    };
+    println!("Using {} threads and {} workers", num_threads, nb_workers);
+
+    let database_dirpath = Path::new(&db_path);
+    let hnswio_res = reloadhnsw::get_hnswio(database_dirpath);
+    if let Err(e) = hnswio_res {
+        panic!("error : {:?}", e);
+    }
+    let mut hnswio = hnswio_res.unwrap();
+
+    let hnsw_path = std::path::PathBuf::from(database_dirpath);
+    let reload_res = ProcessingParams::reload_json(&hnsw_path);
+    let processing_params = if let Ok(params) = reload_res {
+        log::info!("Sketching parameters: {:?}", params.get_sketching_params());
+        params
+    } else {
+        panic!(
+            "Cannot reload parameters (file parameters.json) from dir: {:?}",
+            &hnsw_path
+        );
+    };
+    let sketch_params = processing_params.get_sketching_params();
+
+    info!("Calling sketch_compressedkmer for OptDensHashSketch::<Kmer32bit, f64>");
+    let sketcher = OptDensHashSketch::<Kmer32bit, f64>::new(&sketch_params);
+
+    println!("Loading HNSW index...");
+    // signatures of a database with a compact sketch code are integer codes, queries are encoded on the fly
+    let sketch_code = reload_sketch_code(database_dirpath)
+        .unwrap_or_else(|e| panic!("Cannot read sketch code of the database: {:?}", e));
+    let hnsw_res = SketchIndex::load(&mut hnswio, sketch_code);
+    if let Err(e) = hnsw_res {
+        panic!("error : {:?}", e);
+    }
+    let hnsw = hnsw_res.unwrap();
+
+    let seqname = database_dirpath.join("seqdict.json");
+    log::info!("\n reloading sequence dictionary from {}", &seqname.display());
+    let seqdict = match SeqDict::reload_json(&seqname) {
+        Ok(seqdict) => seqdict,
+        _ => {
+            panic!(
+                "SeqDict reload from dump file  {} failed",
+                seqname.display()
+            );
+        }
+    };
+    println!("HNSW index loaded...");
+
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build_global()
+        .unwrap();
+
+    let server = SearchServer {
+        hnsw: &hnsw,
+        seqdict: &seqdict,
+        sketch_params,
+        nb_answers,
+        ef_search,
+    };
+
+    // accepted connections are dispatched to workers, the channel bounds the pending queue
+    let (tx, rx): (Sender<Box<dyn Connection>>, Receiver<Box<dyn Connection>>) =
+        bounded(4 * nb_workers);
+
+    thread::scope(|scope| {
+        for _ in 0..nb_workers {
+            let rx_clone = rx.clone();
+            let worker = Worker {
+                server: &server,
+                sketcher: sketcher.clone(),
+            };
+            scope.spawn(move || {
+                for conn in rx_clone.iter() {
+                    serve_connection(&worker, conn, IO_TIMEOUT);
+                }
+            });
+        }
+        //
+        if let Some(socket_path) = socket_path {
+            let listener = UnixListener::bind(&socket_path)
+                .unwrap_or_else(|e| panic!("cannot bind unix socket {} : {:?}", socket_path, e));
+            println!("Listening on unix socket {}", socket_path);
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => tx.send(Box::new(stream)).expect("Could not dispatch connection"),
+                    Err(e) => log::error!("connection failed: {:?}", e),
+                }
+            }
+        } else {
+            let listener = TcpListener::bind(&bind_address)
+                .unwrap_or_else(|e| panic!("cannot bind {} : {:?}", bind_address, e));
+            println!("Listening on http://{}", bind_address);
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => tx.send(Box::new(stream)).expect("Could not dispatch connection"),
+                    Err(e) => log::error!("connection failed: {:?}", e),
+                }
+            }
+        }
+        drop(tx);
+    });
+}
@@ -0,0 +1,543 @@
+use clap::{Arg, ArgAction, Command};
+use needletail::{parse_fastx_file, Sequence};
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use hnsw_rs::prelude::*;
+use kmerutils::base::sequence::Sequence as SequenceStruct;
+use kmerutils::base::Kmer32bit;
+use kmerutils::sketcharg::{DataType, SeqSketcherParams, SketchAlgo};
+use kmerutils::sketching::setsketchert::*;
+
+use adas::buildconfig::BuildConfig;
+use adas::reproducible::seeded_order;
+use adas::sketching::{ascii_to_seq, kmer_hash_fn_32bit};
+
+/// approximate size of a link in the HNSW: a pointer to the neighbour and its distance
+const LINK_BYTES: usize = 16;
+
+/// bytes allocated and not yet freed, and their peak since the last [reset_peak]
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// System allocator counting allocated bytes, to measure the memory taken by trial indexes
+struct CountingAllocator;
+
+fn add_allocated(size: usize) {
+    let allocated = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    if allocated > PEAK.load(Ordering::Relaxed) {
+        PEAK.fetch_max(allocated, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            add_allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            add_allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            if new_size > layout.size() {
+                add_allocated(new_size - layout.size());
+            } else {
+                ALLOCATED.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+            }
+        }
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// bytes currently allocated, and sets their peak to them
+fn reset_peak() -> usize {
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(allocated, Ordering::Relaxed);
+    allocated
+}
+
+/// Result of a trial index
+struct Trial {
+    config: BuildConfig,
+    /// fraction of the exact k nearest neighbours found, ties with the k-th neighbour counted as found
+    recall: f64,
+    build_secs: f64,
+    search_secs: f64,
+    /// estimated memory of the index by sequence: signature and links
+    estimated_bytes_by_seq: f64,
+    /// measured memory of the index by sequence: bytes allocated by the build and kept by the index
+    bytes_by_seq: f64,
+    /// measured peak memory of the build by sequence, above the memory in use before it
+    peak_bytes_by_seq: f64,
+}
+
+/// sequences of the records of `fasta_path` whose ranks are in `ranks` (sorted)
+fn read_records(fasta_path: &str, ranks: &[usize]) -> Vec<Vec<u8>> {
+    let mut reader = parse_fastx_file(Path::new(fasta_path)).expect("Invalid path/file for FASTA");
+    let mut seqs = Vec::with_capacity(ranks.len());
+    let mut next = ranks.iter().peekable();
+    let mut rank = 0;
+    while let Some(record) = reader.next() {
+        let seqrec = record.expect("Invalid record");
+        if next.peek() == Some(&&rank) {
+            seqs.push(seqrec.normalize(false).into_owned());
+            next.next();
+            if next.peek().is_none() {
+                break;
+            }
+        }
+        rank += 1;
+    }
+    seqs
+}
+
+fn count_records(fasta_path: &str) -> usize {
+    let mut reader = parse_fastx_file(Path::new(fasta_path)).expect("Invalid path/file for FASTA");
+    let mut nb = 0;
+    while let Some(record) = reader.next() {
+        record.expect("Invalid record");
+        nb += 1;
+    }
+    nb
+}
+
+/// for each query, distance of its k-th nearest base signature, by brute force. k must be positive and base not empty
+fn exact_kth_distances(base: &[Vec<f64>], queries: &[Vec<f64>], k: usize) -> Vec<f32> {
+    queries
+        .par_iter()
+        .map(|query| {
+            let mut dists: Vec<f32> = base.iter().map(|sig| DistHamming.eval(query, sig)).collect();
+            let kth = k.min(dists.len()) - 1;
+            let (_, kth_dist, _) = dists.select_nth_unstable_by(kth, |a, b| a.partial_cmp(b).unwrap());
+            *kth_dist
+        })
+        .collect()
+}
+
+/// builds an index of `base` with `config` and searches `queries` with it
+fn run_trial(
+    config: BuildConfig,
+    base: &[Vec<f64>],
+    queries: &[Vec<f64>],
+    kth_distances: &[f32],
+    knbn: usize,
+    ef_search: usize,
+) -> Trial {
+    let allocated_before = reset_peak();
+    let start = Instant::now();
+    let mut hnsw = Hnsw::<f64, DistHamming>::new(
+        config.max_nb_connection as usize,
+        base.len(),
+        16,
+        config.hnsw_ef,
+        DistHamming {},
+    );
+    hnsw.modify_level_scale(config.scale_modify);
+    hnsw.set_extend_candidates(true);
+    hnsw.set_keeping_pruned(false);
+    let data: Vec<(&Vec<f64>, usize)> = base.iter().enumerate().map(|(idx, sig)| (sig, idx)).collect();
+    hnsw.parallel_insert(&data);
+    let build_secs = start.elapsed().as_secs_f64();
+    drop(data);
+    let nb_seqs = base.len() as f64;
+    let bytes_by_seq = ALLOCATED.load(Ordering::Relaxed).saturating_sub(allocated_before) as f64 / nb_seqs;
+    let peak_bytes_by_seq = PEAK.load(Ordering::Relaxed).saturating_sub(allocated_before) as f64 / nb_seqs;
+
+    let start = Instant::now();
+    let answers = hnsw.parallel_search(queries, knbn, ef_search);
+    let search_secs = start.elapsed().as_secs_f64();
+    let nb_found: usize = answers
+        .iter()
+        .zip(kth_distances.iter())
+        .map(|(neighbours, kth)| neighbours.iter().filter(|n| n.distance <= *kth).count().min(knbn))
+        .sum();
+    let recall = nb_found as f64 / (queries.len() * knbn.min(base.len())) as f64;
+
+    let nb_links: usize = hnsw
+        .get_point_indexation()
+        .into_iter()
+        .map(|point| point.get_neighborhood_id().iter().map(|layer| layer.len()).sum::<usize>())
+        .sum();
+    let estimated_bytes_by_seq =
+        (config.sketch_size * std::mem::size_of::<f64>()) as f64 + (nb_links * LINK_BYTES) as f64 / nb_seqs;
+    Trial {
+        config,
+        recall,
+        build_secs,
+        search_secs,
+        estimated_bytes_by_seq,
+        bytes_by_seq,
+        peak_bytes_by_seq,
+    }
+} // end of run_trial
+
+fn main() {
+    // Initialize logger
+    println!("\n ************** initializing logger *****************\n");
+    let _ = env_logger::Builder::from_default_env().init();
+
+    let matches = Command::new("adas-tune")
+        .version("0.1.1")
+        .about("Tuning of adas-build parameters on a subsample: recall against exact sketch distances, build time and memory")
+        .arg(
+            Arg::new("input")
+                .short('i')
+                .long("input")
+                .value_name("FASTA_FILE")
+                .help("Input FASTA file, as given to adas-build")
+                .required(true)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("sample")
+                .long("sample")
+                .value_name("NB_SEQS")
+                .help("Number of sequences sampled from the input for trial indexes")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("10000"),
+        )
+        .arg(
+            Arg::new("queries")
+                .long("queries")
+                .value_name("NB_QUERIES")
+                .help("Number of sampled sequences kept out of trial indexes and used as queries")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("200"),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("Seed of the subsampling")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u64))
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("kmer_sizes")
+                .short('k')
+                .long("kmer-sizes")
+                .value_name("KMER_SIZES")
+                .help("K-mer sizes tried, comma separated, each must be ≤14")
+                .action(ArgAction::Set)
+                .value_delimiter(',')
+                .value_parser(clap::value_parser!(usize))
+                .default_values(["8", "12"]),
+        )
+        .arg(
+            Arg::new("sketch_sizes")
+                .short('s')
+                .long("sketch-sizes")
+                .value_name("SKETCH_SIZES")
+                .help("Sketch sizes tried, comma separated")
+                .action(ArgAction::Set)
+                .value_delimiter(',')
+                .value_parser(clap::value_parser!(usize))
+                .default_values(["256", "512"]),
+        )
+        .arg(
+            Arg::new("max_nb_connections")
+                .long("max-nb-connections")
+                .value_name("MAX_NB_CONNS")
+                .help("HNSW max_nb_conn values tried, comma separated")
+                .action(ArgAction::Set)
+                .value_delimiter(',')
+                .value_parser(clap::value_parser!(u8))
+                .default_values(["32", "64", "128"]),
+        )
+        .arg(
+            Arg::new("hnsw_efs")
+                .long("hnsw-efs")
+                .value_name("HNSW_EFS")
+                .help("HNSW ef values tried for construction, comma separated")
+                .action(ArgAction::Set)
+                .value_delimiter(',')
+                .value_parser(clap::value_parser!(usize))
+                .default_values(["400", "1600"]),
+        )
+        .arg(
+            Arg::new("scale_modifications")
+                .long("scale-modify-fs")
+                .value_name("SCALE_MODIFYS")
+                .help("Scale modification factors tried, comma separated, each in [0.2,1]")
+                .action(ArgAction::Set)
+                .value_delimiter(',')
+                .value_parser(clap::value_parser!(f64))
+                .default_values(["1.0"]),
+        )
+        .arg(
+            Arg::new("nbng")
+                .short('n')
+                .long("nbng")
+                .value_name("NB_SEARCH_ANSWERS")
+                .help("Number of neighbours for recall")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("10"),
+        )
+        .arg(
+            Arg::new("ef_search")
+                .long("ef-search")
+                .value_name("EF_SEARCH")
+                .help("ef of searches in trial indexes, small compared to the sample size so that recall is not trivially 1")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("128"),
+        )
+        .arg(
+            Arg::new("target_recall")
+                .long("target-recall")
+                .value_name("RECALL")
+                .help("Recall the recommended configuration must reach, the fastest to build is recommended")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(f64))
+                .default_value("0.95"),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_name("OUTPUT_PATH")
+                .help("Report of all trials")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .default_value("adas.tune.txt"),
+        )
+        .arg(
+            Arg::new("write_config")
+                .long("write-config")
+                .value_name("BUILD_CONFIG")
+                .help("Write the recommended configuration as a JSON build config for adas-build --config")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("threads")
+                .short('t')
+                .long("threads")
+                .value_name("THREADS")
+                .help("Number of threads for sketching, index building and searching")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1"),
+        )
+        .get_matches();
+
+    let fasta_path = matches.get_one::<String>("input").unwrap().to_string();
+    let nb_sample = *matches.get_one::<usize>("sample").unwrap();
+    let nb_queries = *matches.get_one::<usize>("queries").unwrap();
+    let seed = *matches.get_one::<u64>("seed").unwrap();
+    let kmer_sizes: Vec<usize> = matches.get_many::<usize>("kmer_sizes").unwrap().copied().collect();
+    let sketch_sizes: Vec<usize> = matches.get_many::<usize>("sketch_sizes").unwrap().copied().collect();
+    let max_nb_connections: Vec<u8> = matches.get_many::<u8>("max_nb_connections").unwrap().copied().collect();
+    let hnsw_efs: Vec<usize> = matches.get_many::<usize>("hnsw_efs").unwrap().copied().collect();
+    let scale_modifications: Vec<f64> = matches.get_many::<f64>("scale_modifications").unwrap().copied().collect();
+    let knbn = *matches.get_one::<usize>("nbng").unwrap();
+    let ef_search = *matches.get_one::<usize>("ef_search").unwrap();
+    let target_recall = *matches.get_one::<f64>("target_recall").unwrap();
+    let output_path = matches.get_one::<String>("output").unwrap().to_string();
+    let config_path = matches.get_one::<String>("write_config").cloned();
+    let num_threads = *matches.get_one::<usize>("threads").unwrap();
+    let num_cpus = num_cpus::get();
+    let num_threads = if num_threads > num_cpus {
+        num_cpus
+    } else {
+        num_threads
+    };
+    println!("Using {} threads", num_threads);
+    if knbn == 0 {
+        panic!("the number of neighbours for recall (--nbng) must be positive");
+    }
+    if kmer_sizes.is_empty()
+        || sketch_sizes.is_empty()
+        || max_nb_connections.is_empty()
+        || hnsw_efs.is_empty()
+        || scale_modifications.is_empty()
+    {
+        panic!("each parameter grid needs at least one value");
+    }
+    if kmer_sizes.contains(&0) || sketch_sizes.contains(&0) || max_nb_connections.contains(&0) || hnsw_efs.contains(&0) {
+        panic!("kmer sizes, sketch sizes, max_nb_connections and hnsw efs must be positive");
+    }
+    if kmer_sizes.iter().any(|k| *k > 14) {
+        panic!("kmer sizes must be ≤14");
+    }
+    if scale_modifications.iter().any(|f| !(0.2..=1.).contains(f)) {
+        panic!("scale modification factors must be in [0.2,1]");
+    }
+
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build_global()
+        .unwrap();
+
+    // Subsample: a seeded random subset of the records, the first ones of the subset are queries
+    let nb_records = count_records(&fasta_path);
+    let nb_sample = nb_sample.min(nb_records);
+    if nb_queries == 0 || nb_queries >= nb_sample {
+        panic!("{} queries asked for a sample of {} sequences", nb_queries, nb_sample);
+    }
+    let mut ranks: Vec<usize> = seeded_order(nb_records, seed, 0)[..nb_sample].to_vec();
+    let mut query_ranks = ranks[..nb_queries].to_vec();
+    query_ranks.sort_unstable();
+    ranks.sort_unstable();
+    let sample = read_records(&fasta_path, &ranks);
+    let is_query: Vec<bool> = ranks.iter().map(|rank| query_ranks.binary_search(rank).is_ok()).collect();
+    let seqs: Vec<SequenceStruct> = sample.par_iter().map(|bases| ascii_to_seq(bases)).collect();
+    drop(sample);
+    println!(
+        "Sample of {} sequences out of {}: {} in trial indexes, {} queries",
+        nb_sample,
+        nb_records,
+        nb_sample - nb_queries,
+        nb_queries
+    );
+    if ef_search >= nb_sample - nb_queries {
+        log::warn!("ef-search {} is not smaller than the trial indexes, searches are exhaustive", ef_search);
+    }
+
+    let nb_trials =
+        kmer_sizes.len() * sketch_sizes.len() * max_nb_connections.len() * hnsw_efs.len() * scale_modifications.len();
+    println!("{} trial indexes", nb_trials);
+    let mut trials: Vec<Trial> = Vec::with_capacity(nb_trials);
+    for &kmer_size in kmer_sizes.iter() {
+        for &sketch_size in sketch_sizes.iter() {
+            let sketch_args = SeqSketcherParams::new(kmer_size, sketch_size, SketchAlgo::OPTDENS, DataType::DNA);
+            let sketcher = OptDensHashSketch::<Kmer32bit, f64>::new(&sketch_args);
+            let vseq: Vec<&SequenceStruct> = seqs.iter().collect();
+            let signatures = sketcher.sketch_compressedkmer(&vseq, kmer_hash_fn_32bit);
+            let (queries, base): (Vec<_>, Vec<_>) = signatures
+                .into_iter()
+                .zip(is_query.iter())
+                .partition(|(_, is_query)| **is_query);
+            let queries: Vec<Vec<f64>> = queries.into_iter().map(|(sig, _)| sig).collect();
+            let base: Vec<Vec<f64>> = base.into_iter().map(|(sig, _)| sig).collect();
+            // exact neighbours, for all HNSW parameters with these sketches
+            let kth_distances = exact_kth_distances(&base, &queries, knbn);
+            for &max_nb_connection in max_nb_connections.iter() {
+                for &hnsw_ef in hnsw_efs.iter() {
+                    for &scale_modify in scale_modifications.iter() {
+                        let config = BuildConfig {
+                            kmer_size,
+                            sketch_size,
+                            max_nb_connection,
+                            hnsw_ef,
+                            scale_modify,
+                        };
+                        let trial = run_trial(config, &base, &queries, &kth_distances, knbn, ef_search);
+                        println!(
+                            "k {} s {} max_nb_conn {} ef {} scale {} : recall {:.4} build {:.2} s search {:.3} s {:.0} bytes/seq (estimated {:.0}, build peak {:.0})",
+                            kmer_size,
+                            sketch_size,
+                            max_nb_connection,
+                            hnsw_ef,
+                            scale_modify,
+                            trial.recall,
+                            trial.build_secs,
+                            trial.search_secs,
+                            trial.bytes_by_seq,
+                            trial.estimated_bytes_by_seq,
+                            trial.peak_bytes_by_seq
+                        );
+                        trials.push(trial);
+                    }
+                }
+            }
+        }
+    }
+
+    // Recommendation: the fastest build reaching the target recall, else the best recall
+    let reaching: Vec<&Trial> = trials.iter().filter(|trial| trial.recall >= target_recall).collect();
+    let recommended = if reaching.is_empty() {
+        log::warn!("no configuration reaches recall {}, recommending the best recall", target_recall);
+        trials.iter().max_by(|a, b| a.recall.partial_cmp(&b.recall).unwrap())
+    } else {
+        reaching
+            .into_iter()
+            .min_by(|a, b| a.build_secs.partial_cmp(&b.build_secs).unwrap())
+    };
+
+    let file = File::create(&output_path).expect("Cannot create output file");
+    let mut writer = BufWriter::new(file);
+    writeln!(
+        writer,
+        "# sample {} sequences of {}, {} queries, recall@{} with ef_search {}, target recall {}",
+        nb_sample, fasta_path, nb_queries, knbn, ef_search, target_recall
+    )
+    .unwrap();
+    writeln!(
+        writer,
+        "kmer_size\tsketch_size\tmax_nb_connection\thnsw_ef\tscale_modify\trecall\tbuild_secs\tsearch_secs\tbytes_by_seq\testimated_bytes_by_seq\tpeak_bytes_by_seq\trecommended"
+    )
+    .unwrap();
+    for trial in trials.iter() {
+        let is_recommended = recommended.is_some_and(|best| std::ptr::eq(best, trial));
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.3}\t{:.4}\t{:.0}\t{:.0}\t{:.0}\t{}",
+            trial.config.kmer_size,
+            trial.config.sketch_size,
+            trial.config.max_nb_connection,
+            trial.config.hnsw_ef,
+            trial.config.scale_modify,
+            trial.recall,
+            trial.build_secs,
+            trial.search_secs,
+            trial.bytes_by_seq,
+            trial.estimated_bytes_by_seq,
+            trial.peak_bytes_by_seq,
+            if is_recommended { "*" } else { "" }
+        )
+        .unwrap();
+    }
+    writer.flush().unwrap();
+    println!("Trials written to {}", output_path);
+
+    if let Some(best) = recommended {
+        let config = &best.config;
+        println!(
+            "\nRecommended (recall {:.4}, build {:.2} s for {} sequences, about {:.1} MB by million sequences):",
+            best.recall,
+            best.build_secs,
+            nb_sample - nb_queries,
+            best.bytes_by_seq
+        );
+        println!(
+            "adas-build -i {} -k {} -s {} --max_nb_connection {} --hnsw-ef {} --scale_modify_f {}",
+            fasta_path, config.kmer_size, config.sketch_size, config.max_nb_connection, config.hnsw_ef, config.scale_modify
+        );
+        if let Some(config_path) = config_path {
+            config
+                .save_json(Path::new(&config_path))
+                .unwrap_or_else(|e| panic!("Cannot write build config {} : {:?}", config_path, e));
+            println!("Build config written to {}, use adas-build --config {}", config_path, config_path);
+        }
+    }
+}
@@ -0,0 +1,28 @@
+//! Build configuration: sketching and HNSW parameters of adas-build, as written by adas-tune.
+//!
+//! The configuration is a JSON file read by `adas-build --config`, options given on the command line
+//! take precedence over it.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BuildConfig {
+    pub kmer_size: usize,
+    pub sketch_size: usize,
+    pub max_nb_connection: u8,
+    pub hnsw_ef: usize,
+    pub scale_modify: f64,
+}
+
+impl BuildConfig {
+    pub fn save_json(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn reload_json(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
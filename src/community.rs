@@ -0,0 +1,296 @@
+//! Community detection on the kNN graph of a database with the Louvain method.
+//!
+//! The directed kNN lists are first made undirected ([symmetrize]), distances becoming similarity weights.
+//! Louvain then alternates local moving of nodes to the neighbouring community of best modularity gain
+//! and aggregation of communities into nodes, until modularity stops increasing.
+
+use std::collections::HashMap;
+
+/// Undirected weighted graph as symmetric adjacency lists: an edge {i,j} appears in the lists of i and j,
+/// a self loop once in the list of its node with weight A_ii.
+pub type Adjacency = Vec<Vec<(usize, f64)>>;
+
+/// Makes kNN lists (neighbour, distance) undirected with weight 1 - distance.
+/// With `mutual` an edge is kept only if each node is in the list of the other, else if one is in the list of the other.
+/// The weight of an edge found in both lists is the largest one. Edges with distance above `max_distance` are dropped.
+pub fn symmetrize(knn: &[Vec<(usize, f64)>], mutual: bool, max_distance: f64) -> Adjacency {
+    let mut pairs: HashMap<(usize, usize), (f64, u8)> = HashMap::new();
+    for (i, neighbours) in knn.iter().enumerate() {
+        for &(j, distance) in neighbours {
+            if i == j || distance > max_distance {
+                continue;
+            }
+            let weight = (1. - distance).max(f64::MIN_POSITIVE);
+            let entry = pairs.entry((i.min(j), i.max(j))).or_insert((0., 0));
+            entry.0 = entry.0.max(weight);
+            // bit 1 if found from the smaller node, bit 2 from the larger
+            entry.1 |= if i < j { 1 } else { 2 };
+        }
+    }
+    let mut adjacency: Adjacency = vec![Vec::new(); knn.len()];
+    let mut edges: Vec<((usize, usize), (f64, u8))> = pairs.into_iter().collect();
+    edges.sort_unstable_by_key(|(pair, _)| *pair);
+    for ((i, j), (weight, found)) in edges {
+        if mutual && found != 3 {
+            continue;
+        }
+        adjacency[i].push((j, weight));
+        adjacency[j].push((i, weight));
+    }
+    adjacency
+} // end of symmetrize
+
+/// Result of community detection
+pub struct Communities {
+    /// community of each node, communities numbered by decreasing size
+    pub labels: Vec<usize>,
+    /// size of each community
+    pub sizes: Vec<usize>,
+    /// modularity of the partition on the input graph
+    pub modularity: f64,
+    /// number of aggregation levels done
+    pub nb_levels: usize,
+}
+
+/// modularity of a partition, with resolution `gamma`: sum over communities of in_c / 2m - gamma (tot_c / 2m)^2
+pub fn modularity(graph: &Adjacency, labels: &[usize], gamma: f64) -> f64 {
+    let nb_communities = labels.iter().max().map_or(0, |max| max + 1);
+    let mut inner = vec![0f64; nb_communities];
+    let mut total = vec![0f64; nb_communities];
+    let mut m2 = 0.;
+    for (i, neighbours) in graph.iter().enumerate() {
+        for &(j, weight) in neighbours {
+            total[labels[i]] += weight;
+            m2 += weight;
+            if labels[i] == labels[j] {
+                inner[labels[i]] += weight;
+            }
+        }
+    }
+    if m2 <= 0. {
+        return 0.;
+    }
+    inner
+        .iter()
+        .zip(total.iter())
+        .map(|(inner, total)| inner / m2 - gamma * (total / m2) * (total / m2))
+        .sum()
+}
+
+/// bound on the number of passes over nodes in local moving, in case rounding makes nodes oscillate
+const MAX_PASSES: usize = 100;
+
+/// Passes of local moving until no node moves. Returns the community of each node and true if a node moved.
+fn local_moving(graph: &Adjacency, gamma: f64) -> (Vec<usize>, bool) {
+    let n = graph.len();
+    let degree: Vec<f64> = graph.iter().map(|neighbours| neighbours.iter().map(|(_, w)| w).sum()).collect();
+    let m2: f64 = degree.iter().sum();
+    let mut community: Vec<usize> = (0..n).collect();
+    let mut total = degree.clone();
+    if m2 <= 0. {
+        return (community, false);
+    }
+    let mut moved_once = false;
+    // weight from the current node to each neighbouring community, reset after each node
+    let mut weight_to: Vec<f64> = vec![0.; n];
+    let mut touched: Vec<usize> = Vec::new();
+    for _ in 0..MAX_PASSES {
+        let mut moved = false;
+        for i in 0..n {
+            let current = community[i];
+            for &(j, weight) in graph[i].iter() {
+                if j == i {
+                    continue;
+                }
+                if weight_to[community[j]] == 0. {
+                    touched.push(community[j]);
+                }
+                weight_to[community[j]] += weight;
+            }
+            total[current] -= degree[i];
+            // gain of joining c, up to a constant: weight_to[c] - gamma * total[c] * degree[i] / 2m
+            let gain = |c: usize| weight_to[c] - gamma * total[c] * degree[i] / m2;
+            let mut best = current;
+            let mut best_gain = gain(current);
+            for &c in touched.iter() {
+                let g = gain(c);
+                if g > best_gain + 1e-12 {
+                    best = c;
+                    best_gain = g;
+                }
+            }
+            total[best] += degree[i];
+            if best != current {
+                community[i] = best;
+                moved = true;
+                moved_once = true;
+            }
+            for &c in touched.iter() {
+                weight_to[c] = 0.;
+            }
+            touched.clear();
+        }
+        if !moved {
+            break;
+        }
+    }
+    (community, moved_once)
+} // end of local_moving
+
+/// renumbers labels 0..nb_communities in order of first appearance, returns the number of communities
+fn renumber(labels: &mut [usize]) -> usize {
+    let mut new_label: HashMap<usize, usize> = HashMap::new();
+    for label in labels.iter_mut() {
+        let next = new_label.len();
+        *label = *new_label.entry(*label).or_insert(next);
+    }
+    new_label.len()
+}
+
+/// graph of communities: A'_CD = sum of A_ij for i in C, j in D
+fn aggregate(graph: &Adjacency, labels: &[usize], nb_communities: usize) -> Adjacency {
+    let mut merged: Vec<HashMap<usize, f64>> = vec![HashMap::new(); nb_communities];
+    for (i, neighbours) in graph.iter().enumerate() {
+        for &(j, weight) in neighbours {
+            *merged[labels[i]].entry(labels[j]).or_insert(0.) += weight;
+        }
+    }
+    merged
+        .into_iter()
+        .map(|neighbours| {
+            let mut neighbours: Vec<(usize, f64)> = neighbours.into_iter().collect();
+            neighbours.sort_unstable_by_key(|(c, _)| *c);
+            neighbours
+        })
+        .collect()
+}
+
+/// Louvain community detection with resolution `gamma` (1 for standard modularity, larger gives smaller communities)
+pub fn louvain(graph: &Adjacency, gamma: f64) -> Communities {
+    let n = graph.len();
+    let mut labels: Vec<usize> = (0..n).collect();
+    let mut current = graph.clone();
+    let mut nb_levels = 0;
+    loop {
+        let (mut level_labels, moved) = local_moving(&current, gamma);
+        if !moved {
+            break;
+        }
+        nb_levels += 1;
+        let nb_communities = renumber(&mut level_labels);
+        for label in labels.iter_mut() {
+            *label = level_labels[*label];
+        }
+        current = aggregate(&current, &level_labels, nb_communities);
+    }
+    // communities by decreasing size, then by smallest node
+    let nb_communities = renumber(&mut labels);
+    let mut sizes = vec![0usize; nb_communities];
+    for label in labels.iter() {
+        sizes[*label] += 1;
+    }
+    let mut order: Vec<usize> = (0..nb_communities).collect();
+    order.sort_by(|a, b| sizes[*b].cmp(&sizes[*a]).then(a.cmp(b)));
+    let mut rank = vec![0usize; nb_communities];
+    for (r, c) in order.iter().enumerate() {
+        rank[*c] = r;
+    }
+    for label in labels.iter_mut() {
+        *label = rank[*label];
+    }
+    let sizes = order.iter().map(|c| sizes[*c]).collect();
+    Communities {
+        modularity: modularity(graph, &labels, gamma),
+        labels,
+        sizes,
+        nb_levels,
+    }
+} // end of louvain
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nb_edges(graph: &Adjacency) -> usize {
+        graph.iter().map(|neighbours| neighbours.len()).sum::<usize>() / 2
+    }
+
+    #[test]
+    fn mutual_and_union_edges() {
+        // 0 and 1 list each other, 0 lists 2 but 2 lists only 3, 3 lists itself
+        let knn = vec![
+            vec![(1, 0.2), (2, 0.4)],
+            vec![(0, 0.1)],
+            vec![(3, 0.9)],
+            vec![(3, 0.), (2, 0.3)],
+        ];
+        let union = symmetrize(&knn, false, 1.);
+        assert_eq!(nb_edges(&union), 3);
+        // the largest weight of the two directions
+        assert_eq!(union[0], vec![(1, 0.9), (2, 0.6)]);
+        assert_eq!(union[1], vec![(0, 0.9)]);
+        assert_eq!(union[2], vec![(0, 0.6), (3, 0.7)]);
+        assert_eq!(union[3], vec![(2, 0.7)]);
+        let mutual = symmetrize(&knn, true, 1.);
+        assert_eq!(nb_edges(&mutual), 2);
+        assert_eq!(mutual[0], vec![(1, 0.9)]);
+        assert_eq!(mutual[2], vec![(3, 0.7)]);
+        // the distance 0.9 from 2 to 3 is dropped, 3 to 2 remains in the union only
+        let close = symmetrize(&knn, true, 0.5);
+        assert_eq!(nb_edges(&close), 1);
+        assert_eq!(symmetrize(&knn, false, 0.5)[3], vec![(2, 0.7)]);
+    }
+
+    #[test]
+    fn modularity_by_hand() {
+        // two triangles 0-1-2 and 3-4-5 joined by the edge 2-3, unit weights:
+        // 2m = 14, each triangle has in = 6 and tot = 7, Q = 2 * (6/14 - (7/14)^2) = 5/14
+        let edges = [(0, 1), (0, 2), (1, 2), (3, 4), (3, 5), (4, 5), (2, 3)];
+        let mut graph: Adjacency = vec![Vec::new(); 6];
+        for (i, j) in edges {
+            graph[i].push((j, 1.));
+            graph[j].push((i, 1.));
+        }
+        let q = modularity(&graph, &[0, 0, 0, 1, 1, 1], 1.);
+        assert!((q - 5. / 14.).abs() < 1e-12);
+        // a single community: 14/14 - 1
+        assert!(modularity(&graph, &[0; 6], 1.).abs() < 1e-12);
+        // resolution 2: 2 * (6/14 - 2 * (7/14)^2) = 12/14 - 1
+        let q2 = modularity(&graph, &[0, 0, 0, 1, 1, 1], 2.);
+        assert!((q2 - (12. / 14. - 1.)).abs() < 1e-12);
+        let communities = louvain(&graph, 1.);
+        assert_eq!(communities.labels, vec![0, 0, 0, 1, 1, 1]);
+        assert!((communities.modularity - 5. / 14.).abs() < 1e-12);
+        // no edge
+        assert_eq!(modularity(&vec![Vec::new(); 3], &[0, 1, 2], 1.), 0.);
+    }
+
+    #[test]
+    fn planted_cliques_are_recovered() {
+        // 5 cliques of 6 nodes, nodes of a clique interleaved, each clique linked to the next by one weak bridge
+        let (nb_cliques, clique_size) = (5, 6);
+        let n = nb_cliques * clique_size;
+        let clique = |i: usize| i % nb_cliques;
+        let mut knn: Vec<Vec<(usize, f64)>> = vec![Vec::new(); n];
+        for (i, neighbours) in knn.iter_mut().enumerate() {
+            neighbours.extend((0..n).filter(|j| *j != i && clique(*j) == clique(i)).map(|j| (j, 0.05)));
+        }
+        for (c, neighbours) in knn.iter_mut().take(nb_cliques).enumerate() {
+            neighbours.push(((c + 1) % nb_cliques + nb_cliques, 0.8));
+        }
+        let graph = symmetrize(&knn, false, 1.);
+        let communities = louvain(&graph, 1.);
+        assert_eq!(communities.sizes, vec![clique_size; nb_cliques]);
+        for i in 0..n {
+            for j in 0..n {
+                assert_eq!(communities.labels[i] == communities.labels[j], clique(i) == clique(j));
+            }
+        }
+        assert!(communities.nb_levels >= 1);
+        // communities of equal size are numbered by their smallest node
+        assert_eq!(communities.labels[..nb_cliques], [0, 1, 2, 3, 4]);
+        assert!(communities.modularity > 0.7);
+        // the bridges are not mutual
+        assert_eq!(nb_edges(&symmetrize(&knn, true, 1.)), nb_cliques * clique_size * (clique_size - 1) / 2);
+    }
+}
@@ -0,0 +1,318 @@
+//! Exact edit distance between a query and a target with the bit-parallel algorithm of Myers,
+//! in the block version of Hyyrö for queries longer than 64 bases.
+//!
+//! Each column of the dynamic programming matrix is encoded by its vertical differences, 64 rows by word,
+//! so a 1500 bp 16S query against a 1500 bp hit costs 24 x 1500 word operations.
+//! Bases are compared case insensitively, non ACGT bases match nothing (not even themselves).
+
+use std::str::FromStr;
+
+/// How the query is aligned to the target
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EditMode {
+    /// end to end on both sequences
+    Global,
+    /// the whole query against a substring of the target, gaps at the ends of the target are free
+    SemiGlobal,
+}
+
+impl FromStr for EditMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(EditMode::Global),
+            "semi-global" => Ok(EditMode::SemiGlobal),
+            _ => Err(format!("unknown edit distance mode {}, expecting global or semi-global", s)),
+        }
+    }
+}
+
+/// Result of the comparison of a query and a target
+#[derive(Clone, Copy, Debug)]
+pub struct EditResult {
+    /// number of substitutions, insertions and deletions
+    pub distance: usize,
+    /// 1 - distance / max(query length, target length) in global mode, 1 - distance / query length in semi-global mode
+    pub identity: f64,
+    /// true if the reverse complement of the query gave the result
+    pub reverse: bool,
+}
+
+const HIGH_BIT: u64 = 1 << 63;
+
+#[inline]
+fn base_code(b: u8) -> usize {
+    match b {
+        b'A' | b'a' => 0,
+        b'C' | b'c' => 1,
+        b'G' | b'g' => 2,
+        b'T' | b't' | b'U' | b'u' => 3,
+        _ => 4,
+    }
+}
+
+/// One column step of a block of 64 rows. pv/mv are the positive/negative vertical differences,
+/// eq the match bits of the target base, hin the horizontal difference entering the block from above.
+/// Returns the horizontal difference leaving the block at its last row.
+#[inline]
+fn advance_block(pv: &mut u64, mv: &mut u64, eq: u64, hin: i32, last_bit: u64) -> (i32, i32) {
+    let hin_neg = (hin < 0) as u64;
+    let xv = eq | *mv;
+    let eq = eq | hin_neg;
+    let xh = ((eq & *pv).wrapping_add(*pv) ^ *pv) | eq;
+    let mut ph = *mv | !(xh | *pv);
+    let mut mh = *pv & xh;
+    let delta = |bit: u64| -> i32 {
+        if ph & bit != 0 {
+            1
+        } else if mh & bit != 0 {
+            -1
+        } else {
+            0
+        }
+    };
+    let (hout, last_delta) = (delta(HIGH_BIT), delta(last_bit));
+    ph = (ph << 1) | (hin > 0) as u64;
+    mh = (mh << 1) | hin_neg;
+    *pv = mh | !(xv | ph);
+    *mv = ph & xv;
+    (hout, last_delta)
+}
+
+/// Exact edit distance between query and target
+pub fn edit_distance(query: &[u8], target: &[u8], mode: EditMode) -> usize {
+    let m = query.len();
+    if m == 0 {
+        return match mode {
+            EditMode::Global => target.len(),
+            EditMode::SemiGlobal => 0,
+        };
+    }
+    let nb_blocks = m.div_ceil(64);
+    // match bits of each base code, by block
+    let mut peq = vec![[0u64; 5]; nb_blocks];
+    for (i, b) in query.iter().enumerate() {
+        let c = base_code(*b);
+        if c < 4 {
+            peq[i / 64][c] |= 1 << (i % 64);
+        }
+    }
+    // first column: D[i][0] = i
+    let mut pv = vec![u64::MAX; nb_blocks];
+    let mut mv = vec![0u64; nb_blocks];
+    let last_bit = 1u64 << ((m - 1) % 64);
+    // top row: D[0][j] = j in global mode, 0 in semi-global mode
+    let top_delta = match mode {
+        EditMode::Global => 1,
+        EditMode::SemiGlobal => 0,
+    };
+    let mut score = m;
+    let mut best = m;
+    for b in target.iter() {
+        let c = base_code(*b);
+        let mut carry = top_delta;
+        for block in 0..nb_blocks {
+            let (hout, last_delta) = advance_block(&mut pv[block], &mut mv[block], peq[block][c], carry, last_bit);
+            carry = hout;
+            if block == nb_blocks - 1 {
+                score = (score as i64 + last_delta as i64) as usize;
+            }
+        }
+        best = best.min(score);
+    }
+    match mode {
+        EditMode::Global => score,
+        EditMode::SemiGlobal => best,
+    }
+} // end of edit_distance
+
+/// Identity derived from an edit distance, see [EditResult]
+pub fn identity(distance: usize, query_len: usize, target_len: usize, mode: EditMode) -> f64 {
+    let len = match mode {
+        EditMode::Global => query_len.max(target_len),
+        EditMode::SemiGlobal => query_len,
+    };
+    if len == 0 {
+        return 0.;
+    }
+    1. - distance as f64 / len as f64
+}
+
+fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|b| match b {
+            b'A' | b'a' => b'T',
+            b'C' | b'c' => b'G',
+            b'G' | b'g' => b'C',
+            b'T' | b't' | b'U' | b'u' => b'A',
+            _ => b'N',
+        })
+        .collect()
+}
+
+/// Compares the query and its reverse complement with the target and keeps the smallest edit distance
+pub fn compare_both_strands(query: &[u8], target: &[u8], mode: EditMode) -> EditResult {
+    let forward = edit_distance(query, target, mode);
+    let reverse = edit_distance(&reverse_complement(query), target, mode);
+    let (distance, reverse) = if reverse < forward { (reverse, true) } else { (forward, false) };
+    EditResult {
+        distance,
+        identity: identity(distance, query.len(), target.len(), mode),
+        reverse,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// quadratic dynamic programming, with the same base comparison as [edit_distance]
+    fn naive_edit_distance(query: &[u8], target: &[u8], mode: EditMode) -> usize {
+        let (m, n) = (query.len(), target.len());
+        // column j of the matrix, D[i][j] for i in 0..=m
+        let mut column: Vec<usize> = (0..=m).collect();
+        let mut best = column[m];
+        for j in 1..=n {
+            let mut previous = column[0];
+            column[0] = match mode {
+                EditMode::Global => j,
+                EditMode::SemiGlobal => 0,
+            };
+            for i in 1..=m {
+                let cq = base_code(query[i - 1]);
+                let substitution = if cq < 4 && cq == base_code(target[j - 1]) { 0 } else { 1 };
+                let value = (previous + substitution).min(column[i] + 1).min(column[i - 1] + 1);
+                previous = column[i];
+                column[i] = value;
+            }
+            best = best.min(column[m]);
+        }
+        match mode {
+            EditMode::Global => column[m],
+            EditMode::SemiGlobal => best,
+        }
+    }
+
+    /// xorshift64
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    fn random_sequence(state: &mut u64, len: usize) -> Vec<u8> {
+        (0..len).map(|_| b"ACGTacgtNR"[(next_random(state) % 10) as usize]).collect()
+    }
+
+    /// a copy of `seq` with about one edit in `rate`
+    fn mutate(state: &mut u64, seq: &[u8], rate: u64) -> Vec<u8> {
+        let mut mutated = Vec::with_capacity(seq.len() + 8);
+        for b in seq {
+            match next_random(state) % (3 * rate) {
+                0 => mutated.push(b"ACGT"[(next_random(state) % 4) as usize]),
+                1 => {}
+                2 => {
+                    mutated.push(*b);
+                    mutated.push(b"ACGT"[(next_random(state) % 4) as usize]);
+                }
+                _ => mutated.push(*b),
+            }
+        }
+        mutated
+    }
+
+    #[test]
+    fn same_as_naive_dynamic_programming() {
+        let mut state = 0x853c49e6748fea9bu64;
+        let mut lens: Vec<usize> = vec![1, 2, 63, 64, 65, 127, 128, 129, 192, 300];
+        for _ in 0..300 {
+            lens.push(1 + (next_random(&mut state) % 300) as usize);
+        }
+        for m in lens {
+            let query = random_sequence(&mut state, m);
+            let related = mutate(&mut state, &query, 8);
+            let n = (next_random(&mut state) % 320) as usize;
+            let unrelated = random_sequence(&mut state, n);
+            // the query inside a longer target, for semi-global mode
+            let mut embedded = random_sequence(&mut state, 20);
+            embedded.extend(&related);
+            embedded.extend(random_sequence(&mut state, 30));
+            for target in [&related, &unrelated, &embedded] {
+                for mode in [EditMode::Global, EditMode::SemiGlobal] {
+                    assert_eq!(
+                        edit_distance(&query, target, mode),
+                        naive_edit_distance(&query, target, mode),
+                        "m {} n {} mode {:?}",
+                        m,
+                        target.len(),
+                        mode
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty_sequences() {
+        assert_eq!(edit_distance(b"", b"", EditMode::Global), 0);
+        assert_eq!(edit_distance(b"", b"ACGT", EditMode::Global), 4);
+        assert_eq!(edit_distance(b"", b"ACGT", EditMode::SemiGlobal), 0);
+        assert_eq!(edit_distance(b"ACG", b"", EditMode::Global), 3);
+        assert_eq!(edit_distance(b"ACG", b"", EditMode::SemiGlobal), 3);
+        let query = vec![b'A'; 65];
+        assert_eq!(edit_distance(&query, b"", EditMode::Global), 65);
+        assert_eq!(identity(0, 0, 0, EditMode::Global), 0.);
+    }
+
+    #[test]
+    fn ambiguous_and_lower_case_bases() {
+        assert_eq!(edit_distance(b"acgt", b"ACGT", EditMode::Global), 0);
+        assert_eq!(edit_distance(b"ACGU", b"ACGT", EditMode::Global), 0);
+        // N matches nothing, not even N
+        assert_eq!(edit_distance(b"ACNT", b"ACNT", EditMode::Global), 1);
+        assert_eq!(edit_distance(b"NNNN", b"ACGT", EditMode::Global), 4);
+    }
+
+    #[test]
+    fn block_boundaries() {
+        // 64 and 65 bases: one full block, then a second block of one row
+        for m in [64, 65] {
+            let query: Vec<u8> = (0..m).map(|i| b"ACGT"[(i * 3 + i / 5) % 4]).collect();
+            assert_eq!(edit_distance(&query, &query, EditMode::Global), 0);
+            let mut last_changed = query.clone();
+            last_changed[m - 1] = if query[m - 1] == b'A' { b'C' } else { b'A' };
+            assert_eq!(edit_distance(&query, &last_changed, EditMode::Global), 1);
+            assert_eq!(edit_distance(&query, &query[..m - 1], EditMode::Global), 1);
+            let mut longer = b"TTT".to_vec();
+            longer.extend(&query);
+            longer.extend(b"GG");
+            assert_eq!(edit_distance(&query, &longer, EditMode::Global), 5);
+            assert_eq!(edit_distance(&query, &longer, EditMode::SemiGlobal), 0);
+        }
+    }
+
+    #[test]
+    fn both_strands() {
+        let target = b"AACCGGTTACGTTGCA".to_vec();
+        let result = compare_both_strands(&target, &target, EditMode::Global);
+        assert_eq!((result.distance, result.reverse), (0, false));
+        assert_eq!(result.identity, 1.);
+        let reversed = reverse_complement(&target);
+        let result = compare_both_strands(&reversed, &target, EditMode::Global);
+        assert_eq!((result.distance, result.reverse), (0, true));
+        // reverse complement of a part of the target, semi-global: identity relative to the query
+        let part = reverse_complement(&target[4..12]);
+        let result = compare_both_strands(&part, &target, EditMode::SemiGlobal);
+        assert_eq!((result.distance, result.reverse), (0, true));
+        assert_eq!(result.identity, 1.);
+        let result = compare_both_strands(&part, &target, EditMode::Global);
+        assert_eq!(result.distance, 8);
+        assert_eq!(result.identity, 0.5);
+        // ties keep the forward strand
+        let result = compare_both_strands(b"ACGT", b"ACGT", EditMode::Global);
+        assert!(!result.reverse);
+    }
+}
@@ -0,0 +1,259 @@
+//! Minimal HTTP/1.1 for adas-serve: one request by connection, answered with a json body.
+//!
+//! Connections come from a tcp or a unix socket listener. Each connection gets read and write timeouts and
+//! headers are read within [MAX_HEADER_BYTES], so that idle or malicious clients cannot hold a worker
+//! or memory for ever.
+
+use serde::Serialize;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// largest request body we accept, some thousands of 16S or read sequences; larger query sets go by several requests
+pub const MAX_BODY_SIZE: usize = 32 * 1024 * 1024;
+
+/// largest size of request line and headers together
+pub const MAX_HEADER_BYTES: u64 = 64 * 1024;
+
+/// a connection coming from either the tcp or the unix socket listener
+pub trait Connection: Read + Write + Send {
+    /// sets read and write timeouts of the underlying socket
+    fn set_timeouts(&self, timeout: Duration) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_timeouts(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+impl Connection for UnixStream {
+    fn set_timeouts(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+/// What answers the routes of the server, with json bodies
+pub trait Routes {
+    /// body of GET /health
+    fn health(&self) -> Vec<u8>;
+    /// body of POST /search given the request body, an Err is a client error answered with status 400
+    fn search(&self, body: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+pub fn error_body(msg: &str) -> Vec<u8> {
+    serde_json::to_vec(&ErrorResponse { error: msg.to_string() }).unwrap()
+}
+
+/// status and body answering `method` on `target`
+pub fn route<R: Routes + ?Sized>(routes: &R, method: &str, target: &str, body: &[u8]) -> (u16, Vec<u8>) {
+    match (method, target) {
+        ("GET", "/health") => (200, routes.health()),
+        ("POST", "/search") => match routes.search(body) {
+            Ok(answer) => (200, answer),
+            Err(e) => (400, error_body(&e)),
+        },
+        (_, "/health") | (_, "/search") => (405, error_body("method not allowed")),
+        _ => (404, error_body("not found")),
+    }
+}
+
+/// reads request line, headers and body. Returns (method, target, body)
+pub fn read_http_request<R: BufRead>(reader: &mut R) -> Result<(String, String, Vec<u8>), String> {
+    let mut content_length = 0usize;
+    let (method, target) = {
+        let mut head = reader.by_ref().take(MAX_HEADER_BYTES);
+        let mut line = String::new();
+        head.read_line(&mut line).map_err(|e| e.to_string())?;
+        let mut fields = line.split_whitespace();
+        let method = fields.next().ok_or("empty request")?.to_string();
+        let target = fields.next().ok_or("no request target")?.to_string();
+        //
+        loop {
+            line.clear();
+            let nb_read = head.read_line(&mut line).map_err(|e| e.to_string())?;
+            if head.limit() == 0 {
+                return Err(format!("request headers larger than {} bytes", MAX_HEADER_BYTES));
+            }
+            let header = line.trim_end();
+            if nb_read == 0 || header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    content_length = value
+                        .trim()
+                        .parse::<usize>()
+                        .map_err(|_| String::from("bad content-length"))?;
+                }
+            }
+        }
+        (method, target)
+    };
+    if content_length > MAX_BODY_SIZE {
+        return Err(format!("request body larger than {} bytes", MAX_BODY_SIZE));
+    }
+    // the buffer grows with the bytes received, not with the announced content-length
+    let mut body = Vec::new();
+    reader
+        .take(content_length as u64)
+        .read_to_end(&mut body)
+        .map_err(|e| e.to_string())?;
+    if body.len() < content_length {
+        return Err(format!("request body of {} bytes, content-length {}", body.len(), content_length));
+    }
+    Ok((method, target, body))
+} // end of read_http_request
+
+pub fn write_http_response<W: Write>(writer: &mut W, status: u16, body: &[u8]) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    };
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        body.len()
+    )?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+/// serves one http request on the connection then closes it
+pub fn serve_connection<R: Routes + ?Sized>(routes: &R, mut conn: Box<dyn Connection>, timeout: Duration) {
+    if let Err(e) = conn.set_timeouts(timeout) {
+        log::error!("could not set connection timeouts: {:?}", e);
+        return;
+    }
+    let request = {
+        let mut reader = BufReader::new(&mut conn);
+        read_http_request(&mut reader)
+    };
+    let (status, body) = match request {
+        Err(e) => (400, error_body(&e)),
+        Ok((method, target, body)) => route(routes, &method, &target, &body),
+    };
+    if let Err(e) = write_http_response(&mut conn, status, &body) {
+        log::error!("could not write response: {:?}", e);
+    }
+} // end of serve_connection
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// answers searches with the request body, so that the test sees what the server read
+    struct Echo;
+
+    impl Routes for Echo {
+        fn health(&self) -> Vec<u8> {
+            b"{\"status\":\"ok\"}".to_vec()
+        }
+
+        fn search(&self, body: &[u8]) -> Result<Vec<u8>, String> {
+            if body.is_empty() {
+                Err(String::from("empty body"))
+            } else {
+                Ok(body.to_vec())
+            }
+        }
+    }
+
+    /// sends `request` to a server thread over a socket pair, returns the full response
+    fn exchange(request: &[u8]) -> String {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || serve_connection(&Echo, Box::new(server), Duration::from_secs(5)));
+        client.write_all(request).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        handle.join().unwrap();
+        response
+    }
+
+    fn status_of(response: &str) -> &str {
+        response.split_whitespace().nth(1).unwrap()
+    }
+
+    fn body_of(response: &str) -> &str {
+        response.split_once("\r\n\r\n").unwrap().1
+    }
+
+    #[test]
+    fn health() {
+        let response = exchange(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert_eq!(status_of(&response), "200");
+        assert!(response.contains("Content-Length: 15\r\n"));
+        assert_eq!(body_of(&response), "{\"status\":\"ok\"}");
+    }
+
+    #[test]
+    fn search_reads_body() {
+        let body = "{\"queries\":[{\"id\":\"q\",\"seq\":\"ACGT\"}]}";
+        let request = format!("POST /search HTTP/1.1\r\ncontent-length: {}\r\n\r\n{}", body.len(), body);
+        let response = exchange(request.as_bytes());
+        assert_eq!(status_of(&response), "200");
+        assert_eq!(body_of(&response), body);
+    }
+
+    #[test]
+    fn client_errors() {
+        let response = exchange(b"POST /search HTTP/1.1\r\n\r\n");
+        assert_eq!(status_of(&response), "400");
+        assert_eq!(body_of(&response), "{\"error\":\"empty body\"}");
+        assert_eq!(status_of(&exchange(b"GET /search HTTP/1.1\r\n\r\n")), "405");
+        assert_eq!(status_of(&exchange(b"GET /other HTTP/1.1\r\n\r\n")), "404");
+        assert_eq!(status_of(&exchange(b"\r\n")), "400");
+        assert_eq!(status_of(&exchange(b"POST /search HTTP/1.1\r\nContent-Length: x\r\n\r\n")), "400");
+    }
+
+    #[test]
+    fn headers_are_bounded() {
+        let mut request = b"GET /health HTTP/1.1\r\nX-Long: ".to_vec();
+        request.extend(std::iter::repeat_n(b'a', 2 * MAX_HEADER_BYTES as usize));
+        request.extend(b"\r\n\r\n");
+        let mut reader = io::Cursor::new(request);
+        let error = read_http_request(&mut reader).unwrap_err();
+        assert!(error.contains("headers larger than"));
+        // reading stopped at the limit
+        assert_eq!(reader.position(), MAX_HEADER_BYTES);
+    }
+
+    #[test]
+    fn body_is_bounded() {
+        let request = format!("POST /search HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_SIZE + 1);
+        let error = read_http_request(&mut io::Cursor::new(request)).unwrap_err();
+        assert!(error.contains("body larger than"));
+        // a large announced body which does not come is an error, without allocating it
+        let request = format!("POST /search HTTP/1.1\r\nContent-Length: {}\r\n\r\nACGT", MAX_BODY_SIZE);
+        let error = read_http_request(&mut io::Cursor::new(request)).unwrap_err();
+        assert!(error.contains("body of 4 bytes"));
+    }
+
+    #[test]
+    fn idle_connection_times_out() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let start = Instant::now();
+        let handle =
+            std::thread::spawn(move || serve_connection(&Echo, Box::new(server), Duration::from_millis(100)));
+        // nothing is sent: the worker must give up instead of waiting for ever
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        handle.join().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(status_of(&response), "400");
+    }
+}
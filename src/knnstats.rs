@@ -0,0 +1,161 @@
+//! Quality and hubness diagnostics of a kNN graph.
+//!
+//! The kNN lists extracted from the HNSW graph can be shorter than asked (the HNSW layers keep at most
+//! max_nb_connection neighbours and pruning drops some), and in high dimension a few points (hubs) appear in the lists
+//! of many others while anti-hubs appear in none. The in-degree of a node is its reverse-kNN count, hubness
+//! is measured by the skewness of the in-degree distribution.
+
+/// Diagnostics of kNN lists given by node index
+pub struct KnnDiagnostics {
+    /// number of neighbours asked
+    pub k: usize,
+    /// number of neighbours of each node
+    pub out_degrees: Vec<usize>,
+    /// number of lists each node appears in (reverse kNN count)
+    pub in_degrees: Vec<usize>,
+    /// nodes without neighbours that are in no list
+    pub nb_isolated: usize,
+}
+
+impl KnnDiagnostics {
+    /// self neighbours are not counted
+    pub fn new(knn: &[Vec<(usize, f64)>], k: usize) -> Self {
+        let mut out_degrees = vec![0usize; knn.len()];
+        let mut in_degrees = vec![0usize; knn.len()];
+        for (i, neighbours) in knn.iter().enumerate() {
+            for (j, _) in neighbours.iter().filter(|(j, _)| *j != i) {
+                out_degrees[i] += 1;
+                in_degrees[*j] += 1;
+            }
+        }
+        let nb_isolated = out_degrees
+            .iter()
+            .zip(in_degrees.iter())
+            .filter(|(out_d, in_d)| **out_d == 0 && **in_d == 0)
+            .count();
+        KnnDiagnostics {
+            k,
+            out_degrees,
+            in_degrees,
+            nb_isolated,
+        }
+    }
+
+    pub fn get_nb_nodes(&self) -> usize {
+        self.out_degrees.len()
+    }
+
+    /// number of nodes by out-degree, index is the out-degree
+    pub fn out_degree_histogram(&self) -> Vec<usize> {
+        let max = self.out_degrees.iter().max().copied().unwrap_or(0);
+        let mut histogram = vec![0usize; max + 1];
+        for d in self.out_degrees.iter() {
+            histogram[*d] += 1;
+        }
+        histogram
+    }
+
+    /// nodes with an empty neighbour list
+    pub fn get_nb_empty(&self) -> usize {
+        self.out_degrees.iter().filter(|d| **d == 0).count()
+    }
+
+    /// fraction of nodes with fewer than k neighbours
+    pub fn get_fraction_short(&self) -> f64 {
+        if self.out_degrees.is_empty() {
+            return 0.;
+        }
+        self.out_degrees.iter().filter(|d| **d < self.k).count() as f64 / self.out_degrees.len() as f64
+    }
+
+    pub fn get_mean_out_degree(&self) -> f64 {
+        mean(&self.out_degrees)
+    }
+
+    /// nodes in no neighbour list
+    pub fn get_nb_antihubs(&self) -> usize {
+        self.in_degrees.iter().filter(|d| **d == 0).count()
+    }
+
+    /// skewness of the in-degree distribution, 0 for a symmetric distribution, large positive with hubs
+    pub fn get_hubness(&self) -> f64 {
+        let n = self.in_degrees.len() as f64;
+        if n == 0. {
+            return 0.;
+        }
+        let m = mean(&self.in_degrees);
+        let (m2, m3) = self.in_degrees.iter().fold((0., 0.), |(m2, m3), d| {
+            let x = *d as f64 - m;
+            (m2 + x * x, m3 + x * x * x)
+        });
+        let var = m2 / n;
+        if var <= 0. {
+            return 0.;
+        }
+        (m3 / n) / var.powf(1.5)
+    }
+
+    /// the `nb` nodes of largest in-degree, as (node, in-degree), by decreasing in-degree
+    pub fn top_hubs(&self, nb: usize) -> Vec<(usize, usize)> {
+        let mut nodes: Vec<(usize, usize)> = self.in_degrees.iter().copied().enumerate().collect();
+        nodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        nodes.truncate(nb);
+        nodes
+    }
+} // end of impl KnnDiagnostics
+
+fn mean(values: &[usize]) -> f64 {
+    if values.is_empty() {
+        return 0.;
+    }
+    values.iter().sum::<usize>() as f64 / values.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hand_computed_lists() {
+        // 0 is its own first neighbour, 4 has no neighbour but is in the list of 3, 5 is isolated
+        let knn = vec![
+            vec![(0, 0.), (1, 0.1), (2, 0.2)],
+            vec![(0, 0.1), (2, 0.3)],
+            vec![(0, 0.2)],
+            vec![(0, 0.5), (4, 0.6)],
+            vec![],
+            vec![],
+        ];
+        let diagnostics = KnnDiagnostics::new(&knn, 2);
+        assert_eq!(diagnostics.get_nb_nodes(), 6);
+        assert_eq!(diagnostics.out_degrees, vec![2, 2, 1, 2, 0, 0]);
+        assert_eq!(diagnostics.in_degrees, vec![3, 1, 2, 0, 1, 0]);
+        assert_eq!(diagnostics.out_degree_histogram(), vec![2, 1, 3]);
+        assert_eq!(diagnostics.get_nb_empty(), 2);
+        assert_eq!(diagnostics.nb_isolated, 1);
+        // 3 is in no list but has neighbours
+        assert_eq!(diagnostics.get_nb_antihubs(), 2);
+        assert_eq!(diagnostics.get_fraction_short(), 0.5);
+        assert!((diagnostics.get_mean_out_degree() - 7. / 6.).abs() < 1e-12);
+        // in-degrees minus their mean 7/6, in sixths: 11 -1 5 -7 -1 -7, sum of squares 246, sum of cubes 768,
+        // skewness (768/6) / (246/6)^1.5
+        assert!((diagnostics.get_hubness() - 128. / 41f64.powf(1.5)).abs() < 1e-12);
+        assert_eq!(diagnostics.top_hubs(2), vec![(0, 3), (2, 2)]);
+        // equal in-degrees by node
+        assert_eq!(diagnostics.top_hubs(4)[2..], [(1, 1), (4, 1)]);
+    }
+
+    #[test]
+    fn no_hubness() {
+        // a ring: every in-degree is 1
+        let knn: Vec<Vec<(usize, f64)>> = (0..5).map(|i| vec![(i, 0.), ((i + 1) % 5, 0.1)]).collect();
+        let diagnostics = KnnDiagnostics::new(&knn, 1);
+        assert_eq!(diagnostics.in_degrees, vec![1; 5]);
+        assert_eq!(diagnostics.get_hubness(), 0.);
+        assert_eq!(diagnostics.get_fraction_short(), 0.);
+        assert_eq!(diagnostics.get_nb_antihubs(), 0);
+        let empty = KnnDiagnostics::new(&[], 5);
+        assert_eq!(empty.out_degree_histogram(), vec![0]);
+        assert_eq!((empty.get_hubness(), empty.get_fraction_short(), empty.get_mean_out_degree()), (0., 0., 0.));
+    }
+}
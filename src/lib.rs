@@ -0,0 +1,16 @@
+//! Library part of adas: code shared by the binaries.
+
+pub mod abundance;
+pub mod buildconfig;
+pub mod community;
+pub mod editdist;
+pub mod httpserve;
+pub mod knnstats;
+pub mod presets;
+pub mod reproducible;
+pub mod sce;
+pub mod seqstore;
+pub mod sketchcode;
+pub mod sketching;
+pub mod spill;
+pub mod taxonomy;
@@ -0,0 +1,22 @@
+//! minimap2 presets by their command line names, shared by adas-chain and the alignment of hits in adas-search.
+
+use minimap2::Preset;
+
+/// command line names of the presets
+pub const PRESET_NAMES: [&str; 9] = ["map-ont", "map-hifi", "map-pb", "asm5", "asm10", "asm20", "ava-ont", "ava-pb", "sr"];
+
+/// minimap2 preset from its command line name
+pub fn preset_from_name(name: &str) -> Preset {
+    match name {
+        "map-ont" => Preset::MapOnt,
+        "map-hifi" => Preset::MapHifi,
+        "map-pb" => Preset::MapPb,
+        "asm5" => Preset::Asm5,
+        "asm10" => Preset::Asm10,
+        "asm20" => Preset::Asm20,
+        "ava-ont" => Preset::AvaOnt,
+        "ava-pb" => Preset::AvaPb,
+        "sr" => Preset::Sr,
+        _ => panic!("unknown preset {}", name),
+    }
+}
@@ -0,0 +1,39 @@
+//! Random orders drawn from a seed, the same on every platform and whatever the number of threads,
+//! e.g. for the subsampling of adas-tune.
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Permutation of 0..nb drawn from `seed` (Fisher-Yates shuffle).
+/// `stream` gives independent permutations for a same seed, e.g. for successive samples.
+pub fn seeded_order(nb: usize, seed: u64, stream: u64) -> Vec<usize> {
+    let mut state = seed ^ stream.wrapping_mul(0xd1b54a32d192ed03);
+    let mut order: Vec<usize> = (0..nb).collect();
+    for i in (1..nb).rev() {
+        let j = (splitmix64(&mut state) % (i as u64 + 1)) as usize;
+        order.swap(i, j);
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_order_is_a_reproducible_permutation() {
+        let order = seeded_order(1000, 42, 0);
+        let mut sorted = order.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..1000).collect::<Vec<usize>>());
+        assert_eq!(order, seeded_order(1000, 42, 0));
+        assert_ne!(order, seeded_order(1000, 43, 0));
+        assert_ne!(order, seeded_order(1000, 42, 1));
+        assert!(seeded_order(0, 42, 0).is_empty());
+    }
+}
@@ -0,0 +1,220 @@
+//! Minimizer index of references, anchor collection and minimap2-style chaining.
+
+use std::collections::HashMap;
+
+use super::minimizer::{minimizers, Minimizer};
+use super::SceParams;
+
+/// Minimizer index of a set of reference sequences.
+/// Sequences are kept as they are needed for extension.
+pub struct MinimizerIndex {
+    k: usize,
+    w: usize,
+    names: Vec<String>,
+    seqs: Vec<Vec<u8>>,
+    /// minimizer hash -> (reference id, position, reverse flag)
+    table: HashMap<u64, Vec<(u32, u32, bool)>>,
+}
+
+impl MinimizerIndex {
+    /// indexes the (w,k)-minimizers of references
+    pub fn new(k: usize, w: usize, names: Vec<String>, seqs: Vec<Vec<u8>>) -> Self {
+        assert_eq!(names.len(), seqs.len(), "one name by reference sequence");
+        let mut table: HashMap<u64, Vec<(u32, u32, bool)>> = HashMap::new();
+        for (ref_id, seq) in seqs.iter().enumerate() {
+            for m in minimizers(seq, k, w) {
+                table.entry(m.hash).or_default().push((ref_id as u32, m.pos, m.rev));
+            }
+        }
+        MinimizerIndex {
+            k,
+            w,
+            names,
+            seqs,
+            table,
+        }
+    }
+
+    pub fn get_kmer_size(&self) -> usize {
+        self.k
+    }
+
+    pub fn get_window_size(&self) -> usize {
+        self.w
+    }
+
+    /// number of reference sequences
+    pub fn get_nb_seqs(&self) -> usize {
+        self.seqs.len()
+    }
+
+    pub fn get_name(&self, ref_id: usize) -> &str {
+        &self.names[ref_id]
+    }
+
+    pub fn get_seq(&self, ref_id: usize) -> &[u8] {
+        &self.seqs[ref_id]
+    }
+
+    /// occurrences of a minimizer in references
+    pub fn get_occurrences(&self, hash: u64) -> &[(u32, u32, bool)] {
+        self.table.get(&hash).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+} // end of impl MinimizerIndex
+
+/// A seed match between query and reference.
+/// For reverse strand anchors `query_pos` is on the reverse complement of the query,
+/// so that along a chain both positions increase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Anchor {
+    pub ref_id: u32,
+    pub rev: bool,
+    pub ref_pos: u32,
+    pub query_pos: u32,
+}
+
+/// Matches query minimizers against the index. Minimizers occurring more than `max_occ` times
+/// in references are ignored. Anchors are returned sorted by reference, strand, reference and query position.
+pub fn collect_anchors(
+    index: &MinimizerIndex,
+    query_minimizers: &[Minimizer],
+    query_len: usize,
+    max_occ: usize,
+) -> Vec<Anchor> {
+    let k = index.get_kmer_size() as u32;
+    let mut anchors = Vec::new();
+    for m in query_minimizers {
+        let occurrences = index.get_occurrences(m.hash);
+        if occurrences.len() > max_occ {
+            continue;
+        }
+        for (ref_id, ref_pos, ref_rev) in occurrences {
+            let rev = m.rev != *ref_rev;
+            let query_pos = if rev { query_len as u32 - (m.pos + k) } else { m.pos };
+            anchors.push(Anchor {
+                ref_id: *ref_id,
+                rev,
+                ref_pos: *ref_pos,
+                query_pos,
+            });
+        }
+    }
+    anchors.sort_unstable_by_key(|a| (a.ref_id, a.rev, a.ref_pos, a.query_pos));
+    anchors
+} // end of collect_anchors
+
+/// A chain of colinear anchors on one reference and one strand
+#[derive(Clone, Debug)]
+pub struct Chain {
+    pub ref_id: u32,
+    pub rev: bool,
+    /// chaining score
+    pub score: f64,
+    /// anchors in increasing positions
+    pub anchors: Vec<Anchor>,
+}
+
+impl Chain {
+    /// query span of chain, in query orientation of the chain (reverse complement if rev)
+    pub fn query_span(&self, k: usize) -> (usize, usize) {
+        (
+            self.anchors[0].query_pos as usize,
+            self.anchors[self.anchors.len() - 1].query_pos as usize + k,
+        )
+    }
+
+    /// reference span of chain
+    pub fn ref_span(&self, k: usize) -> (usize, usize) {
+        (
+            self.anchors[0].ref_pos as usize,
+            self.anchors[self.anchors.len() - 1].ref_pos as usize + k,
+        )
+    }
+} // end of impl Chain
+
+/// gap cost of minimap2 chaining for a difference `gap` between reference and query distances
+#[inline]
+fn gap_cost(gap: u32, k: usize) -> f64 {
+    if gap == 0 {
+        0.
+    } else {
+        0.01 * k as f64 * gap as f64 + 0.5 * (gap as f64).log2()
+    }
+}
+
+/// Chains anchors (sorted as returned by collect_anchors) by dynamic programming as minimap2:
+/// f(i) = max(k, max_j f(j) + min(dq, dr, k) - gap_cost(|dr - dq|)) over at most `max_iter` predecessors
+/// within `max_gap` bases and a diagonal difference at most `bandwidth`.
+/// Chains are then extracted by decreasing score, each anchor belonging to one chain,
+/// and kept if they have at least `min_cnt` anchors and a score at least `min_chain_score`.
+pub fn chain_anchors(anchors: &[Anchor], k: usize, params: &SceParams) -> Vec<Chain> {
+    let n = anchors.len();
+    let mut score = vec![0f64; n];
+    let mut pred: Vec<Option<usize>> = vec![None; n];
+    for i in 0..n {
+        let ai = &anchors[i];
+        score[i] = k as f64;
+        let first = i.saturating_sub(params.max_iter);
+        for j in (first..i).rev() {
+            let aj = &anchors[j];
+            if aj.ref_id != ai.ref_id || aj.rev != ai.rev {
+                break;
+            }
+            let dr = ai.ref_pos - aj.ref_pos;
+            if dr as usize > params.max_gap {
+                break;
+            }
+            if dr == 0 || aj.query_pos >= ai.query_pos {
+                continue;
+            }
+            let dq = ai.query_pos - aj.query_pos;
+            if dq as usize > params.max_gap {
+                continue;
+            }
+            let gap = dr.abs_diff(dq);
+            if gap as usize > params.bandwidth {
+                continue;
+            }
+            let alpha = dr.min(dq).min(k as u32) as f64;
+            let candidate = score[j] + alpha - gap_cost(gap, k);
+            if candidate > score[i] {
+                score[i] = candidate;
+                pred[i] = Some(j);
+            }
+        }
+    }
+    // backtrack from best ends
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_unstable_by(|a, b| score[*b].partial_cmp(&score[*a]).unwrap());
+    let mut used = vec![false; n];
+    let mut chains = Vec::new();
+    for end in order {
+        if used[end] {
+            continue;
+        }
+        let mut members = Vec::new();
+        let mut current = Some(end);
+        let mut start_score = 0.;
+        while let Some(i) = current {
+            if used[i] {
+                start_score = score[i];
+                break;
+            }
+            used[i] = true;
+            members.push(anchors[i]);
+            current = pred[i];
+        }
+        let chain_score = score[end] - start_score;
+        if members.len() < params.min_cnt || chain_score < params.min_chain_score as f64 {
+            continue;
+        }
+        members.reverse();
+        chains.push(Chain {
+            ref_id: members[0].ref_id,
+            rev: members[0].rev,
+            score: chain_score,
+            anchors: members,
+        });
+    }
+    chains
+} // end of chain_anchors
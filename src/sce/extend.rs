@@ -0,0 +1,421 @@
+//! Banded dynamic programming with affine gaps, used to close the gaps between anchors of a chain
+//! and to extend the chain ends. The band adapts to each gap: it is centred on the line joining
+//! the two anchors and widened by the difference between reference and query gap lengths.
+
+use super::chain::Chain;
+
+/// cigar operations, with the numeric codes of SAM and minimap2
+pub const CIGAR_MATCH: u8 = 0;
+pub const CIGAR_INS: u8 = 1;
+pub const CIGAR_DEL: u8 = 2;
+
+const NEG_INF: i32 = i32::MIN / 4;
+
+/// Alignment scores, gaps cost `gap_open + l * gap_extend` for a gap of length l
+#[derive(Clone, Copy, Debug)]
+pub struct Scoring {
+    pub match_score: i32,
+    pub mismatch: i32,
+    pub gap_open: i32,
+    pub gap_extend: i32,
+}
+
+impl Default for Scoring {
+    /// minimap2 map-ont scores
+    fn default() -> Self {
+        Scoring {
+            match_score: 2,
+            mismatch: 4,
+            gap_open: 4,
+            gap_extend: 2,
+        }
+    }
+}
+
+/// Result of a banded alignment of a query segment against a reference segment
+#[derive(Clone, Debug, Default)]
+pub struct DpAlignment {
+    pub score: i32,
+    /// number of query bases aligned
+    pub query_len: usize,
+    /// number of reference bases aligned
+    pub ref_len: usize,
+    /// one operation by alignment column
+    pub ops: Vec<u8>,
+}
+
+/// global alignment or extension (alignment starts at beginning of both segments and may stop anywhere)
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DpMode {
+    Global,
+    Extension,
+}
+
+// traceback bits: 2 bits for the origin of H, then a bit for E and F continuing a gap
+const FROM_DIAG: u8 = 0;
+const FROM_E: u8 = 1;
+const FROM_F: u8 = 2;
+const E_EXTEND: u8 = 4;
+const F_EXTEND: u8 = 8;
+
+/// banded Gotoh alignment of q (rows) against r (columns).
+/// E is a gap in the query (deletion), F a gap in the reference (insertion).
+fn banded_dp(q: &[u8], r: &[u8], band: usize, scoring: &Scoring, mode: DpMode) -> DpAlignment {
+    let (n, m) = (q.len(), r.len());
+    if mode == DpMode::Global && (n == 0 || m == 0) {
+        let (op, len) = if n == 0 { (CIGAR_DEL, m) } else { (CIGAR_INS, n) };
+        let score = if len == 0 { 0 } else { -(scoring.gap_open + len as i32 * scoring.gap_extend) };
+        return DpAlignment {
+            score,
+            query_len: n,
+            ref_len: m,
+            ops: vec![op; len],
+        };
+    }
+    // column range of row i
+    let range = |i: usize| -> (usize, usize) {
+        let center = match mode {
+            DpMode::Global => (i * m + n / 2) / n.max(1),
+            DpMode::Extension => i,
+        };
+        (center.saturating_sub(band), (center + band).min(m))
+    };
+    let open_extend = scoring.gap_open + scoring.gap_extend;
+    let mut rows_lo = Vec::with_capacity(n + 1);
+    let mut trace: Vec<Vec<u8>> = Vec::with_capacity(n + 1);
+    let (lo0, hi0) = range(0);
+    let mut h_prev = vec![NEG_INF; hi0 - lo0 + 1];
+    let mut f_prev = vec![NEG_INF; hi0 - lo0 + 1];
+    let mut trace0 = vec![0u8; hi0 - lo0 + 1];
+    // first row: deletions only
+    let mut e = NEG_INF;
+    for j in lo0..=hi0 {
+        if j == 0 {
+            h_prev[0] = 0;
+            continue;
+        }
+        let h_left = if j > lo0 { h_prev[j - 1 - lo0] } else { NEG_INF };
+        let (e_new, extend) = if e - scoring.gap_extend >= h_left - open_extend {
+            (e - scoring.gap_extend, E_EXTEND)
+        } else {
+            (h_left - open_extend, 0)
+        };
+        e = e_new;
+        h_prev[j - lo0] = e;
+        trace0[j - lo0] = FROM_E | extend;
+    }
+    rows_lo.push(lo0);
+    trace.push(trace0);
+    let (mut best_score, mut best_i, mut best_j) = (0, 0, 0);
+    if mode == DpMode::Extension {
+        for j in lo0..=hi0 {
+            if h_prev[j - lo0] > best_score {
+                (best_score, best_i, best_j) = (h_prev[j - lo0], 0, j);
+            }
+        }
+    }
+    // in extension mode rows beyond m + band have no cell in the band: the query overhangs the reference
+    let last_row = match mode {
+        DpMode::Global => n,
+        DpMode::Extension => n.min(m + band),
+    };
+    let mut prev_lo = lo0;
+    for i in 1..=last_row {
+        let (lo, hi) = range(i);
+        let width = hi - lo + 1;
+        let mut h_row = vec![NEG_INF; width];
+        let mut f_row = vec![NEG_INF; width];
+        let mut trace_row = vec![0u8; width];
+        let prev_hi = prev_lo + h_prev.len() - 1;
+        let get_prev = |v: &Vec<i32>, j: usize| -> i32 {
+            if j >= prev_lo && j <= prev_hi {
+                v[j - prev_lo]
+            } else {
+                NEG_INF
+            }
+        };
+        let mut e = NEG_INF;
+        for j in lo..=hi {
+            let mut t = 0u8;
+            // F: insertion, comes from row above
+            let h_up = get_prev(&h_prev, j);
+            let f_up = get_prev(&f_prev, j);
+            let f = if f_up - scoring.gap_extend >= h_up - open_extend {
+                t |= F_EXTEND;
+                f_up - scoring.gap_extend
+            } else {
+                h_up - open_extend
+            };
+            // E: deletion, comes from the left in this row
+            let h_left = if j > lo { h_row[j - 1 - lo] } else { NEG_INF };
+            e = if e - scoring.gap_extend >= h_left - open_extend {
+                t |= E_EXTEND;
+                e - scoring.gap_extend
+            } else {
+                h_left - open_extend
+            };
+            let diag = if j > 0 {
+                let s = if q[i - 1].eq_ignore_ascii_case(&r[j - 1]) {
+                    scoring.match_score
+                } else {
+                    -scoring.mismatch
+                };
+                let h_diag = get_prev(&h_prev, j - 1);
+                if h_diag == NEG_INF {
+                    NEG_INF
+                } else {
+                    h_diag + s
+                }
+            } else {
+                NEG_INF
+            };
+            let mut h = diag;
+            let mut origin = FROM_DIAG;
+            if e > h {
+                h = e;
+                origin = FROM_E;
+            }
+            if f > h {
+                h = f;
+                origin = FROM_F;
+            }
+            h_row[j - lo] = h;
+            f_row[j - lo] = f;
+            trace_row[j - lo] = t | origin;
+            if mode == DpMode::Extension && h > best_score {
+                (best_score, best_i, best_j) = (h, i, j);
+            }
+        }
+        rows_lo.push(lo);
+        trace.push(trace_row);
+        h_prev = h_row;
+        f_prev = f_row;
+        prev_lo = lo;
+    }
+    let (end_i, end_j, score) = match mode {
+        DpMode::Global => (n, m, h_prev[m - prev_lo]),
+        DpMode::Extension => (best_i, best_j, best_score),
+    };
+    // traceback, state 0 = H, 1 = E, 2 = F
+    let mut ops = Vec::with_capacity(end_i + end_j);
+    let (mut i, mut j) = (end_i, end_j);
+    let mut state = 0u8;
+    while i > 0 || j > 0 {
+        let t = trace[i][j - rows_lo[i]];
+        match state {
+            0 => match t & 3 {
+                FROM_DIAG => {
+                    ops.push(CIGAR_MATCH);
+                    i -= 1;
+                    j -= 1;
+                }
+                FROM_E => state = 1,
+                _ => state = 2,
+            },
+            1 => {
+                ops.push(CIGAR_DEL);
+                if t & E_EXTEND == 0 {
+                    state = 0;
+                }
+                j -= 1;
+            }
+            _ => {
+                ops.push(CIGAR_INS);
+                if t & F_EXTEND == 0 {
+                    state = 0;
+                }
+                i -= 1;
+            }
+        }
+    }
+    ops.reverse();
+    DpAlignment {
+        score,
+        query_len: end_i,
+        ref_len: end_j,
+        ops,
+    }
+} // end of banded_dp
+
+/// global alignment of two segments, band is widened by the length difference of the segments
+pub fn align_global(q: &[u8], r: &[u8], band: usize, scoring: &Scoring) -> DpAlignment {
+    banded_dp(q, r, band + q.len().abs_diff(r.len()), scoring, DpMode::Global)
+}
+
+/// extension alignment from the start of both segments, stopping at the best scoring cell
+pub fn align_extension(q: &[u8], r: &[u8], band: usize, scoring: &Scoring) -> DpAlignment {
+    banded_dp(q, r, band, scoring, DpMode::Extension)
+}
+
+/// Base level alignment of a chain
+#[derive(Clone, Debug)]
+pub struct ChainAlignment {
+    /// aligned interval on the query, in chain orientation (reverse complement for reverse chains)
+    pub query_start: usize,
+    pub query_end: usize,
+    /// aligned interval on the reference
+    pub ref_start: usize,
+    pub ref_end: usize,
+    pub score: i32,
+    /// number of matching bases
+    pub match_len: usize,
+    /// number of alignment columns
+    pub block_len: usize,
+    /// mismatches and gap bases
+    pub nm: usize,
+    /// compressed cigar as (length, operation) with SAM operation codes
+    pub cigar: Vec<(u32, u8)>,
+}
+
+/// Aligns a chain: gaps between consecutive anchors are aligned globally, anchors are exact matches
+/// of k bases, and the alignment is extended beyond the first and last anchors over at most `max_ext` bases.
+/// `query` must be in the chain orientation.
+pub fn align_chain(
+    chain: &Chain,
+    k: usize,
+    query: &[u8],
+    reference: &[u8],
+    band: usize,
+    max_ext: usize,
+    scoring: &Scoring,
+) -> ChainAlignment {
+    let first = chain.anchors[0];
+    let (q0, r0) = (first.query_pos as usize, first.ref_pos as usize);
+    // left extension, on reversed prefixes
+    let left_len_q = q0.min(max_ext);
+    let left_len_r = r0.min(left_len_q + band);
+    let q_left: Vec<u8> = query[q0 - left_len_q..q0].iter().rev().copied().collect();
+    let r_left: Vec<u8> = reference[r0 - left_len_r..r0].iter().rev().copied().collect();
+    let left = align_extension(&q_left, &r_left, band, scoring);
+    let mut ops: Vec<u8> = left.ops.iter().rev().copied().collect();
+    let mut score = left.score;
+    let (query_start, ref_start) = (q0 - left.query_len, r0 - left.ref_len);
+    // anchors and gaps between them
+    let (mut qc, mut rc) = (q0, r0);
+    for anchor in chain.anchors.iter() {
+        let (qa, ra) = (anchor.query_pos as usize, anchor.ref_pos as usize);
+        if qa + k <= qc || ra + k <= rc {
+            continue;
+        }
+        if qa >= qc && ra >= rc {
+            let gap = align_global(&query[qc..qa], &reference[rc..ra], band, scoring);
+            score += gap.score;
+            ops.extend_from_slice(&gap.ops);
+            ops.extend(std::iter::repeat_n(CIGAR_MATCH, k));
+            score += k as i32 * scoring.match_score;
+            (qc, rc) = (qa + k, ra + k);
+        } else if qa + rc == ra + qc {
+            // overlapping anchor on the current diagonal: its end extends the match
+            let nb = qa + k - qc;
+            ops.extend(std::iter::repeat_n(CIGAR_MATCH, nb));
+            score += nb as i32 * scoring.match_score;
+            (qc, rc) = (qa + k, ra + k);
+        }
+    }
+    // right extension
+    let right_len_q = (query.len() - qc).min(max_ext);
+    let right_len_r = (reference.len() - rc).min(right_len_q + band);
+    let right = align_extension(&query[qc..qc + right_len_q], &reference[rc..rc + right_len_r], band, scoring);
+    ops.extend_from_slice(&right.ops);
+    score += right.score;
+    let (query_end, ref_end) = (qc + right.query_len, rc + right.ref_len);
+    // statistics and cigar
+    let (mut match_len, mut nm) = (0usize, 0usize);
+    let (mut qi, mut ri) = (query_start, ref_start);
+    let mut cigar: Vec<(u32, u8)> = Vec::new();
+    for op in ops.iter() {
+        match *op {
+            CIGAR_MATCH => {
+                if query[qi].eq_ignore_ascii_case(&reference[ri]) {
+                    match_len += 1;
+                } else {
+                    nm += 1;
+                }
+                qi += 1;
+                ri += 1;
+            }
+            CIGAR_INS => {
+                nm += 1;
+                qi += 1;
+            }
+            _ => {
+                nm += 1;
+                ri += 1;
+            }
+        }
+        match cigar.last_mut() {
+            Some((len, last)) if *last == *op => *len += 1,
+            _ => cigar.push((1, *op)),
+        }
+    }
+    ChainAlignment {
+        query_start,
+        query_end,
+        ref_start,
+        ref_end,
+        score,
+        match_len,
+        block_len: ops.len(),
+        nm,
+        cigar,
+    }
+} // end of align_chain
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// sequence of pseudo random bases
+    fn random_seq(len: usize, mut state: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                b"ACGT"[(state % 4) as usize]
+            })
+            .collect()
+    }
+
+    fn check_ops(aln: &DpAlignment) {
+        let nb_query = aln.ops.iter().filter(|op| **op != CIGAR_DEL).count();
+        let nb_ref = aln.ops.iter().filter(|op| **op != CIGAR_INS).count();
+        assert_eq!(nb_query, aln.query_len);
+        assert_eq!(nb_ref, aln.ref_len);
+    }
+
+    #[test]
+    fn extension_of_query_overhanging_reference() {
+        let reference = random_seq(50, 1);
+        let mut query = reference.clone();
+        query.extend(random_seq(300, 2));
+        let aln = align_extension(&query, &reference, 20, &Scoring::default());
+        check_ops(&aln);
+        assert_eq!((aln.query_len, aln.ref_len), (50, 50));
+        assert_eq!(aln.score, 100);
+    }
+
+    #[test]
+    fn extension_on_empty_reference() {
+        let query = random_seq(100, 3);
+        let aln = align_extension(&query, &[], 20, &Scoring::default());
+        assert_eq!((aln.score, aln.query_len, aln.ref_len), (0, 0, 0));
+        assert!(aln.ops.is_empty());
+        let aln = align_extension(&[], &query, 20, &Scoring::default());
+        assert_eq!((aln.score, aln.query_len, aln.ref_len), (0, 0, 0));
+    }
+
+    #[test]
+    fn global_alignment_with_gap() {
+        let reference = random_seq(200, 4);
+        let mut query = reference[..100].to_vec();
+        query.extend_from_slice(&reference[110..]);
+        let aln = align_global(&query, &reference, 5, &Scoring::default());
+        check_ops(&aln);
+        assert_eq!((aln.query_len, aln.ref_len), (190, 200));
+        let scoring = Scoring::default();
+        assert_eq!(aln.score, 190 * scoring.match_score - scoring.gap_open - 10 * scoring.gap_extend);
+        let aln = align_global(&[], &reference[..10], 5, &scoring);
+        assert_eq!(aln.ops, vec![CIGAR_DEL; 10]);
+    }
+}
@@ -0,0 +1,113 @@
+//! (w,k)-minimizers of DNA sequences.
+//!
+//! K-mers are 2-bit encoded (k <= 28), canonical (min of forward and reverse complement) and hashed
+//! with the invertible hash of minimap2, so a minimizer hash identifies its k-mer exactly.
+//! Windows do not cross ambiguous bases: the sketch restarts after each N.
+
+use std::collections::VecDeque;
+
+/// largest k-mer size, 2 bits by base must fit in 56 bits
+pub const MAX_KMER_SIZE: usize = 28;
+
+/// A selected k-mer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Minimizer {
+    /// hash of canonical k-mer
+    pub hash: u64,
+    /// start of k-mer in sequence
+    pub pos: u32,
+    /// true if canonical k-mer is the reverse complement of the k-mer read on the sequence
+    pub rev: bool,
+}
+
+/// 2 bits code of a base, 4 for non ACGT
+#[inline]
+pub fn base_code(b: u8) -> u8 {
+    match b {
+        b'A' | b'a' => 0,
+        b'C' | b'c' => 1,
+        b'G' | b'g' => 2,
+        b'T' | b't' | b'U' | b'u' => 3,
+        _ => 4,
+    }
+}
+
+/// invertible integer hash of minimap2 (hash64), restricted to mask
+#[inline]
+pub fn hash64(key: u64, mask: u64) -> u64 {
+    let mut key = (!key).wrapping_add(key << 21) & mask;
+    key ^= key >> 24;
+    key = (key.wrapping_add(key << 3)).wrapping_add(key << 8) & mask;
+    key ^= key >> 14;
+    key = (key.wrapping_add(key << 2)).wrapping_add(key << 4) & mask;
+    key ^= key >> 28;
+    key = key.wrapping_add(key << 31) & mask;
+    key
+}
+
+/// Computes the (w,k)-minimizers of seq: in each window of w consecutive k-mers the k-mer of smallest hash
+/// (leftmost on ties) is kept. Palindromic k-mers are skipped as their strand is undefined.
+/// A stretch of sequence between ambiguous bases too short to hold w k-mers still gives its smallest k-mer.
+pub fn minimizers(seq: &[u8], k: usize, w: usize) -> Vec<Minimizer> {
+    assert!(k > 0 && k <= MAX_KMER_SIZE, "kmer size must be in [1, {}]", MAX_KMER_SIZE);
+    assert!(w > 0, "window size must be > 0");
+    let mask: u64 = (1u64 << (2 * k)) - 1;
+    let shift = 2 * (k - 1) as u64;
+    let mut result: Vec<Minimizer> = Vec::with_capacity(seq.len() / w * 2 + 1);
+    // candidate k-mers of current window, increasing hashes
+    let mut window: VecDeque<Minimizer> = VecDeque::with_capacity(w);
+    let (mut fwd, mut rev) = (0u64, 0u64);
+    // number of bases since last ambiguous base, number of k-mers considered in this stretch
+    let (mut nb_bases, mut nb_kmers) = (0usize, 0usize);
+    //
+    let flush_short_stretch = |window: &VecDeque<Minimizer>, nb_kmers: usize, result: &mut Vec<Minimizer>| {
+        if nb_kmers > 0 && nb_kmers < w {
+            if let Some(m) = window.front() {
+                result.push(*m);
+            }
+        }
+    };
+    for (i, b) in seq.iter().enumerate() {
+        let c = base_code(*b);
+        if c > 3 {
+            flush_short_stretch(&window, nb_kmers, &mut result);
+            window.clear();
+            nb_bases = 0;
+            nb_kmers = 0;
+            continue;
+        }
+        fwd = ((fwd << 2) | c as u64) & mask;
+        rev = (rev >> 2) | ((3 - c as u64) << shift);
+        nb_bases += 1;
+        if nb_bases < k {
+            continue;
+        }
+        nb_kmers += 1;
+        let pos = (i + 1 - k) as u32;
+        if fwd != rev {
+            let (canonical, is_rev) = if fwd < rev { (fwd, false) } else { (rev, true) };
+            let m = Minimizer {
+                hash: hash64(canonical, mask),
+                pos,
+                rev: is_rev,
+            };
+            while window.back().is_some_and(|last| last.hash > m.hash) {
+                window.pop_back();
+            }
+            window.push_back(m);
+        }
+        // drop k-mers out of window [pos + 1 - w, pos]
+        while window.front().is_some_and(|first| first.pos + (w as u32) <= pos) {
+            window.pop_front();
+        }
+        if nb_kmers >= w {
+            if let Some(m) = window.front() {
+                if result.last().is_none_or(|last| last.pos != m.pos) {
+                    result.push(*m);
+                }
+            }
+        }
+    }
+    flush_short_stretch(&window, nb_kmers, &mut result);
+    result
+} // end of minimizers
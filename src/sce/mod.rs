@@ -0,0 +1,393 @@
+//! A seed-chain-extend aligner in Rust, following the stages of minimap2:
+//! - [minimizer] : (w,k)-minimizers of references and queries,
+//! - [chain] : minimizer index, anchors between query and references, chaining by dynamic programming,
+//! - [extend] : base level alignment of chains with adaptive banded dynamic programming.
+//!
+//! Each stage is public so it can be used and inspected on its own. [SceAligner] runs them all.
+
+pub mod chain;
+pub mod extend;
+pub mod minimizer;
+
+use chain::{chain_anchors, collect_anchors, Chain, MinimizerIndex};
+use extend::{align_chain, Scoring};
+use minimizer::minimizers;
+
+/// Parameters of all stages
+#[derive(Clone, Debug)]
+pub struct SceParams {
+    /// k-mer size of minimizers
+    pub k: usize,
+    /// window size of minimizers
+    pub w: usize,
+    /// minimizers occurring more often in references are not used as seeds
+    pub max_occ: usize,
+    /// largest distance between two consecutive anchors of a chain, on query and on reference
+    pub max_gap: usize,
+    /// largest difference between reference and query distances of two consecutive anchors
+    pub bandwidth: usize,
+    /// number of predecessors examined for each anchor while chaining
+    pub max_iter: usize,
+    /// minimal number of anchors in a chain
+    pub min_cnt: usize,
+    /// minimal chaining score
+    pub min_chain_score: i32,
+    /// computes base level alignments, if false only chains are reported
+    pub extend: bool,
+    /// band added to the length difference of segments in dynamic programming
+    pub dp_band: usize,
+    /// maximal length of extension beyond first and last anchors
+    pub max_ext: usize,
+    pub scoring: Scoring,
+    /// secondary hits must score at least this fraction of the best hit
+    pub secondary_ratio: f64,
+    /// maximal number of secondary hits reported
+    pub max_secondary: usize,
+}
+
+impl Default for SceParams {
+    /// values of minimap2 map-ont preset
+    fn default() -> Self {
+        SceParams {
+            k: 15,
+            w: 10,
+            max_occ: 500,
+            max_gap: 5000,
+            bandwidth: 500,
+            max_iter: 5000,
+            min_cnt: 3,
+            min_chain_score: 40,
+            extend: true,
+            dp_band: 100,
+            max_ext: 10000,
+            scoring: Scoring::default(),
+            secondary_ratio: 0.8,
+            max_secondary: 5,
+        }
+    }
+}
+
+impl SceParams {
+    /// parameters for a minimap2 preset name (map-ont, map-hifi, map-pb, asm5, asm10, asm20, ava-ont, ava-pb, sr)
+    pub fn from_preset(name: &str) -> Option<Self> {
+        let mut params = SceParams::default();
+        match name {
+            "map-ont" => {}
+            "map-hifi" => {
+                (params.k, params.w) = (19, 19);
+                params.scoring = Scoring { match_score: 1, mismatch: 4, gap_open: 6, gap_extend: 2 };
+            }
+            "map-pb" => {
+                (params.k, params.w) = (19, 10);
+            }
+            "asm5" | "asm10" | "asm20" => {
+                (params.k, params.w) = if name == "asm20" { (19, 10) } else { (19, 19) };
+                params.max_gap = 10000;
+                params.min_chain_score = 40;
+                params.scoring = match name {
+                    "asm5" => Scoring { match_score: 1, mismatch: 19, gap_open: 39, gap_extend: 3 },
+                    "asm10" => Scoring { match_score: 1, mismatch: 9, gap_open: 16, gap_extend: 2 },
+                    _ => Scoring { match_score: 1, mismatch: 4, gap_open: 6, gap_extend: 2 },
+                };
+            }
+            "ava-ont" | "ava-pb" => {
+                (params.k, params.w) = if name == "ava-ont" { (15, 5) } else { (19, 5) };
+                params.max_gap = 10000;
+                params.min_chain_score = 100;
+                params.extend = false;
+            }
+            "sr" => {
+                (params.k, params.w) = (21, 11);
+                params.max_gap = 100;
+                params.bandwidth = 100;
+                params.min_cnt = 2;
+                params.min_chain_score = 25;
+                params.scoring = Scoring { match_score: 2, mismatch: 8, gap_open: 12, gap_extend: 2 };
+            }
+            _ => return None,
+        }
+        Some(params)
+    }
+} // end of impl SceParams
+
+/// A query alignment found by the engine. Query coordinates are on the forward query strand,
+/// cigar is in reference orientation as in SAM.
+#[derive(Clone, Debug)]
+pub struct SceHit {
+    pub ref_id: usize,
+    pub rev: bool,
+    pub query_start: usize,
+    pub query_end: usize,
+    pub ref_start: usize,
+    pub ref_end: usize,
+    /// number of matching bases
+    pub match_len: usize,
+    /// alignment length (mismatches and gaps included)
+    pub block_len: usize,
+    pub chain_score: f64,
+    pub nb_anchors: usize,
+    /// alignment score, chain score if not extended
+    pub score: i32,
+    /// mismatches and gap bases, None if not extended
+    pub nm: Option<usize>,
+    pub cigar: Option<Vec<(u32, u8)>>,
+    pub mapq: u32,
+    pub is_primary: bool,
+    pub is_supplementary: bool,
+}
+
+/// The whole seed-chain-extend pipeline over an index of references
+pub struct SceAligner {
+    params: SceParams,
+    index: MinimizerIndex,
+}
+
+fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|b| match b {
+            b'A' | b'a' => b'T',
+            b'C' | b'c' => b'G',
+            b'G' | b'g' => b'C',
+            b'T' | b't' | b'U' | b'u' => b'A',
+            _ => b'N',
+        })
+        .collect()
+}
+
+impl SceAligner {
+    /// indexes references with the minimizer parameters of params
+    pub fn new(params: SceParams, names: Vec<String>, seqs: Vec<Vec<u8>>) -> Self {
+        let index = MinimizerIndex::new(params.k, params.w, names, seqs);
+        SceAligner { params, index }
+    }
+
+    pub fn get_params(&self) -> &SceParams {
+        &self.params
+    }
+
+    pub fn get_index(&self) -> &MinimizerIndex {
+        &self.index
+    }
+
+    /// seeds and chains a query, chains are sorted by decreasing score
+    pub fn chain(&self, query: &[u8]) -> Vec<Chain> {
+        let query_minimizers = minimizers(query, self.params.k, self.params.w);
+        let anchors = collect_anchors(&self.index, &query_minimizers, query.len(), self.params.max_occ);
+        let mut chains = chain_anchors(&anchors, self.params.k, &self.params);
+        chains.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+        chains
+    }
+
+    /// maps a query: seeding, chaining, extension of chains and selection of primary and secondary hits
+    pub fn map(&self, query: &[u8]) -> Vec<SceHit> {
+        let k = self.params.k;
+        let chains = self.chain(query);
+        if chains.is_empty() {
+            return Vec::new();
+        }
+        let query_rc = if chains.iter().any(|c| c.rev) {
+            reverse_complement(query)
+        } else {
+            Vec::new()
+        };
+        let mut hits: Vec<SceHit> = chains
+            .iter()
+            .map(|chain| {
+                let oriented = if chain.rev { &query_rc } else { query };
+                let (mut hit, q_start, q_end) = if self.params.extend {
+                    let aln = align_chain(
+                        chain,
+                        k,
+                        oriented,
+                        self.index.get_seq(chain.ref_id as usize),
+                        self.params.dp_band,
+                        self.params.max_ext,
+                        &self.params.scoring,
+                    );
+                    let hit = SceHit {
+                        ref_id: chain.ref_id as usize,
+                        rev: chain.rev,
+                        query_start: 0,
+                        query_end: 0,
+                        ref_start: aln.ref_start,
+                        ref_end: aln.ref_end,
+                        match_len: aln.match_len,
+                        block_len: aln.block_len,
+                        chain_score: chain.score,
+                        nb_anchors: chain.anchors.len(),
+                        score: aln.score,
+                        nm: Some(aln.nm),
+                        cigar: Some(aln.cigar),
+                        mapq: 0,
+                        is_primary: false,
+                        is_supplementary: false,
+                    };
+                    (hit, aln.query_start, aln.query_end)
+                } else {
+                    let (q_start, q_end) = chain.query_span(k);
+                    let (ref_start, ref_end) = chain.ref_span(k);
+                    let hit = SceHit {
+                        ref_id: chain.ref_id as usize,
+                        rev: chain.rev,
+                        query_start: 0,
+                        query_end: 0,
+                        ref_start,
+                        ref_end,
+                        match_len: anchor_coverage(chain, k),
+                        block_len: (q_end - q_start).max(ref_end - ref_start),
+                        chain_score: chain.score,
+                        nb_anchors: chain.anchors.len(),
+                        score: chain.score.round() as i32,
+                        nm: None,
+                        cigar: None,
+                        mapq: 0,
+                        is_primary: false,
+                        is_supplementary: false,
+                    };
+                    (hit, q_start, q_end)
+                };
+                // back to forward strand query coordinates
+                if chain.rev {
+                    (hit.query_start, hit.query_end) = (query.len() - q_end, query.len() - q_start);
+                } else {
+                    (hit.query_start, hit.query_end) = (q_start, q_end);
+                }
+                hit
+            })
+            .collect();
+        hits.sort_by_key(|h| std::cmp::Reverse(h.score));
+        select_hits(hits, &self.params)
+    } // end of map
+} // end of impl SceAligner
+
+/// number of query bases covered by the anchors of a chain
+fn anchor_coverage(chain: &Chain, k: usize) -> usize {
+    let mut covered = 0;
+    let mut end = 0;
+    for anchor in chain.anchors.iter() {
+        let start = (anchor.query_pos as usize).max(end);
+        let anchor_end = anchor.query_pos as usize + k;
+        if anchor_end > start {
+            covered += anchor_end - start;
+        }
+        end = end.max(anchor_end);
+    }
+    covered
+}
+
+/// Marks hits (sorted by decreasing score) as minimap2 does: a hit overlapping a better primary hit on
+/// at least half of its query span is secondary, others are primary (supplementary if not the best).
+/// Secondary hits are kept if they score at least secondary_ratio of their primary, at most max_secondary.
+/// Mapping quality of primary hits decreases with the score of their best secondary.
+fn select_hits(hits: Vec<SceHit>, params: &SceParams) -> Vec<SceHit> {
+    let mut selected: Vec<SceHit> = Vec::with_capacity(hits.len());
+    // for each primary, index in selected and best secondary score
+    let mut primaries: Vec<(usize, Option<i32>)> = Vec::new();
+    let mut nb_secondary = 0;
+    for mut hit in hits {
+        let parent = primaries.iter_mut().find(|(p, _)| {
+            let primary = &selected[*p];
+            let overlap = hit.query_end.min(primary.query_end).saturating_sub(hit.query_start.max(primary.query_start));
+            2 * overlap >= hit.query_end - hit.query_start
+        });
+        match parent {
+            None => {
+                hit.is_primary = true;
+                hit.is_supplementary = !primaries.is_empty();
+                primaries.push((selected.len(), None));
+                selected.push(hit);
+            }
+            Some((p, best_secondary)) => {
+                if best_secondary.is_none() {
+                    *best_secondary = Some(hit.score);
+                }
+                let primary_score = selected[*p].score;
+                if nb_secondary < params.max_secondary
+                    && hit.score as f64 >= params.secondary_ratio * primary_score as f64
+                {
+                    selected.push(hit);
+                    nb_secondary += 1;
+                }
+            }
+        }
+    }
+    for (p, best_secondary) in primaries {
+        let hit = &mut selected[p];
+        let s1 = hit.score.max(1) as f64;
+        let s2 = best_secondary.unwrap_or(0).max(0) as f64;
+        let mapq = 40. * (1. - s2 / s1) * (hit.nb_anchors as f64 / 10.).min(1.) * hit.chain_score.max(1.).ln();
+        hit.mapq = mapq.clamp(0., 60.) as u32;
+    }
+    selected
+} // end of select_hits
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_seq(len: usize, mut state: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                b"ACGT"[(state % 4) as usize]
+            })
+            .collect()
+    }
+
+    /// checks the cigar against the spans of the hit
+    fn check_hit(hit: &SceHit) {
+        let cigar = hit.cigar.as_ref().unwrap();
+        let query_span: u32 = cigar.iter().filter(|(_, op)| *op != extend::CIGAR_DEL).map(|(len, _)| len).sum();
+        let ref_span: u32 = cigar.iter().filter(|(_, op)| *op != extend::CIGAR_INS).map(|(len, _)| len).sum();
+        assert_eq!(query_span as usize, hit.query_end - hit.query_start);
+        assert_eq!(ref_span as usize, hit.ref_end - hit.ref_start);
+    }
+
+    /// a query overhanging the start or the end of the reference, on both strands
+    #[test]
+    fn query_overhanging_reference_ends() {
+        let reference = random_seq(3000, 11);
+        let aligner = SceAligner::new(SceParams::default(), vec![String::from("r")], vec![reference.clone()]);
+        let overhang = random_seq(300, 12);
+        // overhang before the reference start
+        let mut left = overhang.clone();
+        left.extend_from_slice(&reference[..2000]);
+        // overhang after the reference end
+        let mut right = reference[1000..].to_vec();
+        right.extend_from_slice(&overhang);
+        for (query, ref_span) in [(left, (0, 2000)), (right, (1000, 3000))] {
+            for rev in [false, true] {
+                let query = if rev { reverse_complement(&query) } else { query.clone() };
+                let hits = aligner.map(&query);
+                assert!(!hits.is_empty());
+                let hit = &hits[0];
+                assert!(hit.is_primary);
+                assert_eq!(hit.rev, rev);
+                assert_eq!((hit.ref_start, hit.ref_end), ref_span);
+                assert_eq!(hit.query_end - hit.query_start, 2000);
+                assert_eq!(hit.match_len, 2000);
+                check_hit(hit);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_and_short_references() {
+        let query = random_seq(1000, 13);
+        let names = vec![String::from("empty"), String::from("short")];
+        let aligner = SceAligner::new(SceParams::default(), names, vec![Vec::new(), query[..10].to_vec()]);
+        assert!(aligner.map(&query).is_empty());
+        assert!(aligner.map(&[]).is_empty());
+        // a reference shorter than the query on both sides
+        let reference = query[200..400].to_vec();
+        let aligner = SceAligner::new(SceParams::default(), vec![String::from("r")], vec![reference]);
+        let hits = aligner.map(&query);
+        assert!(!hits.is_empty());
+        assert_eq!((hits[0].ref_start, hits[0].ref_end), (0, 200));
+        assert_eq!((hits[0].query_start, hits[0].query_end), (200, 400));
+        check_hit(&hits[0]);
+    }
+}
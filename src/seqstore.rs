@@ -0,0 +1,554 @@
+//! A compressed store of database sequences, kept next to the HNSW dump and indexed by data id.
+//!
+//! Two files are written in the database directory:
+//! - `seqstore.bin` : concatenated packed sequences.
+//! - `seqstore.idx` : a magic header then one fixed size record by sequence (offset, number of bases, encoding).
+//!
+//! Sequences made only of ACGT are packed with 2 bits by base, other sequences use 4 bits by base
+//! with IUPAC codes so ambiguous bases survive a round trip. The i-th sequence pushed in the store
+//! is the sequence of data id i in the HNSW graph and in the SeqDict.
+//!
+//! [DatabaseSequences] reads sequences of a database on demand from its store, or from the FASTA files
+//! recorded at build time when there is no store.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use gsearch::utils::SeqDict;
+use needletail::{parse_fastx_file, parse_fastx_reader, Sequence};
+
+/// name of packed sequence file in database directory
+pub const SEQSTORE_BIN: &str = "seqstore.bin";
+/// name of index file in database directory
+pub const SEQSTORE_IDX: &str = "seqstore.idx";
+
+const SEQSTORE_MAGIC: &[u8; 8] = b"ADASSEQ1";
+// offset (u64), number of bases (u64), encoding (u8)
+const IDX_RECORD_SIZE: usize = 17;
+
+/// IUPAC codes indexed by their 4 bits code (bit 0 = A, bit 1 = C, bit 2 = G, bit 3 = T)
+const IUPAC_FROM_CODE: &[u8; 16] = b"-ACMGRSVTWYHKDBN";
+
+/// Packing used for one sequence
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeqEncoding {
+    /// 4 bases by byte, only ACGT
+    TwoBit,
+    /// 2 bases by byte, IUPAC codes
+    FourBit,
+}
+
+impl SeqEncoding {
+    fn to_u8(self) -> u8 {
+        match self {
+            SeqEncoding::TwoBit => 2,
+            SeqEncoding::FourBit => 4,
+        }
+    }
+
+    fn from_u8(v: u8) -> Option<SeqEncoding> {
+        match v {
+            2 => Some(SeqEncoding::TwoBit),
+            4 => Some(SeqEncoding::FourBit),
+            _ => None,
+        }
+    }
+} // end of impl SeqEncoding
+
+fn base_to_2bit(b: u8) -> Option<u8> {
+    match b {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' | b'U' | b'u' => Some(3),
+        _ => None,
+    }
+}
+
+fn base_to_4bit(b: u8) -> u8 {
+    match b.to_ascii_uppercase() {
+        b'A' => 1,
+        b'C' => 2,
+        b'M' => 3,
+        b'G' => 4,
+        b'R' => 5,
+        b'S' => 6,
+        b'V' => 7,
+        b'T' | b'U' => 8,
+        b'W' => 9,
+        b'Y' => 10,
+        b'H' => 11,
+        b'K' => 12,
+        b'D' => 13,
+        b'B' => 14,
+        b'-' => 0,
+        _ => 15,
+    }
+}
+
+/// packs a sequence, choosing 2 bits encoding when possible
+pub fn pack_sequence(seq: &[u8]) -> (SeqEncoding, Vec<u8>) {
+    if seq.iter().all(|b| base_to_2bit(*b).is_some()) {
+        let mut packed = vec![0u8; seq.len().div_ceil(4)];
+        for (i, b) in seq.iter().enumerate() {
+            packed[i / 4] |= base_to_2bit(*b).unwrap() << (2 * (i % 4));
+        }
+        (SeqEncoding::TwoBit, packed)
+    } else {
+        let mut packed = vec![0u8; seq.len().div_ceil(2)];
+        for (i, b) in seq.iter().enumerate() {
+            packed[i / 2] |= base_to_4bit(*b) << (4 * (i % 2));
+        }
+        (SeqEncoding::FourBit, packed)
+    }
+} // end of pack_sequence
+
+/// unpacks `nb_bases` bases, returns upper case ASCII
+pub fn unpack_sequence(encoding: SeqEncoding, packed: &[u8], nb_bases: usize) -> Vec<u8> {
+    match encoding {
+        SeqEncoding::TwoBit => (0..nb_bases)
+            .map(|i| b"ACGT"[((packed[i / 4] >> (2 * (i % 4))) & 3) as usize])
+            .collect(),
+        SeqEncoding::FourBit => (0..nb_bases)
+            .map(|i| IUPAC_FROM_CODE[((packed[i / 2] >> (4 * (i % 2))) & 15) as usize])
+            .collect(),
+    }
+} // end of unpack_sequence
+
+fn packed_size(encoding: SeqEncoding, nb_bases: usize) -> usize {
+    match encoding {
+        SeqEncoding::TwoBit => nb_bases.div_ceil(4),
+        SeqEncoding::FourBit => nb_bases.div_ceil(2),
+    }
+}
+
+/// returns true if database directory contains a sequence store
+pub fn seqstore_exists(dir: &Path) -> bool {
+    dir.join(SEQSTORE_BIN).exists() && dir.join(SEQSTORE_IDX).exists()
+}
+
+/// Writes sequences to the store. Sequences must be pushed in data id order.
+pub struct SeqStoreWriter {
+    bin: BufWriter<File>,
+    idx: BufWriter<File>,
+    /// offset of next sequence in bin file
+    offset: u64,
+    /// number of sequences in store
+    nb_seqs: usize,
+}
+
+impl SeqStoreWriter {
+    /// creates a new empty store in dir, erasing a previous one
+    pub fn create(dir: &Path) -> std::io::Result<Self> {
+        let bin = File::create(dir.join(SEQSTORE_BIN))?;
+        let mut idx = File::create(dir.join(SEQSTORE_IDX))?;
+        idx.write_all(SEQSTORE_MAGIC)?;
+        Ok(SeqStoreWriter {
+            bin: BufWriter::new(bin),
+            idx: BufWriter::new(idx),
+            offset: 0,
+            nb_seqs: 0,
+        })
+    }
+
+    /// opens an existing store in dir to append new sequences after the current ones
+    pub fn append(dir: &Path) -> std::io::Result<Self> {
+        let idx_path = dir.join(SEQSTORE_IDX);
+        check_magic(&idx_path)?;
+        let idx_len = std::fs::metadata(&idx_path)?.len() as usize;
+        if !(idx_len - SEQSTORE_MAGIC.len()).is_multiple_of(IDX_RECORD_SIZE) {
+            return Err(Error::new(ErrorKind::InvalidData, "truncated sequence store index"));
+        }
+        let bin = OpenOptions::new().append(true).open(dir.join(SEQSTORE_BIN))?;
+        let offset = bin.metadata()?.len();
+        let idx = OpenOptions::new().append(true).open(&idx_path)?;
+        Ok(SeqStoreWriter {
+            bin: BufWriter::new(bin),
+            idx: BufWriter::new(idx),
+            offset,
+            nb_seqs: (idx_len - SEQSTORE_MAGIC.len()) / IDX_RECORD_SIZE,
+        })
+    }
+
+    /// number of sequences in the store, i.e the data id of the next pushed sequence
+    pub fn get_nb_seqs(&self) -> usize {
+        self.nb_seqs
+    }
+
+    /// packs and writes a sequence, returns its data id
+    pub fn push(&mut self, seq: &[u8]) -> std::io::Result<usize> {
+        let (encoding, packed) = pack_sequence(seq);
+        self.bin.write_all(&packed)?;
+        self.idx.write_all(&self.offset.to_le_bytes())?;
+        self.idx.write_all(&(seq.len() as u64).to_le_bytes())?;
+        self.idx.write_all(&[encoding.to_u8()])?;
+        self.offset += packed.len() as u64;
+        self.nb_seqs += 1;
+        Ok(self.nb_seqs - 1)
+    }
+
+    /// flushes both files
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.bin.flush()?;
+        self.idx.flush()
+    }
+} // end of impl SeqStoreWriter
+
+fn check_magic(idx_path: &Path) -> std::io::Result<()> {
+    let mut magic = [0u8; 8];
+    File::open(idx_path)?.read_exact(&mut magic)?;
+    if &magic != SEQSTORE_MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "not an adas sequence store index"));
+    }
+    Ok(())
+}
+
+/// Read access to the store. The index is loaded in memory, sequences are read on demand.
+pub struct SeqStore {
+    /// (offset, number of bases, encoding) by data id
+    index: Vec<(u64, usize, SeqEncoding)>,
+    bin: File,
+}
+
+impl SeqStore {
+    /// opens the store of a database directory
+    pub fn open(dir: &Path) -> std::io::Result<Self> {
+        let idx_path = dir.join(SEQSTORE_IDX);
+        let mut idx_data = Vec::new();
+        File::open(&idx_path)?.read_to_end(&mut idx_data)?;
+        if idx_data.len() < SEQSTORE_MAGIC.len() || &idx_data[..SEQSTORE_MAGIC.len()] != SEQSTORE_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not an adas sequence store index"));
+        }
+        let records = &idx_data[SEQSTORE_MAGIC.len()..];
+        if !records.len().is_multiple_of(IDX_RECORD_SIZE) {
+            return Err(Error::new(ErrorKind::InvalidData, "truncated sequence store index"));
+        }
+        let mut index = Vec::with_capacity(records.len() / IDX_RECORD_SIZE);
+        for record in records.chunks_exact(IDX_RECORD_SIZE) {
+            let offset = u64::from_le_bytes(record[0..8].try_into().unwrap());
+            let nb_bases = u64::from_le_bytes(record[8..16].try_into().unwrap()) as usize;
+            let encoding = SeqEncoding::from_u8(record[16])
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "bad sequence encoding in store index"))?;
+            index.push((offset, nb_bases, encoding));
+        }
+        let bin = File::open(dir.join(SEQSTORE_BIN))?;
+        Ok(SeqStore { index, bin })
+    }
+
+    /// number of sequences in the store
+    pub fn get_nb_seqs(&self) -> usize {
+        self.index.len()
+    }
+
+    /// length of sequence of data id d_id
+    pub fn get_len(&self, d_id: usize) -> Option<usize> {
+        self.index.get(d_id).map(|r| r.1)
+    }
+
+    /// returns the sequence of data id `d_id`, in upper case ASCII
+    pub fn get(&self, d_id: usize) -> std::io::Result<Vec<u8>> {
+        let (offset, nb_bases, encoding) = *self
+            .index
+            .get(d_id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no sequence for data id {}", d_id)))?;
+        let mut packed = vec![0u8; packed_size(encoding, nb_bases)];
+        self.bin.read_exact_at(&mut packed, offset)?;
+        Ok(unpack_sequence(encoding, &packed, nb_bases))
+    }
+} // end of impl SeqStore
+
+/// Where the sequence of a data id is read from when there is no store
+#[derive(Clone, Copy)]
+enum FastaLocation {
+    /// file index and byte offset of the header line of the record, in an uncompressed FASTA file
+    Offset(usize, u64),
+    /// sequence of a compressed file, kept in memory as such files cannot be read from an offset
+    InMemory(usize),
+}
+
+/// Database sequences read on demand, by data id: from the sequence store of the database, or else through
+/// an index of record offsets in the FASTA files recorded at build time (or in a given reference).
+/// Only the sequences asked for are decoded, memory does not grow with the size of the database
+/// (except for compressed FASTA files, whose sequences are kept in memory).
+pub struct DatabaseSequences {
+    source: SequenceSource,
+}
+
+enum SequenceSource {
+    Store(SeqStore),
+    Fasta {
+        paths: Vec<PathBuf>,
+        /// location by data id, None if the sequence was not found
+        locations: Vec<Option<FastaLocation>>,
+        in_memory: Vec<Vec<u8>>,
+    },
+}
+
+/// header line without '>' and line end: the record id as needletail gives it, recorded in the SeqDict
+fn header_id(line: &[u8]) -> &[u8] {
+    let line = line.strip_prefix(b">").unwrap_or(line);
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// path of a recorded FASTA file, looked up as is and then relatively to the database directory
+fn find_fasta(path: &str, database_dirpath: &Path) -> PathBuf {
+    let mut filepath = PathBuf::from(path);
+    if !filepath.exists() {
+        if let Some(fname) = filepath.file_name() {
+            filepath = database_dirpath.join(fname);
+        }
+    }
+    filepath
+}
+
+impl DatabaseSequences {
+    /// Sequences are read from `reference` if given, else from the database sequence store if any,
+    /// else from the FASTA paths recorded in the SeqDict. FASTA files are scanned once to index record offsets.
+    pub fn open(seqdict: &SeqDict, database_dirpath: &Path, reference: Option<&str>) -> Self {
+        if reference.is_none() && seqstore_exists(database_dirpath) {
+            match SeqStore::open(database_dirpath) {
+                Ok(seqstore) => {
+                    return DatabaseSequences {
+                        source: SequenceSource::Store(seqstore),
+                    }
+                }
+                Err(e) => log::error!("cannot open sequence store : {:?}", e),
+            }
+        }
+        // group data ids by file, then by fasta id
+        let mut by_file: HashMap<String, HashMap<String, usize>> = HashMap::new();
+        for (d_id, item) in seqdict.0.iter().enumerate() {
+            let path = match reference {
+                Some(reference) => reference.to_string(),
+                None => item.get_id().get_path().clone(),
+            };
+            by_file
+                .entry(path)
+                .or_default()
+                .insert(item.get_id().get_fasta_id().clone(), d_id);
+        }
+        let mut paths = Vec::with_capacity(by_file.len());
+        let mut locations: Vec<Option<FastaLocation>> = vec![None; seqdict.0.len()];
+        let mut in_memory = Vec::new();
+        for (path, ids) in by_file.iter() {
+            let filepath = find_fasta(path, database_dirpath);
+            let file_index = paths.len();
+            paths.push(filepath.clone());
+            let indexed = index_fasta_offsets(&filepath, |id, offset| {
+                if let Some(d_id) = std::str::from_utf8(id).ok().and_then(|id| ids.get(id)) {
+                    locations[*d_id] = Some(FastaLocation::Offset(file_index, offset));
+                }
+            });
+            match indexed {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => {
+                    log::error!("cannot read database sequences from {} : {:?}", path, e);
+                    continue;
+                }
+            }
+            // not a plain FASTA file (compressed): its sequences are kept in memory
+            log::info!("{} cannot be read from offsets, its sequences are kept in memory", filepath.display());
+            let mut reader = match parse_fastx_file(&filepath) {
+                Ok(reader) => reader,
+                Err(e) => {
+                    log::error!("cannot read database sequences from {} : {:?}", path, e);
+                    continue;
+                }
+            };
+            while let Some(record) = reader.next() {
+                let seqrec = record.expect("Invalid record");
+                let seq_id = String::from_utf8_lossy(seqrec.id()).into_owned();
+                if let Some(d_id) = ids.get(&seq_id) {
+                    locations[*d_id] = Some(FastaLocation::InMemory(in_memory.len()));
+                    in_memory.push(seqrec.normalize(true).into_owned());
+                }
+            }
+        }
+        DatabaseSequences {
+            source: SequenceSource::Fasta {
+                paths,
+                locations,
+                in_memory,
+            },
+        }
+    } // end of open
+
+    /// number of data ids whose sequence cannot be found
+    pub fn get_nb_missing(&self, nb_seqs: usize) -> usize {
+        match &self.source {
+            SequenceSource::Store(seqstore) => nb_seqs.saturating_sub(seqstore.get_nb_seqs()),
+            SequenceSource::Fasta { locations, .. } => locations.iter().filter(|l| l.is_none()).count(),
+        }
+    }
+
+    /// sequence of data id `d_id`, normalized as at build time (upper case, IUPAC codes kept), None if not found
+    pub fn get(&self, d_id: usize) -> Option<Vec<u8>> {
+        match &self.source {
+            SequenceSource::Store(seqstore) => seqstore.get(d_id).ok(),
+            SequenceSource::Fasta {
+                paths,
+                locations,
+                in_memory,
+            } => match (*locations.get(d_id)?)? {
+                FastaLocation::InMemory(i) => Some(in_memory[i].clone()),
+                FastaLocation::Offset(file_index, offset) => {
+                    let read_record = || -> Result<Vec<u8>, String> {
+                        let mut file = File::open(&paths[file_index]).map_err(|e| e.to_string())?;
+                        file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+                        let mut reader = parse_fastx_reader(file).map_err(|e| format!("{:?}", e))?;
+                        let record = reader.next().ok_or("no record at offset")?.map_err(|e| format!("{:?}", e))?;
+                        Ok(record.normalize(true).into_owned())
+                    };
+                    match read_record() {
+                        Ok(seq) => Some(seq),
+                        Err(e) => {
+                            log::error!("cannot read sequence of data id {} in {} : {}", d_id, paths[file_index].display(), e);
+                            None
+                        }
+                    }
+                }
+            },
+        }
+    } // end of get
+} // end of impl DatabaseSequences
+
+/// Calls `found` with the id and byte offset of the header of each record of a plain FASTA file.
+/// Returns false, without calling `found`, if the file does not start with a FASTA header (e.g. compressed).
+fn index_fasta_offsets<F>(path: &Path, mut found: F) -> std::io::Result<bool>
+where
+    F: FnMut(&[u8], u64),
+{
+    let mut reader = BufReader::new(File::open(path)?);
+    if reader.fill_buf()?.first() != Some(&b'>') {
+        return Ok(false);
+    }
+    let mut line = Vec::new();
+    let mut offset = 0u64;
+    loop {
+        line.clear();
+        let nb_read = reader.read_until(b'\n', &mut line)?;
+        if nb_read == 0 {
+            break;
+        }
+        if line.first() == Some(&b'>') {
+            found(header_id(&line), offset);
+        }
+        offset += nb_read as u64;
+    }
+    Ok(true)
+} // end of index_fasta_offsets
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gsearch::utils::idsketch::{Id, ItemDict};
+
+    fn seqdict_of(path: &str, ids: &[&str]) -> SeqDict {
+        SeqDict(ids.iter().map(|id| ItemDict::new(Id::new(path, id), 0)).collect())
+    }
+
+    #[test]
+    fn packing_round_trip() {
+        // all lengths around byte boundaries, for both encodings
+        for len in 0..12 {
+            let acgt: Vec<u8> = (0..len).map(|i| b"ACGT"[(i * 7 + i / 3) % 4]).collect();
+            let (encoding, packed) = pack_sequence(&acgt);
+            assert_eq!(encoding, SeqEncoding::TwoBit);
+            assert_eq!(packed.len(), len.div_ceil(4));
+            assert_eq!(unpack_sequence(encoding, &packed, len), acgt);
+            let iupac: Vec<u8> = (0..len).map(|i| b"ACGTNRYSWKMBDHV-"[(i * 5) % 16]).collect();
+            let (encoding, packed) = pack_sequence(&iupac);
+            if len > 0 && iupac.iter().any(|b| !b"ACGT".contains(b)) {
+                assert_eq!(encoding, SeqEncoding::FourBit);
+                assert_eq!(packed.len(), len.div_ceil(2));
+            }
+            assert_eq!(unpack_sequence(encoding, &packed, len), iupac);
+        }
+        // lower case and U are stored as upper case and T, unknown characters as N
+        let (encoding, packed) = pack_sequence(b"acgu");
+        assert_eq!(unpack_sequence(encoding, &packed, 4), b"ACGT".to_vec());
+        let (encoding, packed) = pack_sequence(b"ACrX");
+        assert_eq!(unpack_sequence(encoding, &packed, 4), b"ACRN".to_vec());
+    }
+
+    #[test]
+    fn store_round_trip_and_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let first: Vec<&[u8]> = vec![b"ACGTACGTA", b"", b"ACGTNNRYAC", b"T"];
+        let mut writer = SeqStoreWriter::create(dir.path()).unwrap();
+        for (d_id, seq) in first.iter().enumerate() {
+            assert_eq!(writer.push(seq).unwrap(), d_id);
+        }
+        writer.finish().unwrap();
+        assert!(seqstore_exists(dir.path()));
+        // a magic header then one 17 bytes record (offset, number of bases, encoding) by sequence
+        let idx = std::fs::read(dir.path().join(SEQSTORE_IDX)).unwrap();
+        assert_eq!(idx.len(), SEQSTORE_MAGIC.len() + first.len() * IDX_RECORD_SIZE);
+        assert_eq!(&idx[..8], SEQSTORE_MAGIC);
+        let records: Vec<(u64, u64, u8)> = idx[8..]
+            .chunks_exact(IDX_RECORD_SIZE)
+            .map(|r| {
+                let offset = u64::from_le_bytes(r[0..8].try_into().unwrap());
+                let nb_bases = u64::from_le_bytes(r[8..16].try_into().unwrap());
+                (offset, nb_bases, r[16])
+            })
+            .collect();
+        assert_eq!(records, vec![(0, 9, 2), (3, 0, 2), (3, 10, 4), (8, 1, 2)]);
+        assert_eq!(std::fs::metadata(dir.path().join(SEQSTORE_BIN)).unwrap().len(), 9);
+        // appending goes on after the current sequences
+        let mut writer = SeqStoreWriter::append(dir.path()).unwrap();
+        assert_eq!(writer.get_nb_seqs(), first.len());
+        assert_eq!(writer.push(b"GGCCA").unwrap(), 4);
+        assert_eq!(writer.push(b"ACGTRYKM").unwrap(), 5);
+        writer.finish().unwrap();
+        let store = SeqStore::open(dir.path()).unwrap();
+        assert_eq!(store.get_nb_seqs(), 6);
+        for (d_id, seq) in first.iter().enumerate() {
+            assert_eq!(store.get(d_id).unwrap(), seq.to_vec());
+            assert_eq!(store.get_len(d_id), Some(seq.len()));
+        }
+        assert_eq!(store.get(4).unwrap(), b"GGCCA".to_vec());
+        assert_eq!(store.get(5).unwrap(), b"ACGTRYKM".to_vec());
+        assert!(store.get(6).is_err());
+        assert_eq!(store.get_len(6), None);
+    }
+
+    #[test]
+    fn bad_index_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = SeqStoreWriter::create(dir.path()).unwrap();
+        writer.push(b"ACGT").unwrap();
+        writer.finish().unwrap();
+        let idx_path = dir.path().join(SEQSTORE_IDX);
+        let mut idx = std::fs::read(&idx_path).unwrap();
+        idx.pop();
+        std::fs::write(&idx_path, &idx).unwrap();
+        assert!(SeqStore::open(dir.path()).is_err());
+        assert!(SeqStoreWriter::append(dir.path()).is_err());
+        idx[0] = b'X';
+        std::fs::write(&idx_path, &idx).unwrap();
+        assert!(SeqStore::open(dir.path()).is_err());
+        assert!(SeqStoreWriter::append(dir.path()).is_err());
+    }
+
+    #[test]
+    fn fasta_sequences_on_demand() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.fa");
+        // multi-line records, descriptions, CRLF line ends and lower case bases
+        std::fs::write(&path, ">s1 first\nACGT\nacgt\n>s2\r\nGGGG\r\nTT\r\n>s3 not in database\nAAAA\n>s4\nCCCCNNRY\n").unwrap();
+        let seqdict = seqdict_of(path.to_str().unwrap(), &["s4", "s1 first", "s2", "absent"]);
+        let refseqs = DatabaseSequences::open(&seqdict, dir.path(), None);
+        assert_eq!(refseqs.get_nb_missing(4), 1);
+        assert_eq!(refseqs.get(0).unwrap(), b"CCCCNNRY".to_vec());
+        assert_eq!(refseqs.get(1).unwrap(), b"ACGTACGT".to_vec());
+        assert_eq!(refseqs.get(2).unwrap(), b"GGGGTT".to_vec());
+        assert!(refseqs.get(3).is_none());
+        assert!(refseqs.get(4).is_none());
+    }
+}
@@ -0,0 +1,465 @@
+//! Compact integer codes of sketches.
+//!
+//! Sketch values (f64) are only compared for equality, so each one can be replaced by a hash code of its bits:
+//! a u32 or u16, or b bits (b = 8, 4, 2 or 1) packed in u64 words, for an index 2 to 64 times smaller.
+//! Two different values get the same code with probability 2^-b, distances are corrected for these collisions:
+//! with m the fraction of differing codes, the estimated distance is m / (1 - 2^-b).
+//!
+//! The code of a database is written in [SKETCH_CODE_FILE], a database without it has f64 signatures.
+//! [SketchIndex] holds the HNSW of any code and is searched with f64 signatures, encoded on the fly.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use gsearch::utils::dumpload::dumpall;
+use gsearch::utils::parameters::ProcessingParams;
+use gsearch::utils::SeqDict;
+use hnsw_rs::hnswio::HnswIo;
+use hnsw_rs::prelude::*;
+
+/// file of the database giving its sketch code
+pub const SKETCH_CODE_FILE: &str = "sketchcode.txt";
+
+/// How sketch values are stored in the HNSW
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SketchCode {
+    /// values as computed, Hamming distance on f64
+    F64,
+    /// 32 bits hash codes
+    U32,
+    /// 16 bits hash codes
+    U16,
+    /// b bits hash codes packed in u64 words, b in 8, 4, 2, 1
+    Packed(u32),
+}
+
+impl SketchCode {
+    /// number of bits by sketch value
+    pub fn get_bits(&self) -> u32 {
+        match self {
+            SketchCode::F64 => 64,
+            SketchCode::U32 => 32,
+            SketchCode::U16 => 16,
+            SketchCode::Packed(bits) => *bits,
+        }
+    }
+
+    /// packed codes fill whole words, the sketch size must be a multiple of 64 / b
+    pub fn check_sketch_size(&self, sketch_size: usize) -> Result<(), String> {
+        match self {
+            SketchCode::Packed(bits) if !(sketch_size * *bits as usize).is_multiple_of(64) => Err(format!(
+                "sketch size {} is not a multiple of {}, needed for {} bits codes",
+                sketch_size,
+                64 / bits,
+                bits
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromStr for SketchCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "f64" => Ok(SketchCode::F64),
+            "u32" => Ok(SketchCode::U32),
+            "u16" => Ok(SketchCode::U16),
+            "b8" => Ok(SketchCode::Packed(8)),
+            "b4" => Ok(SketchCode::Packed(4)),
+            "b2" => Ok(SketchCode::Packed(2)),
+            "b1" => Ok(SketchCode::Packed(1)),
+            other => Err(format!("unknown sketch code {}, expected f64, u32, u16, b8, b4, b2 or b1", other)),
+        }
+    }
+}
+
+impl fmt::Display for SketchCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SketchCode::F64 => write!(f, "f64"),
+            SketchCode::U32 => write!(f, "u32"),
+            SketchCode::U16 => write!(f, "u16"),
+            SketchCode::Packed(bits) => write!(f, "b{}", bits),
+        }
+    }
+}
+
+/// writes the sketch code of the database in `dir`
+pub fn save_sketch_code(dir: &Path, code: SketchCode) -> std::io::Result<()> {
+    fs::write(dir.join(SKETCH_CODE_FILE), format!("{}\n", code))
+}
+
+/// sketch code of the database in `dir`, f64 if the database has no code file
+pub fn reload_sketch_code(dir: &Path) -> std::io::Result<SketchCode> {
+    let path = dir.join(SKETCH_CODE_FILE);
+    if !path.exists() {
+        return Ok(SketchCode::F64);
+    }
+    fs::read_to_string(&path)?
+        .parse::<SketchCode>()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Loads the HNSW of the database in `dir` with its f64 signatures, for `tool` which uses the stored
+/// signatures of graph points. Databases with compact codes are rejected.
+pub fn load_f64_index<'b>(hnswio: &'b mut HnswIo, dir: &Path, tool: &str) -> Result<Hnsw<'b, f64, DistHamming>, String> {
+    match reload_sketch_code(dir) {
+        Ok(SketchCode::F64) => {}
+        Ok(code) => {
+            return Err(format!(
+                "Database signatures are {} codes, {} needs a database built with --sketch-code f64",
+                code, tool
+            ))
+        }
+        Err(e) => return Err(format!("Cannot read sketch code of the database: {:?}", e)),
+    }
+    hnswio.load_hnsw::<f64, DistHamming>().map_err(|e| format!("{:?}", e))
+}
+
+/// 64 bits hash of a sketch value (splitmix64 finalizer of its bits)
+pub fn hash_code(x: f64) -> u64 {
+    let mut z = x.to_bits();
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+pub fn encode_u32(sig: &[f64]) -> Vec<u32> {
+    sig.iter().map(|x| (hash_code(*x) >> 32) as u32).collect()
+}
+
+pub fn encode_u16(sig: &[f64]) -> Vec<u16> {
+    sig.iter().map(|x| (hash_code(*x) >> 48) as u16).collect()
+}
+
+/// b bits codes packed in u64 words, value i in word i / (64/b) at bit (i % (64/b)) * b
+pub fn pack_codes(sig: &[f64], bits: u32) -> Vec<u64> {
+    let per_word = (64 / bits) as usize;
+    let mask = if bits == 64 { u64::MAX } else { (1u64 << bits) - 1 };
+    let mut words = vec![0u64; sig.len().div_ceil(per_word)];
+    for (i, x) in sig.iter().enumerate() {
+        words[i / per_word] |= (hash_code(*x) & mask) << ((i % per_word) as u32 * bits);
+    }
+    words
+}
+
+/// distance from the number of differing b bits codes, corrected for collisions
+fn corrected_distance(nb_diff: u32, nb_values: usize, bits: u32) -> f32 {
+    if nb_values == 0 {
+        return 0.;
+    }
+    let collision = 0.5f64.powi(bits as i32);
+    ((nb_diff as f64 / nb_values as f64) / (1. - collision)).min(1.) as f32
+}
+
+/// Hamming distance on u32 or u16 codes. The count of differing codes has no branch, so that the compiler can auto-vectorise it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DistCodeHamming;
+
+impl Distance<u32> for DistCodeHamming {
+    fn eval(&self, va: &[u32], vb: &[u32]) -> f32 {
+        let nb_diff: u32 = va.iter().zip(vb.iter()).map(|(a, b)| (a != b) as u32).sum();
+        corrected_distance(nb_diff, va.len(), 32)
+    }
+}
+
+impl Distance<u16> for DistCodeHamming {
+    fn eval(&self, va: &[u16], vb: &[u16]) -> f32 {
+        let nb_diff: u32 = va.iter().zip(vb.iter()).map(|(a, b)| (a != b) as u32).sum();
+        corrected_distance(nb_diff, va.len(), 16)
+    }
+}
+
+/// Hamming distance on B bits codes packed in u64 words: differing lanes of a xor are counted
+/// 64 / B at a time with a popcount.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DistPackedHamming<const B: u32>;
+
+impl<const B: u32> DistPackedHamming<B> {
+    /// lowest bit of each lane
+    const LOW_BITS: u64 = u64::MAX / ((1u64 << B) - 1);
+
+    /// number of non null B bits lanes of x
+    #[inline]
+    fn nb_diff_lanes(mut x: u64) -> u32 {
+        // fold the bits of each lane on its lowest bit
+        let mut shift = 1;
+        while shift < B {
+            x |= x >> shift;
+            shift <<= 1;
+        }
+        (x & Self::LOW_BITS).count_ones()
+    }
+}
+
+impl<const B: u32> Distance<u64> for DistPackedHamming<B> {
+    fn eval(&self, va: &[u64], vb: &[u64]) -> f32 {
+        let nb_diff: u32 = va.iter().zip(vb.iter()).map(|(a, b)| Self::nb_diff_lanes(a ^ b)).sum();
+        corrected_distance(nb_diff, va.len() * (64 / B) as usize, B)
+    }
+}
+
+/// HNSW of a database, with the signature type of its sketch code
+pub enum SketchIndex<'b> {
+    F64(Hnsw<'b, f64, DistHamming>),
+    U32(Hnsw<'b, u32, DistCodeHamming>),
+    U16(Hnsw<'b, u16, DistCodeHamming>),
+    B8(Hnsw<'b, u64, DistPackedHamming<8>>),
+    B4(Hnsw<'b, u64, DistPackedHamming<4>>),
+    B2(Hnsw<'b, u64, DistPackedHamming<2>>),
+    B1(Hnsw<'b, u64, DistPackedHamming<1>>),
+}
+
+/// runs `$body` with `$hnsw` the HNSW of the index and `$encode` the encoder of f64 signatures to its type
+macro_rules! on_index {
+    ($index:expr, $hnsw:ident, $encode:ident, $body:expr) => {
+        match $index {
+            SketchIndex::F64($hnsw) => {
+                let $encode = |sig: &[f64]| sig.to_vec();
+                $body
+            }
+            SketchIndex::U32($hnsw) => {
+                let $encode = encode_u32;
+                $body
+            }
+            SketchIndex::U16($hnsw) => {
+                let $encode = encode_u16;
+                $body
+            }
+            SketchIndex::B8($hnsw) => {
+                let $encode = |sig: &[f64]| pack_codes(sig, 8);
+                $body
+            }
+            SketchIndex::B4($hnsw) => {
+                let $encode = |sig: &[f64]| pack_codes(sig, 4);
+                $body
+            }
+            SketchIndex::B2($hnsw) => {
+                let $encode = |sig: &[f64]| pack_codes(sig, 2);
+                $body
+            }
+            SketchIndex::B1($hnsw) => {
+                let $encode = |sig: &[f64]| pack_codes(sig, 1);
+                $body
+            }
+        }
+    };
+}
+
+impl<'b> SketchIndex<'b> {
+    /// empty HNSW for `code`, arguments as in Hnsw::new
+    pub fn new(code: SketchCode, max_nb_connection: usize, capacity: usize, max_layer: usize, ef: usize) -> Self {
+        match code {
+            SketchCode::F64 => SketchIndex::F64(Hnsw::new(max_nb_connection, capacity, max_layer, ef, DistHamming {})),
+            SketchCode::U32 => SketchIndex::U32(Hnsw::new(max_nb_connection, capacity, max_layer, ef, DistCodeHamming)),
+            SketchCode::U16 => SketchIndex::U16(Hnsw::new(max_nb_connection, capacity, max_layer, ef, DistCodeHamming)),
+            SketchCode::Packed(8) => SketchIndex::B8(Hnsw::new(max_nb_connection, capacity, max_layer, ef, DistPackedHamming)),
+            SketchCode::Packed(4) => SketchIndex::B4(Hnsw::new(max_nb_connection, capacity, max_layer, ef, DistPackedHamming)),
+            SketchCode::Packed(2) => SketchIndex::B2(Hnsw::new(max_nb_connection, capacity, max_layer, ef, DistPackedHamming)),
+            SketchCode::Packed(1) => SketchIndex::B1(Hnsw::new(max_nb_connection, capacity, max_layer, ef, DistPackedHamming)),
+            SketchCode::Packed(bits) => panic!("unsupported code width {} bits", bits),
+        }
+    }
+
+    /// loads the HNSW dumped in `hnswio`, whose signatures have type `code`
+    pub fn load(hnswio: &'b mut HnswIo, code: SketchCode) -> Result<Self, String> {
+        let index = match code {
+            SketchCode::F64 => hnswio.load_hnsw::<f64, DistHamming>().map(SketchIndex::F64),
+            SketchCode::U32 => hnswio.load_hnsw::<u32, DistCodeHamming>().map(SketchIndex::U32),
+            SketchCode::U16 => hnswio.load_hnsw::<u16, DistCodeHamming>().map(SketchIndex::U16),
+            SketchCode::Packed(8) => hnswio.load_hnsw::<u64, DistPackedHamming<8>>().map(SketchIndex::B8),
+            SketchCode::Packed(4) => hnswio.load_hnsw::<u64, DistPackedHamming<4>>().map(SketchIndex::B4),
+            SketchCode::Packed(2) => hnswio.load_hnsw::<u64, DistPackedHamming<2>>().map(SketchIndex::B2),
+            SketchCode::Packed(1) => hnswio.load_hnsw::<u64, DistPackedHamming<1>>().map(SketchIndex::B1),
+            SketchCode::Packed(bits) => return Err(format!("unsupported code width {} bits", bits)),
+        };
+        index.map_err(|e| format!("{:?}", e))
+    }
+
+    pub fn get_code(&self) -> SketchCode {
+        match self {
+            SketchIndex::F64(_) => SketchCode::F64,
+            SketchIndex::U32(_) => SketchCode::U32,
+            SketchIndex::U16(_) => SketchCode::U16,
+            SketchIndex::B8(_) => SketchCode::Packed(8),
+            SketchIndex::B4(_) => SketchCode::Packed(4),
+            SketchIndex::B2(_) => SketchCode::Packed(2),
+            SketchIndex::B1(_) => SketchCode::Packed(1),
+        }
+    }
+
+    /// the f64 HNSW, None for compact codes
+    pub fn as_f64(&self) -> Option<&Hnsw<'b, f64, DistHamming>> {
+        match self {
+            SketchIndex::F64(hnsw) => Some(hnsw),
+            _ => None,
+        }
+    }
+
+    pub fn get_nb_point(&self) -> usize {
+        on_index!(self, hnsw, _encode, hnsw.get_nb_point())
+    }
+
+    pub fn modify_level_scale(&mut self, scale_modification: f64) {
+        on_index!(self, hnsw, _encode, hnsw.modify_level_scale(scale_modification))
+    }
+
+    pub fn set_extend_candidates(&mut self, flag: bool) {
+        on_index!(self, hnsw, _encode, hnsw.set_extend_candidates(flag))
+    }
+
+    pub fn set_keeping_pruned(&mut self, flag: bool) {
+        on_index!(self, hnsw, _encode, hnsw.set_keeping_pruned(flag))
+    }
+
+    /// inserts f64 signatures with their data ids
+    pub fn parallel_insert(&self, data: &[(&Vec<f64>, usize)]) {
+        on_index!(self, hnsw, encode, {
+            let encoded: Vec<_> = data.iter().map(|(sig, d_id)| (encode(sig), *d_id)).collect();
+            let refs: Vec<_> = encoded.iter().map(|(sig, d_id)| (sig, *d_id)).collect();
+            hnsw.parallel_insert(&refs)
+        })
+    }
+
+    pub fn search(&self, sig: &[f64], knbn: usize, ef: usize) -> Vec<Neighbour> {
+        on_index!(self, hnsw, encode, hnsw.search(&encode(sig), knbn, ef))
+    }
+
+    pub fn parallel_search(&self, sigs: &[Vec<f64>], knbn: usize, ef: usize) -> Vec<Vec<Neighbour>> {
+        match self {
+            // no copy for f64 signatures
+            SketchIndex::F64(hnsw) => hnsw.parallel_search(sigs, knbn, ef),
+            _ => on_index!(self, hnsw, encode, {
+                let encoded: Vec<_> = sigs.iter().map(|sig| encode(sig)).collect();
+                hnsw.parallel_search(&encoded, knbn, ef)
+            }),
+        }
+    }
+
+    /// dumps the HNSW, dictionary and parameters in `dump_path`, and the sketch code
+    pub fn dump(&self, dump_path: &PathBuf, seqdict: &SeqDict, processing_params: &ProcessingParams) -> Result<(), String> {
+        on_index!(self, hnsw, _encode, {
+            dumpall(dump_path, hnsw, seqdict, processing_params).map_err(|e| format!("{:?}", e))
+        })?;
+        save_sketch_code(dump_path, self.get_code()).map_err(|e| format!("{:?}", e))
+    }
+} // end of impl SketchIndex
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// xorshift64 values in [0, 1)
+    fn next_value(state: &mut u64) -> f64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        (*state >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn naive_nb_diff_lanes(x: u64, bits: u32) -> u32 {
+        let mask = (1u64 << bits) - 1;
+        (0..64 / bits).filter(|lane| (x >> (lane * bits)) & mask != 0).count() as u32
+    }
+
+    #[test]
+    fn nb_diff_lanes_is_naive_count() {
+        let mut state = 0x9e3779b97f4a7c15u64;
+        let mut words = vec![0u64, u64::MAX, 1, 1 << 63, 0x8000_0000_0000_0001];
+        for _ in 0..1000 {
+            let x = (next_value(&mut state) * (1u64 << 53) as f64) as u64;
+            // sparse words too, with few non null lanes
+            words.push(x << 11);
+            words.push(x & (x >> 7) & (x >> 19));
+        }
+        for x in words {
+            assert_eq!(DistPackedHamming::<8>::nb_diff_lanes(x), naive_nb_diff_lanes(x, 8));
+            assert_eq!(DistPackedHamming::<4>::nb_diff_lanes(x), naive_nb_diff_lanes(x, 4));
+            assert_eq!(DistPackedHamming::<2>::nb_diff_lanes(x), naive_nb_diff_lanes(x, 2));
+            assert_eq!(DistPackedHamming::<1>::nb_diff_lanes(x), naive_nb_diff_lanes(x, 1));
+        }
+    }
+
+    #[test]
+    fn collision_correction() {
+        assert_eq!(corrected_distance(0, 0, 1), 0.);
+        assert_eq!(corrected_distance(0, 256, 8), 0.);
+        // 1 bit codes: half of different values collide, 64 differing codes out of 256 is a distance of 0.5
+        assert!((corrected_distance(64, 256, 1) - 0.5).abs() < 1e-6);
+        assert!((corrected_distance(96, 256, 2) - 0.5).abs() < 1e-6);
+        assert!((corrected_distance(120, 256, 4) - 0.5).abs() < 1e-6);
+        // all codes different: the estimate is capped at 1
+        assert_eq!(corrected_distance(256, 256, 1), 1.);
+        assert_eq!(corrected_distance(256, 256, 32), 1.);
+    }
+
+    #[test]
+    fn sketch_size_of_packed_codes() {
+        for code in [SketchCode::F64, SketchCode::U32, SketchCode::U16] {
+            assert!(code.check_sketch_size(100).is_ok());
+        }
+        assert!(SketchCode::Packed(8).check_sketch_size(256).is_ok());
+        assert!(SketchCode::Packed(8).check_sketch_size(8).is_ok());
+        assert!(SketchCode::Packed(8).check_sketch_size(12).is_err());
+        assert!(SketchCode::Packed(1).check_sketch_size(64).is_ok());
+        assert!(SketchCode::Packed(1).check_sketch_size(96).is_err());
+        assert!(SketchCode::Packed(2).check_sketch_size(32).is_ok());
+        assert!(SketchCode::Packed(4).check_sketch_size(40).is_err());
+    }
+
+    #[test]
+    fn packed_distance_is_naive_count() {
+        let mut state = 88172645463325252u64;
+        let a: Vec<f64> = (0..256).map(|_| next_value(&mut state)).collect();
+        let mut b = a.clone();
+        for i in 0..64 {
+            b[i * 3] = next_value(&mut state);
+        }
+        for bits in [8u32, 4, 2, 1] {
+            let (pa, pb) = (pack_codes(&a, bits), pack_codes(&b, bits));
+            assert_eq!(pa.len(), 256 * bits as usize / 64);
+            let distance = match bits {
+                8 => DistPackedHamming::<8>.eval(&pa, &pb),
+                4 => DistPackedHamming::<4>.eval(&pa, &pb),
+                2 => DistPackedHamming::<2>.eval(&pa, &pb),
+                _ => DistPackedHamming::<1>.eval(&pa, &pb),
+            };
+            let mask = (1u64 << bits) - 1;
+            let nb_diff = a.iter().zip(b.iter()).filter(|(x, y)| hash_code(**x) & mask != hash_code(**y) & mask).count();
+            assert_eq!(distance, corrected_distance(nb_diff as u32, 256, bits));
+        }
+    }
+
+    /// points at increasing distances of a query: u32 and u16 codes must find the same neighbours, in the same order, as f64
+    #[test]
+    fn codes_find_f64_neighbours() {
+        let mut state = 0x2545f4914f6cdd1du64;
+        let sketch_size = 256;
+        let query: Vec<f64> = (0..sketch_size).map(|_| next_value(&mut state)).collect();
+        let nb_points = 50;
+        let points: Vec<Vec<f64>> = (0..nb_points)
+            .map(|rank| {
+                let mut sig = query.clone();
+                for value in sig.iter_mut().take(1 + 4 * rank) {
+                    *value = next_value(&mut state);
+                }
+                sig
+            })
+            .collect();
+        let data: Vec<(&Vec<f64>, usize)> = points.iter().enumerate().map(|(d_id, sig)| (sig, d_id)).collect();
+        let neighbours = |code: SketchCode| -> Vec<usize> {
+            let index = SketchIndex::new(code, 16, nb_points, 16, 200);
+            index.parallel_insert(&data);
+            assert_eq!(index.get_nb_point(), nb_points);
+            index.search(&query, 10, 200).iter().map(|n| n.d_id).collect()
+        };
+        let expected = neighbours(SketchCode::F64);
+        assert_eq!(expected, (0..10).collect::<Vec<usize>>());
+        assert_eq!(neighbours(SketchCode::U32), expected);
+        assert_eq!(neighbours(SketchCode::U16), expected);
+    }
+}
@@ -0,0 +1,92 @@
+//! Batched sketching of FASTA files.
+//!
+//! A reader thread cuts the file into batches of records sent through a bounded channel. Each batch is sketched
+//! with one call on the rayon pool (kmerutils sketches the sequences of a call in parallel), then handed to the
+//! calling thread in file order. Collection needs no lock and the rank of a record in the file is its rank in the
+//! collected signatures, whatever the number of threads.
+
+use crossbeam::channel::bounded;
+use needletail::{parse_fastx_file, Sequence};
+use rayon::prelude::*;
+use std::path::Path;
+use std::thread;
+
+use kmerutils::base::alphabet::Alphabet2b;
+use kmerutils::base::sequence::Sequence as SequenceStruct;
+use kmerutils::base::{kmergenerator::*, CompressedKmerT, Kmer32bit};
+use kmerutils::sketching::setsketchert::*;
+
+/// a FASTA/FASTQ record: id and normalised sequence, upper case with IUPAC ambiguity codes kept
+/// (so that the sequence store keeps them)
+pub type Record = (Vec<u8>, Vec<u8>);
+
+fn is_iupac_ambiguity(b: u8) -> bool {
+    matches!(b, b'B' | b'D' | b'H' | b'V' | b'R' | b'Y' | b'S' | b'W' | b'K' | b'M')
+}
+
+/// 2 bits encoding of a sequence. IUPAC ambiguity codes are encoded as N, as if normalised without IUPAC codes
+pub fn ascii_to_seq(bases: &[u8]) -> SequenceStruct {
+    let alphabet = Alphabet2b::new();
+    let mut seq = SequenceStruct::with_capacity(2, bases.len());
+    if bases.iter().any(|b| is_iupac_ambiguity(*b)) {
+        let bases: Vec<u8> = bases.iter().map(|b| if is_iupac_ambiguity(*b) { b'N' } else { *b }).collect();
+        seq.encode_and_add(&bases, &alphabet);
+    } else {
+        seq.encode_and_add(bases, &alphabet);
+    }
+    seq
+}
+
+/// hash of a k-mer: its canonical compressed value
+pub fn kmer_hash_fn_32bit(kmer: &Kmer32bit) -> <Kmer32bit as CompressedKmerT>::Val {
+    let canonical = kmer.reverse_complement().min(*kmer);
+    let nb_alphabet_bits = Alphabet2b::new().get_nb_bits();
+    let mask: <Kmer32bit as CompressedKmerT>::Val = ((1u64 << (nb_alphabet_bits * kmer.get_nb_base())) - 1)
+        .try_into()
+        .unwrap();
+    canonical.get_compressed_value() & mask
+}
+
+/// Sketches the records of `fasta_path` by batches of `batch_size`.
+/// `on_batch` gets, in file order, the records of each batch, the lengths of their sequences and their signatures.
+/// Returns the number of records sketched.
+pub fn sketch_fasta_batches<F>(
+    fasta_path: &str,
+    sketcher: &OptDensHashSketch<Kmer32bit, f64>,
+    batch_size: usize,
+    mut on_batch: F,
+) -> usize
+where
+    F: FnMut(Vec<Record>, Vec<usize>, Vec<Vec<f64>>),
+{
+    let batch_size = batch_size.max(1);
+    // 2 batches in flight: the next one is read while one is sketched
+    let (tx, rx) = bounded::<Vec<Record>>(2);
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            let mut reader = parse_fastx_file(Path::new(fasta_path)).expect("Invalid path/file for FASTA");
+            let mut batch = Vec::with_capacity(batch_size);
+            while let Some(record) = reader.next() {
+                let seqrec = record.expect("Invalid record");
+                batch.push((seqrec.id().to_owned(), seqrec.normalize(true).into_owned()));
+                if batch.len() == batch_size {
+                    let full_batch = std::mem::replace(&mut batch, Vec::with_capacity(batch_size));
+                    tx.send(full_batch).expect("Could not send batch");
+                }
+            }
+            if !batch.is_empty() {
+                tx.send(batch).expect("Could not send batch");
+            }
+        });
+        let mut nb_records = 0;
+        for batch in rx.iter() {
+            let seqs: Vec<SequenceStruct> = batch.par_iter().map(|(_, bases)| ascii_to_seq(bases)).collect();
+            let vseq: Vec<&SequenceStruct> = seqs.iter().collect();
+            let signatures = sketcher.sketch_compressedkmer(&vseq, kmer_hash_fn_32bit);
+            let lens = seqs.iter().map(|seq| seq.size()).collect();
+            nb_records += batch.len();
+            on_batch(batch, lens, signatures);
+        }
+        nb_records
+    })
+} // end of sketch_fasta_batches
@@ -0,0 +1,225 @@
+//! Signatures waiting for insertion in the HNSW, within a memory budget.
+//!
+//! Signatures are kept in memory until the budget is reached, the following ones are spilled to a temporary file
+//! (little-endian f64, one fixed-length record by signature). They are then fed to the graph in file order,
+//! by chunks fitting in the budget, so the rank of a signature is kept as its data id.
+//! The spill file gets a random name, is created exclusively (no existing file or symlink is followed)
+//! and is removed when the spool is dropped.
+
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// memory taken by a signature of `sig_len` values, with its Vec header
+fn signature_bytes(sig_len: usize) -> usize {
+    sig_len * std::mem::size_of::<f64>() + std::mem::size_of::<Vec<f64>>()
+}
+
+/// Signatures in memory and in a spill file, in insertion order
+pub struct SignatureSpool {
+    /// memory budget in bytes, None for no limit
+    max_bytes: Option<usize>,
+    in_memory: Vec<Vec<f64>>,
+    /// number of values of a signature, known at the first one
+    sig_len: usize,
+    tmp_dir: PathBuf,
+    spill: Option<BufWriter<NamedTempFile>>,
+    nb_spilled: usize,
+}
+
+impl SignatureSpool {
+    /// `max_bytes` bounds the memory of signatures, the spill file is created in `tmp_dir` when needed
+    pub fn new(max_bytes: Option<usize>, tmp_dir: &Path) -> Self {
+        SignatureSpool {
+            max_bytes,
+            in_memory: Vec::new(),
+            sig_len: 0,
+            tmp_dir: tmp_dir.to_path_buf(),
+            spill: None,
+            nb_spilled: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.in_memory.len() + self.nb_spilled
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get_nb_spilled(&self) -> usize {
+        self.nb_spilled
+    }
+
+    /// number of signatures fitting in the budget
+    fn capacity(&self) -> usize {
+        match self.max_bytes {
+            Some(max_bytes) => (max_bytes / signature_bytes(self.sig_len)).max(1),
+            None => usize::MAX,
+        }
+    }
+
+    /// appends signatures, to the spill file once the budget is reached
+    pub fn extend(&mut self, signatures: Vec<Vec<f64>>) -> std::io::Result<()> {
+        for sig in signatures {
+            if self.sig_len == 0 {
+                self.sig_len = sig.len();
+            }
+            if sig.len() != self.sig_len {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("signature of {} values, expected {}", sig.len(), self.sig_len),
+                ));
+            }
+            // once spilling started all following signatures go to the file, to keep the order
+            if self.spill.is_none() && self.in_memory.len() < self.capacity() {
+                self.in_memory.push(sig);
+                continue;
+            }
+            if self.spill.is_none() {
+                let file = tempfile::Builder::new()
+                    .prefix("adas.spill.")
+                    .suffix(".bin")
+                    .tempfile_in(&self.tmp_dir)?;
+                log::info!("signatures exceed memory budget, spilling to {}", file.path().display());
+                self.spill = Some(BufWriter::new(file));
+            }
+            let writer = self.spill.as_mut().unwrap();
+            for x in sig.iter() {
+                writer.write_all(&x.to_le_bytes())?;
+            }
+            self.nb_spilled += 1;
+        }
+        Ok(())
+    }
+
+    /// Calls `insert` with the rank of the first signature of each chunk and the chunk, in order:
+    /// signatures in memory, then spilled ones read back by chunks fitting in the budget.
+    /// The spill file is removed at the end.
+    pub fn drain_chunks<F>(mut self, mut insert: F) -> std::io::Result<()>
+    where
+        F: FnMut(usize, &[Vec<f64>]),
+    {
+        let in_memory = std::mem::take(&mut self.in_memory);
+        let nb_in_memory = in_memory.len();
+        if nb_in_memory > 0 {
+            insert(0, &in_memory);
+        }
+        drop(in_memory);
+        let writer = match self.spill.take() {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        let chunk_size = self.capacity();
+        let mut reader = BufReader::new(file);
+        let mut record = vec![0u8; self.sig_len * std::mem::size_of::<f64>()];
+        let mut first = nb_in_memory;
+        let mut nb_read = 0;
+        while nb_read < self.nb_spilled {
+            let nb_in_chunk = chunk_size.min(self.nb_spilled - nb_read);
+            let mut chunk = Vec::with_capacity(nb_in_chunk);
+            for _ in 0..nb_in_chunk {
+                reader.read_exact(&mut record)?;
+                let sig: Vec<f64> = record
+                    .chunks_exact(std::mem::size_of::<f64>())
+                    .map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap()))
+                    .collect();
+                chunk.push(sig);
+            }
+            insert(first, &chunk);
+            first += nb_in_chunk;
+            nb_read += nb_in_chunk;
+        }
+        Ok(())
+    } // end of drain_chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// signatures of 4 values whose bytes all differ, so that a wrong byte order or offset is seen
+    fn signatures(nb: usize) -> Vec<Vec<f64>> {
+        (0..nb as u64)
+            .map(|i| (0..4u64).map(|j| f64::from_bits(0x0102030405060708 + (i << 40) + (j << 8))).collect())
+            .collect()
+    }
+
+    fn drain(spool: SignatureSpool) -> Vec<(usize, Vec<Vec<f64>>)> {
+        let mut chunks = Vec::new();
+        spool.drain_chunks(|first, chunk| chunks.push((first, chunk.to_vec()))).unwrap();
+        chunks
+    }
+
+    fn same_bits(a: &[Vec<f64>], b: &[Vec<f64>]) -> bool {
+        a.len() == b.len()
+            && a.iter()
+                .zip(b.iter())
+                .all(|(x, y)| x.iter().map(|v| v.to_bits()).eq(y.iter().map(|v| v.to_bits())))
+    }
+
+    #[test]
+    fn spill_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let sigs = signatures(7);
+        // a signature takes 4 * 8 + 24 bytes, 2 fit in 120 bytes
+        assert_eq!(signature_bytes(4), 56);
+        let mut spool = SignatureSpool::new(Some(120), dir.path());
+        spool.extend(sigs[..3].to_vec()).unwrap();
+        spool.extend(sigs[3..].to_vec()).unwrap();
+        assert_eq!((spool.len(), spool.get_nb_spilled()), (7, 5));
+        // spilled records are little-endian f64, one after the other
+        let writer = spool.spill.as_mut().unwrap();
+        writer.flush().unwrap();
+        let path = writer.get_ref().path().to_path_buf();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 5 * 4 * 8);
+        // the first spilled signature is the third one, its first value has bits 0x0102050405060708
+        assert_eq!(bytes[..8], [0x08, 0x07, 0x06, 0x05, 0x04, 0x05, 0x02, 0x01]);
+        assert_eq!(bytes[8..16], sigs[2][1].to_le_bytes());
+        assert_eq!(bytes[32..40], sigs[3][0].to_le_bytes());
+        // in memory first, then chunks of the budget size, last one partial
+        let chunks = drain(spool);
+        let firsts: Vec<usize> = chunks.iter().map(|(first, _)| *first).collect();
+        assert_eq!(firsts, vec![0, 2, 4, 6]);
+        for (first, chunk) in chunks.iter() {
+            assert!(same_bits(chunk, &sigs[*first..*first + chunk.len()]));
+        }
+        assert_eq!(chunks[3].1.len(), 1);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn chunk_boundaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let sigs = signatures(6);
+        // no budget: one chunk, no file
+        let mut spool = SignatureSpool::new(None, dir.path());
+        spool.extend(sigs.clone()).unwrap();
+        assert_eq!(spool.get_nb_spilled(), 0);
+        let chunks = drain(spool);
+        assert_eq!(chunks.len(), 1);
+        assert!(same_bits(&chunks[0].1, &sigs));
+        // spilled signatures a multiple of the chunk size: no empty chunk at the end
+        let mut spool = SignatureSpool::new(Some(112), dir.path());
+        spool.extend(sigs.clone()).unwrap();
+        let chunks = drain(spool);
+        assert_eq!(chunks.iter().map(|(first, chunk)| (*first, chunk.len())).collect::<Vec<_>>(), vec![(0, 2), (2, 2), (4, 2)]);
+        // a budget smaller than a signature still keeps one signature by chunk
+        let mut spool = SignatureSpool::new(Some(1), dir.path());
+        spool.extend(sigs.clone()).unwrap();
+        assert_eq!(spool.get_nb_spilled(), 5);
+        let chunks = drain(spool);
+        assert_eq!(chunks.len(), 6);
+        assert!(chunks.iter().enumerate().all(|(i, (first, chunk))| *first == i && same_bits(chunk, &sigs[i..i + 1])));
+        // nothing was left in the directory
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+        // signatures of another length are rejected
+        let mut spool = SignatureSpool::new(Some(120), dir.path());
+        assert!(spool.extend(vec![vec![0.; 4], vec![0.; 3]]).is_err());
+        assert!(SignatureSpool::new(None, dir.path()).is_empty());
+    }
+}
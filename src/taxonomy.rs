@@ -0,0 +1,251 @@
+//! Taxonomy attached to a database and classification of queries from their hits.
+//!
+//! The taxonomy is a TSV file, one line by database sequence: `sequence id <TAB> lineage`, the lineage being
+//! ranks from domain to species separated by `;` (e.g. `d__Bacteria;p__Pseudomonadota;...;s__Pelagibacter ubique`).
+//! It is stored as `taxonomy.tsv` in the database directory.
+//!
+//! A query is classified by a weighted lowest common ancestor: going down the ranks, the taxon keeping
+//! the largest weight of hits is assigned while its fraction of the total weight is above a confidence threshold.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// name of the taxonomy file in the database directory
+pub const TAXONOMY_FILE: &str = "taxonomy.tsv";
+
+/// ranks of a lineage, in order
+pub const RANKS: [&str; 7] = ["domain", "phylum", "class", "order", "family", "genus", "species"];
+
+/// Lineages of database sequences, by sequence id
+pub struct Taxonomy {
+    lineages: HashMap<String, Vec<String>>,
+}
+
+impl Taxonomy {
+    /// Reads a taxonomy TSV file. Empty lines and lines beginning with # are skipped,
+    /// empty levels at the end of a lineage are dropped.
+    pub fn from_tsv(path: &Path) -> std::io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut lineages = HashMap::new();
+        for (nb_line, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (id, lineage) = line.split_once('\t').ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("line {} of {} has no tab", nb_line + 1, path.display()),
+                )
+            })?;
+            let mut levels: Vec<String> = lineage.split(';').map(|level| level.trim().to_string()).collect();
+            while levels.last().is_some_and(|level| level.is_empty()) {
+                levels.pop();
+            }
+            levels.truncate(RANKS.len());
+            lineages.insert(id.trim().to_string(), levels);
+        }
+        Ok(Taxonomy { lineages })
+    }
+
+    /// reloads the taxonomy of a database directory
+    pub fn reload(dir: &Path) -> std::io::Result<Self> {
+        Taxonomy::from_tsv(&dir.join(TAXONOMY_FILE))
+    }
+
+    pub fn get_nb_entries(&self) -> usize {
+        self.lineages.len()
+    }
+
+    /// Lineage of a sequence. The id is looked up as is, then by its first word
+    /// as database ids are whole FASTA header lines.
+    pub fn get_lineage(&self, fasta_id: &str) -> Option<&[String]> {
+        if let Some(lineage) = self.lineages.get(fasta_id) {
+            return Some(lineage);
+        }
+        let first_word = fasta_id.split_whitespace().next()?;
+        self.lineages.get(first_word).map(|lineage| lineage.as_slice())
+    }
+} // end of impl Taxonomy
+
+/// true if the database directory has a taxonomy file
+pub fn taxonomy_exists(dir: &Path) -> bool {
+    dir.join(TAXONOMY_FILE).exists()
+}
+
+/// Copies a taxonomy file in a database directory, after checking it can be read.
+/// Returns the number of lineages.
+pub fn attach_taxonomy(dir: &Path, taxonomy_path: &Path) -> std::io::Result<usize> {
+    let taxonomy = Taxonomy::from_tsv(taxonomy_path)?;
+    std::fs::copy(taxonomy_path, dir.join(TAXONOMY_FILE))?;
+    Ok(taxonomy.get_nb_entries())
+}
+
+/// Assignment of a query at one rank
+#[derive(Clone, Debug)]
+pub struct RankAssignment {
+    /// None if the query is unclassified at this rank
+    pub taxon: Option<String>,
+    /// fraction of the hit weight supporting the best taxon at this rank (assigned or not)
+    pub confidence: f64,
+}
+
+/// Classification of a query, one assignment by rank of [RANKS]
+#[derive(Clone, Debug)]
+pub struct Classification {
+    /// number of hits with a lineage used for classification
+    pub nb_hits: usize,
+    pub ranks: Vec<RankAssignment>,
+}
+
+impl Classification {
+    /// deepest assigned rank and taxon, None if unclassified at domain level
+    pub fn get_lca(&self) -> Option<(&'static str, &str)> {
+        self.ranks
+            .iter()
+            .enumerate()
+            .filter_map(|(r, assignment)| assignment.taxon.as_deref().map(|taxon| (RANKS[r], taxon)))
+            .next_back()
+    }
+}
+
+/// Weighted LCA of hits given as (lineage, weight).
+/// At each rank, the weights of hits are summed by taxon among the children of the taxon assigned at the
+/// previous rank. The best one is assigned if it holds at least `min_confidence` of the total weight,
+/// otherwise the query stays unclassified at this rank and below.
+pub fn classify(hits: &[(&[String], f64)], min_confidence: f64) -> Classification {
+    let total_weight: f64 = hits.iter().map(|(_, weight)| weight).sum();
+    let mut ranks = Vec::with_capacity(RANKS.len());
+    // hits consistent with the taxa assigned so far
+    let mut consistent: Vec<usize> = (0..hits.len()).collect();
+    let mut classified = true;
+    for r in 0..RANKS.len() {
+        let mut weights: HashMap<&str, f64> = HashMap::new();
+        for &h in consistent.iter() {
+            let (lineage, weight) = hits[h];
+            if let Some(taxon) = lineage.get(r).filter(|taxon| !taxon.is_empty()) {
+                *weights.entry(taxon.as_str()).or_insert(0.) += weight;
+            }
+        }
+        // best taxon, ties broken by name so the result does not depend on hash order
+        let best = weights
+            .iter()
+            .max_by(|a, b| a.1.partial_cmp(b.1).unwrap().then(b.0.cmp(a.0)))
+            .map(|(taxon, weight)| (taxon.to_string(), *weight));
+        let confidence = match (&best, total_weight > 0.) {
+            (Some((_, weight)), true) => weight / total_weight,
+            _ => 0.,
+        };
+        let taxon = match best {
+            Some((taxon, _)) if classified && confidence >= min_confidence => Some(taxon),
+            _ => None,
+        };
+        match taxon.as_ref() {
+            Some(taxon) => consistent.retain(|&h| hits[h].0.get(r) == Some(taxon)),
+            None => classified = false,
+        }
+        ranks.push(RankAssignment { taxon, confidence });
+    }
+    Classification {
+        nb_hits: hits.len(),
+        ranks,
+    }
+} // end of classify
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lineage(levels: &str) -> Vec<String> {
+        levels.split(';').map(|level| level.to_string()).collect()
+    }
+
+    fn taxa(classification: &Classification) -> Vec<Option<&str>> {
+        classification.ranks.iter().map(|assignment| assignment.taxon.as_deref()).collect()
+    }
+
+    #[test]
+    fn weighted_lca() {
+        let a = lineage("d__B;p__P;c__C;o__O;f__F;g__G1;s__S1");
+        let b = lineage("d__B;p__P;c__C;o__O;f__F;g__G1;s__S2");
+        let c = lineage("d__B;p__P;c__C;o__O;f__F;g__G2;s__S3");
+        let hits: Vec<(&[String], f64)> = vec![(&a, 0.5), (&b, 0.3), (&c, 0.2)];
+        // genus G1 holds 0.8 of the weight, species S1 only 0.5
+        let classification = classify(&hits, 0.7);
+        assert_eq!(classification.nb_hits, 3);
+        assert_eq!(
+            taxa(&classification),
+            vec![Some("d__B"), Some("p__P"), Some("c__C"), Some("o__O"), Some("f__F"), Some("g__G1"), None]
+        );
+        assert!((classification.ranks[5].confidence - 0.8).abs() < 1e-12);
+        assert!((classification.ranks[6].confidence - 0.5).abs() < 1e-12);
+        assert_eq!(classification.get_lca(), Some(("genus", "g__G1")));
+        // with a lower threshold the species is assigned
+        assert_eq!(classify(&hits, 0.5).get_lca(), Some(("species", "s__S1")));
+    }
+
+    #[test]
+    fn below_confidence_stays_unclassified() {
+        let a = lineage("d__B;p__P1;c__C1");
+        let b = lineage("d__B;p__P2;c__C2");
+        let hits: Vec<(&[String], f64)> = vec![(&a, 0.6), (&b, 0.4)];
+        let classification = classify(&hits, 0.9);
+        // phylum fails, lower ranks stay unclassified even where a single taxon would remain
+        assert_eq!(taxa(&classification)[..3], [Some("d__B"), None, None]);
+        assert!(classification.ranks[3..].iter().all(|assignment| assignment.taxon.is_none()));
+        assert_eq!(classification.get_lca(), Some(("domain", "d__B")));
+        // no hit at all
+        let classification = classify(&[], 0.5);
+        assert_eq!(classification.get_lca(), None);
+        assert!(classification.ranks.iter().all(|assignment| assignment.confidence == 0.));
+    }
+
+    #[test]
+    fn ties_are_broken_by_name() {
+        let a = lineage("d__B;p__Zeta");
+        let b = lineage("d__B;p__Alpha");
+        for hits in [vec![(a.as_slice(), 1.), (b.as_slice(), 1.)], vec![(b.as_slice(), 1.), (a.as_slice(), 1.)]] {
+            let classification = classify(&hits, 0.5);
+            assert_eq!(taxa(&classification)[1], Some("p__Alpha"));
+        }
+    }
+
+    #[test]
+    fn hits_missing_a_rank() {
+        // hits without species still count in the total weight, the species confidence is over all hits
+        let a = lineage("d__B;p__P;c__C;o__O;f__F;g__G;s__S");
+        let b = lineage("d__B;p__P;c__C;o__O;f__F;g__G");
+        let c = lineage("d__B;p__P;c__C;o__O;f__F;g__G;");
+        let hits: Vec<(&[String], f64)> = vec![(&a, 1.), (&b, 1.), (&c, 1.)];
+        let classification = classify(&hits, 0.5);
+        assert_eq!(classification.get_lca(), Some(("genus", "g__G")));
+        assert!((classification.ranks[6].confidence - 1. / 3.).abs() < 1e-12);
+        assert_eq!(classify(&hits, 0.3).get_lca(), Some(("species", "s__S")));
+    }
+
+    #[test]
+    fn taxonomy_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("taxonomy.tsv");
+        std::fs::write(
+            &path,
+            "# id\tlineage\n\nseq1\td__B; p__P ;c__C;;;\nseq2 desc\td__A;p__Q;c__R;o__S;f__T;g__U;s__V;x__extra\n",
+        )
+        .unwrap();
+        let taxonomy = Taxonomy::from_tsv(&path).unwrap();
+        assert_eq!(taxonomy.get_nb_entries(), 2);
+        // trailing empty levels are dropped, levels are trimmed
+        assert_eq!(taxonomy.get_lineage("seq1").unwrap(), lineage("d__B;p__P;c__C").as_slice());
+        // database ids are whole header lines, looked up by their first word
+        assert_eq!(taxonomy.get_lineage("seq1 some description").unwrap().len(), 3);
+        // levels beyond species are dropped
+        assert_eq!(taxonomy.get_lineage("seq2 desc").unwrap().len(), RANKS.len());
+        assert!(taxonomy.get_lineage("seq2").is_none());
+        assert!(taxonomy.get_lineage("seq3").is_none());
+        // a line without tab is an error
+        std::fs::write(&path, "seq1 d__B\n").unwrap();
+        assert!(Taxonomy::from_tsv(&path).is_err());
+    }
+}